@@ -1,13 +1,46 @@
+mod export;
+mod force_field;
+mod frame_stats;
+mod hud;
+mod kinematic_particle;
+mod osc;
+mod parameters;
 mod particles;
+mod replay;
+#[cfg(feature = "hot-reload")]
+mod shader_reload;
 
-use crate::display::Display;
-use anyhow::{Context, Result};
-use particles::Particles;
-use std::time::Instant;
+use crate::{
+    args::Args,
+    config::Config,
+    display::{ClearColor, Display, DisplayOptions, SwapchainState},
+    error::ParticleError,
+    math::{clamp, lerp},
+};
+use anyhow::{ensure, Context, Result};
+use force_field::ForceField;
+use frame_stats::FrameStats;
+use hud::Hud;
+pub use parameters::BoundaryShape;
+use parameters::Parameters;
+use particles::{
+    InitialLayout, Particles, Region, RenderSettings, View, WorldShape,
+    DEFAULT_PARTICLE_COUNT, LOCAL_SIZE_X,
+};
+use replay::{InputEvent, Recorder, Replay};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use vulkano::{
+    command_buffer::AutoCommandBuffer, pipeline::viewport::Viewport,
+};
 use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
     event::{
-        ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode,
-        WindowEvent,
+        ElementState, Event, KeyboardInput, ModifiersState, MouseButton,
+        MouseScrollDelta, VirtualKeyCode, WindowEvent,
     },
     event_loop::ControlFlow,
     window::Fullscreen,
@@ -15,72 +48,1444 @@ use winit::{
 
 type Vec2 = nalgebra::Vector2<f32>;
 
+/// Touchpads report scrolling in pixels, this converts it into the line
+/// steps reported by mouse wheels.
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+
+/// The fixed amount of simulated time covered by each tick, unless the
+/// config sets another. Ticks never use the real elapsed time, so the
+/// dynamics don't depend on the framerate.
+const DT: Duration = Duration::from_millis(15);
+
+/// The most ticks which will be run in a single update to catch up after a
+/// stall.
+const MAX_SUBSTEPS: u32 = 8;
+
+/// How many ticks particles spend relaxing back into their starting
+/// pattern, about two seconds.
+const RELAX_TICKS: u32 = 133;
+
+/// The most shockwaves which can be expanding at once, must match
+/// `MAX_SHOCKWAVES` in the compute shader.
+const MAX_SHOCKWAVES: usize = 4;
+
+/// How many ticks a shockwave expands for before it's gone, about two
+/// seconds.
+const SHOCKWAVE_TICKS: u32 = 133;
+
+/// The largest point size which can be picked from the keyboard, in pixels.
+const MAX_POINT_SIZE: f32 = 64.0;
+
+/// The range the view can be zoomed within.
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 32.0;
+
+/// How far each arrow key press pans the view, as a fraction of the visible
+/// half-height.
+const PAN_STEP: f32 = 0.25;
+
+/// The seed used for the initial layout when hashing frames, so every run
+/// starts from the same state. --seed takes precedence.
+const HASH_SEED: u64 = 0x5eed;
+
+/// The controls listed in the hud.
+const CONTROLS: &str = "\
+Tab        hide this help   F  fps
+mouse      hold to attract, middle click to pin
+ctrl+shift+click  place an attractor
+alt+shift+click   remove the nearest attractor
+right drag repel, add the left button to herd
+shift+drag lasso nearby particles
+ctrl+click place or remove the slow zone
+alt+click  fire a shockwave
+scroll     brush radius, with ctrl to zoom
+arrows     pan              Home  reset the view
+shift+arr  gravity          shift+Home  no gravity
+Space      reset            R  relax
+B          brush mode       M  magnetic
+Q shift+Q  swirl counter-clockwise or clockwise
+D          drag mode        G  center gravity
+[ ]        center gravity   J  jitter
+K          sink             Y  spawn limit
+- =        eps              PgUp PgDn  particle count
+num+ num-  strength
+O          soft points      C  cull static
+A          additive blending  Z X  point size
+N          invert colors    V  force field
+; '        exposure         F5 present mode
+T          always on top    I  dump parameters
+E          boundary shape   W  wrap around edges
+H          trail length     U  palette
+P          pause
+.          step once while paused
+S          screenshot       L  save particles to csv
+shift+L    load the last saved particles";
+
+/// The fractions of the previous frame kept by each trail length, H steps
+/// through them.
+const TRAIL_DECAYS: [f32; 5] = [0.0, 0.8, 0.9, 0.95, 0.98];
+
+/// The most attractors which can be placed at once, must match
+/// `MAX_ATTRACTORS` in the compute shader.
+///
+/// They're held in the compute shader's uniform buffer rather than its push
+/// constants, so raising this only costs sixteen bytes per attractor.
+const MAX_ATTRACTORS: usize = 8;
+
+/// An expanding ring which kicks particles outward as it passes.
+#[derive(Debug, Copy, Clone)]
+struct Shockwave {
+    center: Vec2,
+    start_tick: u32,
+}
+
+/// A second simulation which is rendered beside the first, using its own
+/// parameters.
+struct Comparison {
+    particles: Particles,
+    parameters: Parameters,
+    spawn_carry: f32,
+}
+
 pub struct Application {
     display: Display,
     particles: Particles,
+    comparison: Option<Comparison>,
+    force_field: ForceField,
+    show_force_field: bool,
+    hud: Hud,
+    show_hud: bool,
     last_update: Instant,
+    accumulator: Duration,
+    /// The fraction of a respawn left over from the spawn budget of the
+    /// previous tick.
+    spawn_carry: f32,
+    tick_interval: Duration,
+    paused: bool,
+    step_queued: bool,
+    last_frame: Instant,
+
+    /// The latest size the window was resized to. Resizing fires a flood of
+    /// events so the swapchain is only rebuilt once per frame.
+    pending_resize: Option<PhysicalSize<u32>>,
+    frame_interval: Option<Duration>,
+    frame_stats: FrameStats,
+    show_stats: bool,
+
+    /// The size of one simulation's column of the window, in physical
+    /// pixels. Cursor positions from winit are physical too, so the two can
+    /// be compared directly whatever the scale factor.
     screen_dims: Vec2,
+
+    /// The window's physical pixels per logical pixel, 2 on a typical hidpi
+    /// display. Only the window's initial size is logical.
+    scale_factor: f64,
+
+    /// The attractor's position in world coordinates.
     mouse: Vec2,
+    zoom: f32,
+    pan: Vec2,
     pressed: bool,
+    repelling: bool,
+    herd_target: Option<Vec2>,
+    pinned: Option<Vec2>,
+    /// Attractors which stay where they were placed with ctrl+shift+click.
+    attractors: Vec<Vec2>,
+
+    /// The csv which shift+L loads particles from.
+    snapshot: Option<PathBuf>,
+    modifiers: ModifiersState,
+    lasso: bool,
+    slow_zone: Option<Vec2>,
+    shockwaves: Vec<Shockwave>,
+    parameters: Parameters,
+    tick_count: u32,
+    relax_ticks_remaining: u32,
+    always_on_top: bool,
+    osc: Option<osc::SharedOscState>,
+    osc_pressed: bool,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    export: Option<export::Export>,
+    #[cfg(feature = "hot-reload")]
+    shader_watcher: Option<shader_reload::ShaderWatcher>,
 }
 
 impl Application {
-    pub fn initialize() -> Result<Self> {
-        let display =
-            Display::create().context("unable to create the display")?;
-        let particles = Particles::new(&display)?;
+    pub fn initialize(args: &Args, config: &Config) -> Result<Self> {
+        // checked before any vulkan setup so mistakes are reported quickly
+        ensure!(
+            args.width > 0 && args.height > 0,
+            "the window size must be at least 1x1, got {}x{}",
+            args.width,
+            args.height
+        );
+        let tick_interval =
+            config.tick_millis.map_or(DT, Duration::from_millis);
+        ensure!(
+            tick_interval > Duration::from_secs(0),
+            "the tick interval must be at least a millisecond"
+        );
+        let particle_count = args.particles.unwrap_or(DEFAULT_PARTICLE_COUNT);
+        ensure!(
+            particle_count > 0 && particle_count % LOCAL_SIZE_X == 0,
+            "the particle count must be a positive multiple of {}, got {}",
+            LOCAL_SIZE_X,
+            particle_count
+        );
+
+        let max_samples = args.max_samples.or(config.max_samples);
+        ensure!(
+            max_samples.map_or(true, |samples| samples > 0),
+            "at least one sample per pixel is needed"
+        );
+
+        let display_options = DisplayOptions {
+            allow_software: args.allow_software,
+            gpu: args.gpu,
+            image_count: args.image_count,
+            hdr: args.hdr,
+            tonemap: args.tonemap,
+            require_srgb: args.require_srgb,
+            sample_shading: args.sample_shading,
+            present_mode: args.present_mode,
+            window_size: [args.width, args.height],
+            max_samples,
+            clear_color: args
+                .clear_color
+                .or(config
+                    .clear_color
+                    .map(|[r, g, b]| ClearColor([r, g, b, 1.0])))
+                .unwrap_or_default(),
+        };
+        let display = Display::create(&display_options)
+            .context("unable to create the display")?;
+        let defaults = Parameters::default();
+        let damping = config.damping.unwrap_or(defaults.damping);
+        let parameters = Parameters {
+            strength: config.strength.unwrap_or(defaults.strength),
+            eps: config.eps.unwrap_or(defaults.eps),
+            damping: [
+                args.damping_x.unwrap_or(damping[0]),
+                args.damping_y.unwrap_or(damping[1]),
+            ],
+            max_vel: config.max_vel.unwrap_or(defaults.max_vel),
+            gravity: config.gravity.unwrap_or(defaults.gravity),
+            spawn_limited: args.spawn_rate.is_some(),
+            spawn_rate: args.spawn_rate.unwrap_or(defaults.spawn_rate),
+            max_alive: args.max_alive,
+            species_strength: [
+                args.species_a_strength
+                    .unwrap_or(defaults.species_strength[0]),
+                args.species_b_strength
+                    .unwrap_or(defaults.species_strength[1]),
+            ],
+            force_texture_strength: args
+                .force_field_strength
+                .unwrap_or(defaults.force_texture_strength),
+            boundary_shape: args.boundary,
+            boundary_radius: args.boundary_radius,
+            ..defaults
+        };
+        let shape = WorldShape {
+            half_height: args.world_half_height,
+            aspect: args.world_aspect,
+        };
+        ensure!(
+            parameters.boundary_radius.map_or(true, |r| r > 0.0),
+            "the boundary radius must be positive"
+        );
+        ensure!(
+            shape.half_height > 0.0 && shape.aspect.map_or(true, |a| a > 0.0),
+            "the world's half-height and aspect ratio must be positive"
+        );
+
+        ensure!(
+            (0.0..=1.0).contains(&args.species_mix),
+            "the species mix must be between 0 and 1"
+        );
+        let force_texture = args.force_field.as_deref();
+        let layout = InitialLayout {
+            seed: args.seed.or(args.hash_frame.map(|_| HASH_SEED)),
+            species_mix: args.species_mix,
+            image: args.seed_image.clone(),
+        };
+
+        let primary_region = if args.compare {
+            Region::LeftHalf
+        } else {
+            Region::Full
+        };
+        let force_field = ForceField::new(&display, primary_region, shape)?;
+        let hud = Hud::new(&display)?;
 
+        let (mut particles, comparison) = if args.compare {
+            let comparison = Comparison {
+                particles: Particles::new(
+                    &display,
+                    Region::RightHalf,
+                    shape,
+                    particle_count,
+                    layout.clone(),
+                    force_texture,
+                )?,
+                parameters: Parameters {
+                    damping: args
+                        .right_damping
+                        .map_or(parameters.damping, |damping| [damping; 2]),
+                    eps: args.right_eps.unwrap_or(parameters.eps),
+                    ..parameters
+                },
+                spawn_carry: 0.0,
+            };
+            let particles = Particles::new(
+                &display,
+                Region::LeftHalf,
+                shape,
+                particle_count,
+                layout,
+                force_texture,
+            )?;
+            (particles, Some(comparison))
+        } else {
+            (
+                Particles::new(
+                    &display,
+                    Region::Full,
+                    shape,
+                    particle_count,
+                    layout,
+                    force_texture,
+                )?,
+                None,
+            )
+        };
+        if let Some(path) = &args.load_positions {
+            particles.load_positions(&display, path)?;
+        }
+        let export = args
+            .export_socket
+            .as_deref()
+            .map(|address| {
+                export::Export::new(&display, address, args.export_stride)
+            })
+            .transpose()?;
+
+        let scale_factor = display.surface.window().scale_factor();
         Ok(Self {
             display,
             particles,
+            comparison,
+            force_field,
+            show_force_field: false,
+            hud,
+            show_hud: false,
             last_update: Instant::now(),
+            accumulator: Duration::from_secs(0),
+            spawn_carry: 0.0,
+            tick_interval,
+            paused: false,
+            step_queued: false,
+            last_frame: Instant::now(),
+            pending_resize: None,
+            frame_stats: FrameStats::default(),
+            show_stats: false,
+            frame_interval: if args.max_fps > 0 {
+                Some(Duration::from_secs_f64(1.0 / args.max_fps as f64))
+            } else {
+                None
+            },
             screen_dims: [1.0, 1.0].into(),
+            scale_factor,
             mouse: [0.0, 0.0].into(),
+            zoom: 1.0,
+            pan: Vec2::zeros(),
             pressed: false,
+            repelling: false,
+            herd_target: None,
+            pinned: None,
+            attractors: vec![],
+            snapshot: args.load_positions.clone(),
+            modifiers: ModifiersState::empty(),
+            lasso: false,
+            slow_zone: None,
+            shockwaves: vec![],
+            parameters,
+            tick_count: 0,
+            relax_ticks_remaining: 0,
+            always_on_top: args.always_on_top,
+            osc: args
+                .osc_port
+                .map(|port| osc::spawn_listener(args.osc_address, port))
+                .transpose()?,
+            osc_pressed: false,
+            recorder: args
+                .record_input
+                .as_deref()
+                .map(Recorder::create)
+                .transpose()?,
+            replay: args
+                .replay_input
+                .as_deref()
+                .map(Replay::load)
+                .transpose()?,
+            export,
+            #[cfg(feature = "hot-reload")]
+            shader_watcher: args
+                .compute_shader
+                .as_deref()
+                .map(shader_reload::ShaderWatcher::new),
         })
     }
 
-    /// Tick the application state based on the wall-clock time since the
-    /// last tick.
+    /// Advance the simulation by exactly one fixed step of `time` seconds.
+    ///
+    /// This never looks at the wall clock. `update` keeps an accumulator of
+    /// elapsed time and calls this once for every tick_interval it holds,
+    /// DT = 15ms by default, at most MAX_SUBSTEPS = 8 times per frame.
     fn tick(&mut self, time: f32) -> Result<()> {
-        let constants = particles::PushConstants {
-            enabled: if self.pressed { 1 } else { 0 },
-            attractor: self.mouse.into(),
+        self.apply_replay()?;
+
+        let spawn_budget =
+            self.parameters.spawn_budget(time, &mut self.spawn_carry);
+        let constants =
+            self.push_constants(&self.parameters, time, spawn_budget);
+        let simulation = self.simulation(&self.parameters);
+        self.particles.tick(&self.display, constants, simulation)?;
+
+        let comparison_budget = self.comparison.as_mut().map(|comparison| {
+            comparison
+                .parameters
+                .spawn_budget(time, &mut comparison.spawn_carry)
+        });
+        let comparison_constants =
+            self.comparison.as_ref().zip(comparison_budget).map(
+                |(comparison, spawn_budget)| {
+                    (
+                        self.push_constants(
+                            &comparison.parameters,
+                            time,
+                            spawn_budget,
+                        ),
+                        self.simulation(&comparison.parameters),
+                    )
+                },
+            );
+        if let (Some(comparison), Some((constants, simulation))) =
+            (&mut self.comparison, comparison_constants)
+        {
+            comparison
+                .particles
+                .tick(&self.display, constants, simulation)?;
+        }
+
+        self.tick_count = self.tick_count.wrapping_add(1);
+        let tick = self.tick_count;
+        self.shockwaves.retain(|wave| {
+            tick.wrapping_sub(wave.start_tick) < SHOCKWAVE_TICKS
+        });
+        self.relax_ticks_remaining =
+            self.relax_ticks_remaining.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Build the compute shader's push constants for a single tick.
+    fn push_constants(
+        &self,
+        parameters: &Parameters,
+        time: f32,
+        spawn_budget: u32,
+    ) -> particles::PushConstants {
+        let enabled = self.pressed || self.osc_pressed || self.pinned.is_some();
+        let attractor = self.pinned.or(self.herd_target).unwrap_or(self.mouse);
+        particles::PushConstants {
+            enabled: if enabled { 1 } else { 0 },
+            attractor: attractor.into(),
             timestep: time,
+            seed: self.tick_count,
+            relax_enabled: if self.relax_ticks_remaining > 0 { 1 } else { 0 },
+            spawn_budget,
+            lasso_enabled: if self.lasso { 1 } else { 0 },
+            lasso_center: self.mouse.into(),
+            slow_center: self.slow_zone.unwrap_or(self.mouse).into(),
+            repel_enabled: if self.repelling { 1 } else { 0 },
+            repulsor: self.mouse.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Build the compute shader's simulation parameters for a single tick.
+    fn simulation(&self, parameters: &Parameters) -> particles::Simulation {
+        let mut shockwaves = [[0.0; 4]; MAX_SHOCKWAVES];
+        for (packed, wave) in shockwaves.iter_mut().zip(&self.shockwaves) {
+            let age = self.tick_count.wrapping_sub(wave.start_tick);
+            let fade = 1.0 - age as f32 / SHOCKWAVE_TICKS as f32;
+            *packed = [
+                wave.center.x,
+                wave.center.y,
+                age as f32
+                    * self.tick_interval.as_secs_f32()
+                    * parameters.shockwave_speed,
+                parameters.shockwave_strength * fade,
+            ];
+        }
+        let mut attractors = [[0.0; 4]; MAX_ATTRACTORS];
+        for (packed, placed) in attractors.iter_mut().zip(&self.attractors) {
+            *packed = [placed.x, placed.y, 0.0, 0.0];
+        }
+        particles::Simulation {
+            strength: parameters.strength,
+            jitter_strength: parameters.jitter_strength,
+            sink_radius: parameters.sink_radius,
+            eps: parameters.eps,
+            damping: parameters.damping,
+            max_vel: parameters.max_vel,
+            drag_mode: parameters.drag_mode.shader_id(),
+            drag_coeff: parameters.drag_coeff,
+            center_gravity: parameters.effective_center_gravity(),
+            magnetic_strength: parameters.magnetic_strength,
+            relax_strength: parameters.relax_strength,
+            max_alive: parameters.max_alive.unwrap_or(u32::MAX),
+            brush_mode: parameters.brush_mode.shader_id(),
+            brush_radius: parameters.brush_radius,
+            lasso_radius: parameters.lasso_radius,
+            lasso_strength: parameters.lasso_strength,
+            slow_radius: if self.slow_zone.is_some() {
+                parameters.slow_radius
+            } else {
+                0.0
+            },
+            slow_factor: parameters.slow_factor,
+            boundary_shape: parameters.boundary_shape.shader_id(),
+            boundary_radius: parameters.boundary_radius.unwrap_or(0.0),
+            wrap: if parameters.wrap { 1 } else { 0 },
+            species_strength: parameters.species_strength,
+            shockwaves,
+            shockwave_count: self.shockwaves.len() as u32,
+            shockwave_thickness: parameters.shockwave_thickness,
+            force_texture_strength: parameters.force_texture_strength,
+            attractors,
+            attractor_count: self.attractors.len() as u32,
+            gravity: parameters.gravity,
+            swirl: parameters.swirl,
             ..Default::default()
+        }
+    }
+
+    /// Map a point normalized to [0, 1] across the screen, with y pointing
+    /// down, into world coordinates.
+    ///
+    /// Every input source which positions the attractor goes through this
+    /// so they all agree with the projection used for rendering.
+    fn screen_to_world(&self, normalized: Vec2) -> Vec2 {
+        screen_to_world(self.orthographic_bounds(), normalized)
+    }
+
+    /// The inverse of screen_to_world.
+    fn world_to_screen(&self, world: Vec2) -> Vec2 {
+        world_to_screen(self.orthographic_bounds(), world)
+    }
+
+    /// The edges of the visible world, exactly as the particles are
+    /// projected with.
+    fn orthographic_bounds(&self) -> (f32, f32, f32, f32) {
+        particles::orthographic_bounds(
+            self.particles.world_bounds(),
+            &self.view(),
+        )
+    }
+
+    /// Normalize a cursor position, in physical pixels, to [0, 1] across a
+    /// single simulation's viewport.
+    fn normalize_cursor(&self, position: PhysicalPosition<f64>) -> Vec2 {
+        let viewport = self.particles.viewport(&self.display);
+        normalize_cursor(position, self.screen_dims, &viewport)
+    }
+
+    /// Apply any attractor controls received over OSC since the last frame.
+    fn poll_osc(&mut self) {
+        let state = match self.osc.as_ref().and_then(|osc| osc.lock().ok()) {
+            Some(mut state) => std::mem::take(&mut *state),
+            None => return,
+        };
+        if let Some([x, y]) = state.xy {
+            self.mouse = self.screen_to_world(Vec2::new(x, 1.0 - y));
+        }
+        if let Some(strength) = state.strength {
+            self.parameters.strength = strength.max(0.0);
+        }
+        if let Some(active) = state.active {
+            self.osc_pressed = active;
+        }
+    }
+
+    /// Convert a window event into input which can be recorded and
+    /// replayed.
+    fn input_event(&self, event: &Event<()>) -> Option<InputEvent> {
+        let event = match event {
+            Event::WindowEvent { event, .. } => event,
+            _ => return None,
         };
-        self.particles.tick(&self.display, constants)
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                Some(InputEvent::CursorMoved(
+                    self.normalize_cursor(*position).into(),
+                ))
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Released,
+                        virtual_keycode: Some(key),
+                        ..
+                    },
+                ..
+            } => Some(InputEvent::KeyReleased(*key)),
+            WindowEvent::MouseInput {
+                button: MouseButton::Left,
+                state,
+                ..
+            } => Some(InputEvent::LeftButton(*state == ElementState::Pressed)),
+            WindowEvent::MouseInput {
+                button: MouseButton::Right,
+                state,
+                ..
+            } => Some(InputEvent::RightButton(*state == ElementState::Pressed)),
+            WindowEvent::MouseInput {
+                button: MouseButton::Middle,
+                state: ElementState::Released,
+                ..
+            } => Some(InputEvent::MiddleReleased),
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => {
+                        position.y as f32 / PIXELS_PER_SCROLL_LINE
+                    }
+                };
+                Some(InputEvent::Scrolled(lines))
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                Some(InputEvent::ModifiersChanged(*modifiers))
+            }
+            _ => None,
+        }
     }
 
-    /// Draw the screen.
-    fn render(&mut self) -> Result<()> {
-        let particle_draw_commands = self.particles.draw(&self.display)?;
-        self.display.render(vec![particle_draw_commands])?;
+    /// Handle input from the window. Live input is recorded when requested,
+    /// and ignored entirely while a recording is being replayed.
+    fn live_input(&mut self, input: InputEvent) -> Result<()> {
+        if self.replay.is_some() {
+            return Ok(());
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(self.tick_count, input)?;
+        }
+        self.handle_input(input)
+    }
+
+    /// Apply any replayed input which is due before the next tick.
+    fn apply_replay(&mut self) -> Result<()> {
+        let tick = self.tick_count;
+        while let Some(input) = self
+            .replay
+            .as_mut()
+            .and_then(|replay| replay.next_due(tick))
+        {
+            self.handle_input(input)?;
+        }
+        if self.replay.as_ref().map_or(false, Replay::is_finished) {
+            log::info!("input replay finished");
+            self.replay = None;
+        }
+        Ok(())
+    }
+
+    /// Respond to user input, whether it's live or replayed.
+    fn handle_input(&mut self, input: InputEvent) -> Result<()> {
+        match input {
+            InputEvent::CursorMoved([x, y]) => {
+                self.mouse = self.screen_to_world(Vec2::new(x, y));
+            }
+            InputEvent::LeftButton(true)
+                if self.modifiers.ctrl() && self.modifiers.shift() =>
+            {
+                self.place_attractor();
+            }
+            InputEvent::LeftButton(true)
+                if self.modifiers.alt() && self.modifiers.shift() =>
+            {
+                self.remove_nearest_attractor();
+            }
+            InputEvent::LeftButton(true) if self.modifiers.ctrl() => {
+                self.place_slow_zone();
+            }
+            InputEvent::LeftButton(true) if self.modifiers.alt() => {
+                self.fire_shockwave();
+            }
+            InputEvent::LeftButton(pressed) => {
+                // shift+drag grabs the particles near the cursor with the
+                // lasso instead of moving the attractor
+                self.lasso = pressed && self.modifiers.shift();
+                self.pressed = pressed && !self.lasso;
+                if !self.pressed {
+                    self.herd_target = None;
+                }
+            }
+            InputEvent::RightButton(pressed) => {
+                // holding the left button leaves the attractor where the
+                // right button went down, so the cursor can herd particles
+                // toward it
+                self.repelling = pressed;
+                self.herd_target = if pressed && self.pressed {
+                    Some(self.mouse)
+                } else {
+                    None
+                };
+            }
+            InputEvent::MiddleReleased => {
+                // latch the attractor at the cursor, or release the latch so
+                // the left button controls the attractor again
+                self.pinned = match self.pinned {
+                    Some(_) => None,
+                    None => Some(self.mouse),
+                };
+            }
+            InputEvent::Scrolled(lines) if self.modifiers.ctrl() => {
+                self.set_zoom(self.zoom * 1.1f32.powf(lines))?;
+            }
+            InputEvent::Scrolled(lines) => {
+                self.parameters.scale_brush_radius(1.1f32.powf(lines));
+            }
+            InputEvent::KeyReleased(key) => self.handle_key_released(key)?,
+            InputEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers
+            }
+        }
+        Ok(())
+    }
+
+    /// How the world is framed in every simulation's viewport.
+    fn view(&self) -> View {
+        View {
+            zoom: self.zoom,
+            pan: self.pan.into(),
+        }
+    }
+
+    /// Zoom the view in or out around its center.
+    fn set_zoom(&mut self, zoom: f32) -> Result<()> {
+        self.set_view(clamp(zoom, MIN_ZOOM, MAX_ZOOM), self.pan)
+    }
+
+    /// Move the view by a fraction of its visible height.
+    fn pan_by(&mut self, x: f32, y: f32) -> Result<()> {
+        let [_, half_height] = self
+            .view()
+            .visible_half_extents(self.particles.world_bounds());
+        let offset = Vec2::new(x, y) * PAN_STEP * half_height;
+        self.set_view(self.zoom, self.pan + offset)
+    }
+
+    fn set_view(&mut self, zoom: f32, pan: Vec2) -> Result<()> {
+        // keep the attractor under the cursor
+        let cursor = self.world_to_screen(self.mouse);
+        self.zoom = zoom;
+        self.pan = pan;
+        let view = self.view();
+        self.each_particles(|particles, display| {
+            particles.set_view(display, view)
+        })?;
+        self.mouse = self.screen_to_world(cursor);
+        log::info!("zoom {} centered on {:?}", self.zoom, view.pan);
+        Ok(())
+    }
+
+    /// Start a shockwave at the cursor, replacing the oldest one when too
+    /// many are already expanding.
+    fn fire_shockwave(&mut self) {
+        if self.shockwaves.len() == MAX_SHOCKWAVES {
+            self.shockwaves.remove(0);
+        }
+        self.shockwaves.push(Shockwave {
+            center: self.mouse,
+            start_tick: self.tick_count,
+        });
+    }
+
+    /// Leave an attractor under the cursor. Once there are MAX_ATTRACTORS
+    /// the oldest one is replaced.
+    fn place_attractor(&mut self) {
+        if self.attractors.len() == MAX_ATTRACTORS {
+            self.attractors.remove(0);
+        }
+        self.attractors.push(self.mouse);
+        log::info!("attractors {:?}", self.attractors);
+    }
+
+    /// Remove the placed attractor closest to the cursor.
+    fn remove_nearest_attractor(&mut self) {
+        let mouse = self.mouse;
+        let nearest = self
+            .attractors
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let a = (**a - mouse).norm();
+                let b = (**b - mouse).norm();
+                a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .map(|(index, _)| index);
+        if let Some(index) = nearest {
+            self.attractors.remove(index);
+        }
+        log::info!("attractors {:?}", self.attractors);
+    }
+
+    /// Put the slow zone under the cursor, or remove it when the cursor is
+    /// already inside it.
+    fn place_slow_zone(&mut self) {
+        let radius = self.parameters.slow_radius;
+        self.slow_zone = match self.slow_zone {
+            Some(center) if (center - self.mouse).norm() < radius => None,
+            _ => Some(self.mouse),
+        };
+        log::info!("slow zone {:?}", self.slow_zone);
+    }
+
+    /// Respond to a key being released.
+    fn handle_key_released(&mut self, key: VirtualKeyCode) -> Result<()> {
+        match key {
+            VirtualKeyCode::Space => self
+                .each_particles(|particles, display| {
+                    particles.reset_vertices(display)
+                })
+                .context("problem while reseting vertices")?,
+            VirtualKeyCode::R => {
+                // spring particles back into their starting pattern rather
+                // than snapping them there like a reset
+                self.relax_ticks_remaining = RELAX_TICKS;
+            }
+            VirtualKeyCode::O => self.update_render_settings(|settings| {
+                settings.soft_points = !settings.soft_points;
+            })?,
+            VirtualKeyCode::A => self.update_render_settings(|settings| {
+                settings.blend_mode = settings.blend_mode.toggle();
+                log::info!("blend mode {:?}", settings.blend_mode);
+            })?,
+            VirtualKeyCode::U => self.update_render_settings(|settings| {
+                settings.palette = settings.palette.next();
+                log::info!("palette {:?}", settings.palette);
+            })?,
+            VirtualKeyCode::Z => self.scale_point_size(0.8)?,
+            VirtualKeyCode::X => self.scale_point_size(1.25)?,
+            VirtualKeyCode::C => self.update_render_settings(|settings| {
+                settings.cull_static = !settings.cull_static;
+                log::info!("cull static particles {}", settings.cull_static);
+            })?,
+            VirtualKeyCode::N => {
+                self.display.invert_colors = !self.display.invert_colors;
+                let invert = self.display.invert_colors;
+                self.update_render_settings(|settings| {
+                    settings.invert_colors = invert;
+                })?
+            }
+            VirtualKeyCode::T => {
+                self.always_on_top = !self.always_on_top;
+                self.apply_always_on_top();
+            }
+            VirtualKeyCode::V => {
+                self.show_force_field = !self.show_force_field;
+            }
+            VirtualKeyCode::Tab => self.show_hud = !self.show_hud,
+            VirtualKeyCode::E => self.parameters.toggle_boundary_shape(),
+            VirtualKeyCode::W => self.parameters.toggle_wrap(),
+            VirtualKeyCode::H => self.cycle_trail_decay(),
+            VirtualKeyCode::D => self.parameters.toggle_drag_mode(),
+            VirtualKeyCode::G => self.parameters.toggle_center_gravity(),
+            VirtualKeyCode::LBracket => {
+                self.parameters.scale_center_gravity(0.8)
+            }
+            VirtualKeyCode::RBracket => {
+                self.parameters.scale_center_gravity(1.25)
+            }
+            VirtualKeyCode::J => self.parameters.toggle_jitter(),
+            VirtualKeyCode::K => self.parameters.toggle_sink(),
+            VirtualKeyCode::I => self.dump_parameters()?,
+            VirtualKeyCode::Y => self.parameters.toggle_spawn_limit(),
+            VirtualKeyCode::B => self.parameters.cycle_brush_mode(),
+            VirtualKeyCode::M => self.parameters.toggle_magnetic(),
+            VirtualKeyCode::Q if self.modifiers.shift() => {
+                self.parameters.nudge_swirl(-1.0)
+            }
+            VirtualKeyCode::Q => self.parameters.nudge_swirl(1.0),
+            VirtualKeyCode::F5 => {
+                let mode = self.display.preferred_present_mode().next();
+                log::info!("requesting present mode {:?}", mode);
+                self.display.set_present_mode(mode);
+                self.rebuild_swapchain_resources()?;
+            }
+            VirtualKeyCode::Semicolon => self.scale_exposure(0.8),
+            VirtualKeyCode::Apostrophe => self.scale_exposure(1.25),
+            VirtualKeyCode::PageUp => self.scale_particle_count(10.0)?,
+            VirtualKeyCode::PageDown => self.scale_particle_count(0.1)?,
+            VirtualKeyCode::Minus => self.parameters.scale_eps(0.8),
+            VirtualKeyCode::Equals => self.parameters.scale_eps(1.25),
+            VirtualKeyCode::NumpadAdd => self.parameters.scale_strength(1.25),
+            VirtualKeyCode::NumpadSubtract => {
+                self.parameters.scale_strength(0.8)
+            }
+            VirtualKeyCode::Left if self.modifiers.shift() => {
+                self.parameters.nudge_gravity(-1.0, 0.0)
+            }
+            VirtualKeyCode::Right if self.modifiers.shift() => {
+                self.parameters.nudge_gravity(1.0, 0.0)
+            }
+            VirtualKeyCode::Up if self.modifiers.shift() => {
+                self.parameters.nudge_gravity(0.0, 1.0)
+            }
+            VirtualKeyCode::Down if self.modifiers.shift() => {
+                self.parameters.nudge_gravity(0.0, -1.0)
+            }
+            VirtualKeyCode::Home if self.modifiers.shift() => {
+                self.parameters.clear_gravity()
+            }
+            VirtualKeyCode::Left => self.pan_by(-1.0, 0.0)?,
+            VirtualKeyCode::Right => self.pan_by(1.0, 0.0)?,
+            VirtualKeyCode::Up => self.pan_by(0.0, 1.0)?,
+            VirtualKeyCode::Down => self.pan_by(0.0, -1.0)?,
+            VirtualKeyCode::Home => self.set_view(1.0, Vec2::zeros())?,
+            VirtualKeyCode::S => self.save_screenshot(),
+            VirtualKeyCode::L if self.modifiers.shift() => {
+                self.restore_positions()
+            }
+            VirtualKeyCode::L => self.save_positions(),
+            VirtualKeyCode::F => self.show_stats = !self.show_stats,
+            VirtualKeyCode::P => self.toggle_pause(),
+            VirtualKeyCode::Period if self.paused => self.step_queued = true,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Log the full set of tunable values as toml, so a configuration found
+    /// interactively can be saved and loaded again with --config.
+    fn dump_parameters(&self) -> Result<()> {
+        #[derive(Serialize)]
+        struct Dump<'a> {
+            particle_count: u32,
+            present_mode: String,
+            exposure: f32,
+            parameters: &'a Parameters,
+            render: RenderSettings,
+        }
+
+        let [red, green, blue, _] = self.display.clear_color;
+        let config = Config {
+            strength: Some(self.parameters.strength),
+            eps: Some(self.parameters.eps),
+            damping: Some(self.parameters.damping),
+            max_vel: Some(self.parameters.max_vel),
+            tick_millis: Some(self.tick_interval.as_millis() as u64),
+            max_samples: self.display.max_samples(),
+            gravity: Some(self.parameters.gravity),
+            clear_color: Some([red, green, blue]),
+        };
+        let dump = Dump {
+            particle_count: self.particles.particle_count(),
+            present_mode: format!(
+                "{:?}",
+                self.display.swapchain.present_mode()
+            ),
+            exposure: self.display.exposure,
+            parameters: &self.parameters,
+            render: self.particles.settings(),
+        };
+        let config = toml::to_string(&config)
+            .context("unable to serialize the current config")?;
+        let dump = toml::to_string(&dump)
+            .context("unable to serialize the current parameters")?;
+        // everything --config can't load is commented out, so the whole
+        // dump can be pasted into a config file
+        let mut text = config;
+        for line in dump.lines() {
+            text.push_str("\n# ");
+            text.push_str(line);
+        }
+        log::info!("current parameters\n{}", text);
+        Ok(())
+    }
+
+    /// Freeze the simulation while still drawing every frame, or resume it.
+    fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.step_queued = false;
+        if !self.paused {
+            // the time spent paused must not be simulated all at once
+            self.last_update = Instant::now();
+            self.accumulator = Duration::from_secs(0);
+        }
+        log::info!("paused {}", self.paused);
+    }
+
+    /// Scale the brightness of the scene before it's tonemapped.
+    fn scale_exposure(&mut self, factor: f32) {
+        if !self.display.is_hdr() {
+            log::warn!("exposure only applies when running with --hdr");
+            return;
+        }
+        self.display.exposure = (self.display.exposure * factor).max(0.01);
+        log::info!("exposure {}", self.display.exposure);
+    }
+
+    /// Step through the trail lengths, wrapping back to no trails.
+    fn cycle_trail_decay(&mut self) {
+        let current = TRAIL_DECAYS
+            .iter()
+            .position(|&decay| decay == self.display.trail_decay)
+            .unwrap_or(0);
+        self.display.trail_decay =
+            TRAIL_DECAYS[(current + 1) % TRAIL_DECAYS.len()];
+        log::info!("trail decay {}", self.display.trail_decay);
+    }
+
+    /// Keep the window above other windows, or not, based on the
+    /// always_on_top flag. This has no effect in exclusive fullscreen.
+    fn apply_always_on_top(&self) {
+        let window = self.display.surface.window();
+        if let Some(Fullscreen::Exclusive(_)) = window.fullscreen() {
+            log::warn!("always-on-top is ignored in exclusive fullscreen");
+            return;
+        }
+        window.set_always_on_top(self.always_on_top);
+        log::info!("always on top {}", self.always_on_top);
+    }
+
+    /// Grow or shrink the number of simulated particles.
+    fn scale_particle_count(&mut self, factor: f32) -> Result<()> {
+        let count = (self.particles.particle_count() as f32 * factor) as u32;
+        self.each_particles(|particles, display| {
+            particles.resize(display, count)
+        })
+        .context("unable to resize the particle buffer")
+    }
+
+    /// Grow or shrink the particles on screen. The size actually drawn is
+    /// also limited by the device.
+    fn scale_point_size(&mut self, factor: f32) -> Result<()> {
+        self.update_render_settings(|settings| {
+            settings.point_size =
+                clamp(settings.point_size * factor, 1.0, MAX_POINT_SIZE);
+            log::info!("point size {}", settings.point_size);
+        })
+    }
+
+    /// Change the render settings for every simulation on screen.
+    fn update_render_settings<F>(&mut self, mut update: F) -> Result<()>
+    where
+        F: FnMut(&mut particles::RenderSettings),
+    {
+        self.each_particles(|particles, display| {
+            let mut settings = particles.settings();
+            update(&mut settings);
+            particles.set_settings(display, settings)
+        })
+    }
+
+    /// Apply an operation to every simulation on screen.
+    fn each_particles<F>(&mut self, mut operation: F) -> Result<()>
+    where
+        F: FnMut(&mut Particles, &Display) -> Result<(), ParticleError>,
+    {
+        operation(&mut self.particles, &self.display)?;
+        if let Some(comparison) = &mut self.comparison {
+            operation(&mut comparison.particles, &self.display)?;
+        }
         Ok(())
     }
 
+    /// Draw the screen.
+    fn render(&mut self) -> Result<SwapchainState> {
+        let (draw_commands, overlay_commands) = self.draw_commands()?;
+        let mut compute =
+            vulkano::sync::now(self.display.device.clone()).boxed();
+        compute = self.particles.join_compute(compute);
+        if let Some(comparison) = &mut self.comparison {
+            compute = comparison.particles.join_compute(compute);
+        }
+        Ok(self
+            .display
+            .render(draw_commands, overlay_commands, compute)?)
+    }
+
+    /// Build the secondary command buffers for the scene and for the
+    /// overlays drawn on top of it.
+    fn draw_commands(
+        &mut self,
+    ) -> Result<(Vec<AutoCommandBuffer>, Vec<AutoCommandBuffer>)> {
+        let mut draw_commands = vec![self.particles.draw(&self.display)?];
+        if let Some(comparison) = &self.comparison {
+            draw_commands.push(comparison.particles.draw(&self.display)?);
+        }
+        if self.show_force_field {
+            let constants = self.push_constants(&self.parameters, 0.0, 0);
+            let simulation = particles::Simulation {
+                bounds: self.particles.world_bounds(),
+                ..self.simulation(&self.parameters)
+            };
+            let view = self.view();
+            draw_commands.push(self.force_field.draw(
+                &self.display,
+                &constants,
+                &simulation,
+                &view,
+            )?);
+        }
+        let mut overlay_commands = vec![];
+        let mut text = String::new();
+        if self.show_stats {
+            text.push_str(&self.stats_text());
+        }
+        if self.show_hud {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&self.hud_text());
+        }
+        if !text.is_empty() {
+            overlay_commands.extend(self.hud.draw(&self.display, &text)?);
+        }
+        Ok((draw_commands, overlay_commands))
+    }
+
+    /// Save the window's contents, as presented, to a png named after the
+    /// current time. Failures are logged rather than ending the app.
+    fn save_screenshot(&mut self) {
+        let result = self.capture_screenshot();
+        match result {
+            Ok(path) => log::info!("saved a screenshot to {:?}", path),
+            Err(error) => {
+                log::error!("unable to save a screenshot {:?}", error)
+            }
+        }
+    }
+
+    /// Write the primary simulation's particles to a csv named after the
+    /// current time. Failures are logged rather than ending the app.
+    fn save_positions(&mut self) {
+        let result = unix_timestamp().and_then(|timestamp| {
+            let path = PathBuf::from(format!("particles-{}.csv", timestamp));
+            self.particles.export_positions(&self.display, &path)?;
+            Ok(path)
+        });
+        match result {
+            Ok(path) => {
+                log::info!("saved the particles to {:?}", path);
+                self.snapshot = Some(path);
+            }
+            Err(error) => {
+                log::error!("unable to save the particles {:?}", error)
+            }
+        }
+    }
+
+    /// Replace the primary simulation's particles with the last ones saved,
+    /// or the file given with --load-positions. Failures are logged rather
+    /// than ending the app.
+    fn restore_positions(&mut self) {
+        let path = match &self.snapshot {
+            Some(path) => path,
+            None => {
+                log::warn!("no particles have been saved yet");
+                return;
+            }
+        };
+        match self.particles.load_positions(&self.display, path) {
+            Ok(()) => log::info!("loaded the particles from {:?}", path),
+            Err(error) => {
+                log::error!("unable to load the particles {:?}", error)
+            }
+        }
+    }
+
+    fn capture_screenshot(&mut self) -> Result<String> {
+        self.display.capture_next_frame()?;
+        let state = self.render()?;
+        // rebuilding drops the capture, so read it back first
+        let image = self.display.capture_frame();
+        if state == SwapchainState::NeedsRebuild {
+            self.rebuild_swapchain_resources()?;
+        }
+        let image = image?;
+        let path = format!("screenshot-{}.png", unix_timestamp()?);
+        image
+            .save(&path)
+            .with_context(|| format!("unable to write {}", path))?;
+        Ok(path)
+    }
+
+    /// Run one fixed-length tick per frame without ever showing the window,
+    /// writing each frame to a numbered png in the output directory. The
+    /// frames can be assembled into a video afterwards, for example with
+    /// `ffmpeg -i frame-%05d.png`.
+    pub fn render_sequence(
+        mut self,
+        frames: usize,
+        out_dir: &Path,
+    ) -> Result<()> {
+        std::fs::create_dir_all(out_dir).with_context(|| {
+            format!("unable to create the output directory {:?}", out_dir)
+        })?;
+        let target = self.display.create_offscreen_target()?;
+        for frame in 0..frames {
+            self.tick(self.tick_interval.as_secs_f32())?;
+            let (draw_commands, _) = self.draw_commands()?;
+            self.each_particles(|particles, _| particles.wait_for_compute())?;
+            let image = self
+                .display
+                .render_offscreen_image(&target, draw_commands)?;
+            let path = out_dir.join(format!("frame-{:05}.png", frame));
+            image
+                .save(&path)
+                .with_context(|| format!("unable to write {:?}", path))?;
+            log::debug!("wrote {:?}", path);
+        }
+        log::info!("wrote {} frames to {:?}", frames, out_dir);
+        Ok(self.display.shutdown()?)
+    }
+
+    /// Finish any compute dispatches and wait for the device to go idle
+    /// before the window closes.
+    fn shutdown(&mut self) -> Result<()> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush()?;
+        }
+        self.each_particles(|particles, _| particles.wait_for_compute())?;
+        Ok(self.display.shutdown()?)
+    }
+
+    /// Run a fixed number of ticks without ever showing the window, then
+    /// render the final frame offscreen and return a hash of its pixels.
+    ///
+    /// With a fixed seed, as used by --hash-frame, the result only depends
+    /// on the code and the GPU, so it can be compared against a stored
+    /// golden hash to catch rendering regressions. Goldens are only
+    /// comparable between runs on the same device and driver.
+    pub fn hash_frame(mut self, frames: u32) -> Result<u64> {
+        for _ in 0..frames {
+            self.tick(self.tick_interval.as_secs_f32())?;
+            // nothing renders in between, so end each tick's compute chain
+            // before the next one grows it
+            self.each_particles(|particles, _| particles.wait_for_compute())?;
+        }
+        let target = self.display.create_offscreen_target()?;
+        let (mut draw_commands, mut overlay_commands) = self.draw_commands()?;
+        draw_commands.append(&mut overlay_commands);
+        self.each_particles(|particles, _| particles.wait_for_compute())?;
+        let pixels = self.display.render_offscreen(&target, draw_commands)?;
+        self.display.shutdown()?;
+        Ok(fnv1a(&pixels))
+    }
+
+    /// The framerate and the number of simulated particles.
+    fn stats_text(&self) -> String {
+        let mut particle_count = self.particles.particle_count();
+        if let Some(comparison) = &self.comparison {
+            particle_count += comparison.particles.particle_count();
+        }
+        format!(
+            "fps            {:.0} (avg {:.1})\n\
+             particles      {}\n\
+             window         {}x{} at {:.2}x\n",
+            self.frame_stats.instantaneous_fps(),
+            self.frame_stats.average_fps(),
+            particle_count,
+            self.display.swapchain.dimensions()[0],
+            self.display.swapchain.dimensions()[1],
+            self.scale_factor,
+        )
+    }
+
+    /// The current parameter values followed by the controls.
+    fn hud_text(&self) -> String {
+        let parameters = &self.parameters;
+        format!(
+            "particles      {}\n\
+             strength       {:.3}\n\
+             eps            {:.4}\n\
+             damping        {:.3} {:.3}\n\
+             drag           {:?} {:.3}\n\
+             brush          {:?} radius {:.3}\n\
+             center gravity {:.3}\n\
+             gravity        {:.2} {:.2}\n\
+             swirl          {:.2}\n\
+             magnetic       {:.3}\n\
+             jitter         {:.4}\n\
+             sink radius    {:.3}\n\
+             boundary       {:?} wrap {}\n\
+             attractors     {} of {}\n\
+             present mode   {:?}\n\n\
+             {}",
+            self.particles.particle_count(),
+            parameters.strength,
+            parameters.eps,
+            parameters.damping[0],
+            parameters.damping[1],
+            parameters.drag_mode,
+            parameters.drag_coeff,
+            parameters.brush_mode,
+            parameters.brush_radius,
+            parameters.effective_center_gravity(),
+            parameters.gravity[0],
+            parameters.gravity[1],
+            parameters.swirl,
+            parameters.magnetic_strength,
+            parameters.jitter_strength,
+            parameters.sink_radius,
+            parameters.boundary_shape,
+            parameters.wrap,
+            self.attractors.len(),
+            MAX_ATTRACTORS,
+            self.display.preferred_present_mode(),
+            CONTROLS,
+        )
+    }
+
+    /// Render the frame which is shown when the window first appears.
+    ///
+    /// Some drivers report that a brand new swapchain is already out of date,
+    /// so rebuild it and retry a few times before giving up.
+    fn render_first_frame(&mut self) -> Result<()> {
+        const MAX_ATTEMPTS: usize = 3;
+        let mut attempt = 1;
+        loop {
+            match self.render()? {
+                SwapchainState::Optimal => return Ok(()),
+                SwapchainState::NeedsRebuild if attempt < MAX_ATTEMPTS => {
+                    log::warn!(
+                        "swapchain out of date before the first frame, \
+                        rebuilding (attempt {})",
+                        attempt
+                    );
+                    self.rebuild_swapchain_resources()?;
+                    attempt += 1;
+                }
+                SwapchainState::NeedsRebuild => anyhow::bail!(
+                    "the swapchain was still out of date after {} attempts",
+                    MAX_ATTEMPTS
+                ),
+            }
+        }
+    }
+
     /// Rebuild the swapchain and command buffers
     fn rebuild_swapchain_resources(&mut self) -> Result<()> {
-        self.display.rebuild_swapchain()?;
-        self.particles.rebuild_swapchain_resources(&self.display)?;
+        // the world can change shape, keep the attractor under the cursor
+        let cursor = self.world_to_screen(self.mouse);
+
+        match self.display.rebuild_swapchain() {
+            // the window changed size again mid rebuild, the old swapchain
+            // is kept and the next frame finds it out of date and retries
+            Err(ParticleError::OutOfDate) => {
+                log::debug!("the swapchain went out of date while rebuilding");
+                return Ok(());
+            }
+            result => result?,
+        }
+        self.each_particles(|particles, display| {
+            particles.rebuild_swapchain_resources(display)
+        })?;
+        self.force_field
+            .rebuild_swapchain_resources(&self.display)?;
+        self.hud.rebuild_swapchain_resources(&self.display)?;
 
+        // in compare mode each simulation only covers half of the window
         let [width, height] = self.display.swapchain.dimensions();
-        self.screen_dims.x = width as f32;
+        let columns = if self.comparison.is_some() { 2.0 } else { 1.0 };
+        self.screen_dims.x = width as f32 / columns;
         self.screen_dims.y = height as f32;
+        self.mouse = self.screen_to_world(cursor);
 
         Ok(())
     }
 
-    /// Update the application, only tick once every 15 milliseconds
+    /// Rebuild the swapchain for the latest resize, unless the window is
+    /// back to the swapchain's size or has been minimized.
+    fn apply_pending_resize(&mut self) -> Result<()> {
+        let size = match self.pending_resize.take() {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let unchanged =
+            [size.width, size.height] == self.display.swapchain.dimensions();
+        if unchanged || size.width == 0 || size.height == 0 {
+            return Ok(());
+        }
+        self.rebuild_swapchain_resources()
+    }
+
+    /// Update the application, running one fixed-length tick for every
+    /// tick_interval (DT by default) which has elapsed since the last update.
+    ///
+    /// After a stall at most MAX_SUBSTEPS ticks are run to catch up, any
+    /// time beyond that is dropped so a slow frame can't cause an ever
+    /// growing backlog of ticks.
     fn update(&mut self) -> Result<()> {
-        const TICK_MILLIS: u128 = 15;
-        let duration = Instant::now() - self.last_update;
-        if duration.as_millis() >= TICK_MILLIS {
-            self.tick(duration.as_secs_f32())?;
-            self.last_update = Instant::now();
-            Ok(())
-        } else {
-            Ok(())
+        #[cfg(feature = "hot-reload")]
+        self.reload_compute_shader();
+
+        if self.paused {
+            // a queued step runs exactly one tick of the usual length
+            if std::mem::take(&mut self.step_queued) {
+                self.tick(self.tick_interval.as_secs_f32())?;
+            }
+            return Ok(());
+        }
+        let now = Instant::now();
+        self.accumulator += now - self.last_update;
+        self.last_update = now;
+
+        let mut steps = 0;
+        while self.accumulator >= self.tick_interval && steps < MAX_SUBSTEPS {
+            self.tick(self.tick_interval.as_secs_f32())?;
+            self.accumulator -= self.tick_interval;
+            steps += 1;
+        }
+
+        if self.accumulator >= self.tick_interval {
+            log::debug!("dropping {:?} of simulation time", self.accumulator);
+            self.accumulator = Duration::from_secs(0);
+        }
+
+        if let Some(export) = &mut self.export {
+            export.update(&self.display, &mut self.particles)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the compute pipelines when the watched shader source has
+    /// changed. Problems are logged and the last good pipeline keeps
+    /// running.
+    #[cfg(feature = "hot-reload")]
+    fn reload_compute_shader(&mut self) {
+        let watcher = match &mut self.shader_watcher {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        let source = match watcher.poll() {
+            Ok(Some(source)) => source,
+            Ok(None) => return,
+            Err(error) => {
+                log::warn!("unable to check the compute shader {:?}", error);
+                return;
+            }
+        };
+        log::info!("reloading the compute shader from {:?}", watcher.path());
+        let result = self.each_particles(|particles, display| {
+            particles.reload_compute_shader(display, &source)
+        });
+        if let Err(error) = result {
+            log::error!("keeping the previous compute shader {:?}", error);
         }
     }
 
@@ -96,109 +1501,305 @@ impl Application {
             .context("unable to take ownership of the event loop")?;
 
         // render once before showing the window so it's not garbage
-        self.render()
+        self.render_first_frame()
             .context("unable to render the first application frame")?;
         self.display.surface.window().set_visible(true);
         self.display
             .surface
             .window()
             .set_fullscreen(Some(Fullscreen::Borderless(None)));
+        if self.always_on_top {
+            self.apply_always_on_top();
+        }
 
         event_loop.run(move |event, _, control_flow| {
-            *control_flow = ControlFlow::Poll;
+            if let Some(input) = self.input_event(&event) {
+                if let Err(error) = self.live_input(input) {
+                    log::error!("unable to handle input {:?}", error);
+                    *control_flow = ControlFlow::Exit;
+                }
+                return;
+            }
 
             match event {
                 Event::WindowEvent {
                     event: WindowEvent::CloseRequested,
                     ..
                 } => {
+                    if let Err(error) = self.shutdown() {
+                        log::error!("unable to shut down cleanly {:?}", error);
+                    }
                     *control_flow = ControlFlow::Exit;
                 }
 
                 Event::WindowEvent {
-                    event: WindowEvent::CursorMoved { position, .. },
+                    event: WindowEvent::Resized(size),
                     ..
-                } => {
-                    let world_width = self.screen_dims.x / self.screen_dims.y;
-                    self.mouse.y =
-                        lerp(position.y as f32 / self.screen_dims.y, 1.0, -1.0);
-                    self.mouse.x = lerp(
-                        position.x as f32 / self.screen_dims.x,
-                        -world_width,
-                        world_width,
-                    );
-                }
+                } => self.pending_resize = Some(size),
 
                 Event::WindowEvent {
                     event:
-                        WindowEvent::KeyboardInput {
-                            input:
-                                KeyboardInput {
-                                    state: ElementState::Released,
-                                    virtual_keycode: Some(VirtualKeyCode::Space),
-                                    ..
-                                },
-                            ..
+                        WindowEvent::ScaleFactorChanged {
+                            scale_factor,
+                            new_inner_size,
                         },
                     ..
-                } => match self.particles.reset_vertices(&self.display) {
-                    Err(error) => {
-                        log::error!(
-                            "problem while reseting vertices {:?}",
-                            error
-                        );
-                        *control_flow = ControlFlow::Exit;
+                } => {
+                    // moving to a monitor with a different scale resizes
+                    // the window in physical pixels, which is handled like
+                    // any other resize
+                    log::info!("scale factor changed to {}", scale_factor);
+                    self.scale_factor = scale_factor;
+                    self.pending_resize = Some(*new_inner_size);
+                }
+
+                Event::MainEventsCleared => {
+                    if let Some(interval) = self.frame_interval {
+                        let next_frame = self.last_frame + interval;
+                        if Instant::now() < next_frame {
+                            *control_flow = ControlFlow::WaitUntil(next_frame);
+                            return;
+                        }
                     }
-                    _ => {}
-                },
 
-                Event::WindowEvent {
-                    event:
-                        WindowEvent::MouseInput {
-                            button: MouseButton::Left,
-                            state,
-                            ..
-                        },
-                    ..
-                } => {
-                    self.pressed = match state {
-                        ElementState::Pressed => true,
-                        ElementState::Released => false,
+                    // a minimized window has nothing to draw into, so the
+                    // simulation sleeps until it's restored and resized
+                    let size = self.display.surface.window().inner_size();
+                    if size.width == 0 || size.height == 0 {
+                        *control_flow = ControlFlow::Wait;
+                        return;
                     }
-                }
 
-                Event::WindowEvent {
-                    event: WindowEvent::Resized(_),
-                    ..
-                } => match self.rebuild_swapchain_resources() {
-                    Err(error) => {
+                    if let Err(error) = self.apply_pending_resize() {
                         log::error!(
                             "unable to rebuild the swapchain {}",
                             error
                         );
                         *control_flow = ControlFlow::Exit;
+                        return;
                     }
-                    Ok(_) => {}
-                },
 
-                Event::MainEventsCleared => {
-                    match self.update().and_then(|_| self.render()) {
+                    self.poll_osc();
+                    let rendered = self
+                        .update()
+                        .and_then(|_| self.render())
+                        .and_then(|state| match state {
+                            SwapchainState::Optimal => Ok(()),
+                            SwapchainState::NeedsRebuild => {
+                                self.rebuild_swapchain_resources()
+                            }
+                        });
+                    match rendered {
                         Err(error) => {
                             log::error!("unable to render the frame {}", error);
                             *control_flow = ControlFlow::Exit;
                         }
                         Ok(_) => {
+                            self.last_frame = Instant::now();
+                            self.frame_stats.record(self.last_frame);
                             self.display.surface.window().request_redraw();
+                            // with --max-fps the loop sleeps until the next
+                            // frame is due instead of spinning
+                            *control_flow = match self.frame_interval {
+                                Some(interval) => ControlFlow::WaitUntil(
+                                    self.last_frame + interval,
+                                ),
+                                None => ControlFlow::Poll,
+                            };
                         }
                     }
                 }
 
+                Event::LoopDestroyed => {
+                    // the event loop never returns, so this is the last chance
+                    // to let the GPU finish when exiting because of an error
+                    if let Some(Err(error)) =
+                        self.recorder.as_mut().map(Recorder::flush)
+                    {
+                        log::error!("unable to save the recording {:?}", error);
+                    }
+                    if let Err(error) = self.display.wait_idle() {
+                        log::error!("unable to shut down cleanly {:?}", error);
+                    }
+                }
+
                 _ => {}
             }
         });
     }
 }
 
-fn lerp(x: f32, min: f32, max: f32) -> f32 {
-    x * max + (1.0 - x) * min
+/// Normalize a cursor position to [0, 1] across the viewport, which lies
+/// within the window's first column. The position, the column's size, and
+/// the viewport are all in physical pixels.
+fn normalize_cursor(
+    position: PhysicalPosition<f64>,
+    column: Vec2,
+    viewport: &Viewport,
+) -> Vec2 {
+    // wrap the cursor into a single column so it drives the same spot in
+    // both halves when comparing
+    let x = position.x as f32 % column.x;
+    let y = position.y as f32;
+    Vec2::new(
+        (x - viewport.origin[0]) / viewport.dimensions[0],
+        (y - viewport.origin[1]) / viewport.dimensions[1],
+    )
+}
+
+/// Map a position normalized to [0, 1] across the viewport, with y pointing
+/// down, into the world within the edges from `orthographic_bounds`.
+fn screen_to_world(bounds: (f32, f32, f32, f32), normalized: Vec2) -> Vec2 {
+    // the window's top edge is the projection's "bottom"
+    let (left, right, bottom, top) = bounds;
+    Vec2::new(
+        lerp(normalized.x, left, right),
+        lerp(normalized.y, bottom, top),
+    )
+}
+
+/// The inverse of screen_to_world.
+fn world_to_screen(bounds: (f32, f32, f32, f32), world: Vec2) -> Vec2 {
+    let (left, right, bottom, top) = bounds;
+    Vec2::new(
+        (world.x - left) / (right - left),
+        (world.y - bottom) / (top - bottom),
+    )
+}
+
+/// The 64 bit FNV-1a hash of the bytes.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The number of seconds since the unix epoch, used to name saved files.
+fn unix_timestamp() -> Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("the system clock is before the unix epoch")?
+        .as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn half_extents(extent: [u32; 2]) -> [f32; 2] {
+        let viewport = Region::Full.viewport(extent);
+        WorldShape::default().half_extents(&viewport)
+    }
+
+    /// The edges of the world shown in a window of the given size.
+    fn bounds(extent: [u32; 2], view: &View) -> (f32, f32, f32, f32) {
+        particles::orthographic_bounds(half_extents(extent), view)
+    }
+
+    fn assert_near(actual: Vec2, expected: Vec2) {
+        assert!(
+            (actual - expected).norm() < 1e-5,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    const EXTENTS: [[u32; 2]; 3] = [[800, 800], [600, 1000], [3440, 1440]];
+
+    #[test]
+    fn screen_and_world_round_trip_when_zoomed_and_panned() {
+        let view = View {
+            zoom: 2.5,
+            pan: [0.3, -0.2],
+        };
+        for &extent in &EXTENTS {
+            let bounds = bounds(extent, &view);
+            assert_near(
+                screen_to_world(bounds, Vec2::new(0.5, 0.5)),
+                Vec2::from(view.pan),
+            );
+            for &[x, y] in &[[0.0, 0.0], [1.0, 1.0], [0.25, 0.75]] {
+                let screen = Vec2::new(x, y);
+                let world = screen_to_world(bounds, screen);
+                assert_near(world_to_screen(bounds, world), screen);
+            }
+        }
+    }
+
+    #[test]
+    fn screen_to_world_lands_under_the_cursor_once_projected() {
+        let view = View {
+            zoom: 0.75,
+            pan: [-0.4, 0.1],
+        };
+        for &extent in &EXTENTS {
+            let projection =
+                particles::world_projection(half_extents(extent), &view);
+            for &[x, y] in &[[0.0, 0.0], [1.0, 1.0], [0.2, 0.9]] {
+                let world =
+                    screen_to_world(bounds(extent, &view), Vec2::new(x, y));
+                let clip = projection
+                    * nalgebra::Vector4::new(world.x, world.y, 0.0, 1.0);
+                // vulkan's clip space has y pointing down, like the screen
+                assert_near(
+                    Vec2::new(clip.x / clip.w, clip.y / clip.w),
+                    Vec2::new(x * 2.0 - 1.0, y * 2.0 - 1.0),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cursors_on_hidpi_displays_normalize_in_physical_pixels() {
+        // a 640x360 logical window on a display which scales by 2
+        let scale_factor = 2.0;
+        let size = winit::dpi::LogicalSize::new(640.0, 360.0)
+            .to_physical::<f32>(scale_factor);
+        let column = Vec2::new(size.width, size.height);
+        assert_eq!(column, Vec2::new(1280.0, 720.0));
+        let viewport = Region::Full.viewport([1280, 720]);
+
+        let center = winit::dpi::LogicalPosition::new(320.0, 180.0)
+            .to_physical(scale_factor);
+        assert_near(
+            normalize_cursor(center, column, &viewport),
+            Vec2::new(0.5, 0.5),
+        );
+        let corner = winit::dpi::LogicalPosition::new(160.0, 270.0)
+            .to_physical(scale_factor);
+        assert_near(
+            normalize_cursor(corner, column, &viewport),
+            Vec2::new(0.25, 0.75),
+        );
+
+        // comparing splits the window into two columns of half the width
+        let half = Vec2::new(column.x / 2.0, column.y);
+        let viewport = Region::LeftHalf.viewport([1280, 720]);
+        assert_near(
+            normalize_cursor(
+                PhysicalPosition::new(960.0, 360.0),
+                half,
+                &viewport,
+            ),
+            Vec2::new(0.5, 0.5),
+        );
+    }
+
+    #[test]
+    fn cursors_normalize_across_a_letterboxed_world() {
+        // a square world is centered in a wide window
+        let shape = WorldShape {
+            half_height: 1.0,
+            aspect: Some(1.0),
+        };
+        let column = Vec2::new(1280.0, 720.0);
+        let viewport = shape.letterbox(Region::Full.viewport([1280, 720]));
+        let normalize = |x, y| {
+            normalize_cursor(PhysicalPosition::new(x, y), column, &viewport)
+        };
+        assert_near(normalize(640.0, 360.0), Vec2::new(0.5, 0.5));
+        assert_near(normalize(280.0, 0.0), Vec2::new(0.0, 0.0));
+        assert_near(normalize(1000.0, 720.0), Vec2::new(1.0, 1.0));
+    }
 }
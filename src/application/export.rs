@@ -0,0 +1,187 @@
+mod pipeline;
+
+use super::particles::Particles;
+use crate::{display::Display, error::ParticleError};
+use anyhow::{Context, Result};
+use std::{
+    io::Write,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::AutoCommandBufferBuilder,
+    pipeline::ComputePipelineAbstract,
+    sync::{FenceSignalFuture, FlushError, GpuFuture},
+};
+
+type PositionBuffer = CpuAccessibleBuffer<[[f32; 2]]>;
+type ReadbackFuture = Arc<FenceSignalFuture<Box<dyn GpuFuture>>>;
+
+/// A copy of particle positions which is still being made on the gpu.
+struct Readback {
+    positions: Arc<PositionBuffer>,
+    future: ReadbackFuture,
+}
+
+/// Streams particle positions to tcp clients for external visualizers.
+///
+/// Each frame is a little-endian u32 count followed by that many pairs of
+/// little-endian f32 positions. Only every `stride`th particle is sent to
+/// keep the bandwidth reasonable.
+pub struct Export {
+    stride: u32,
+    pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    positions: Option<Arc<PositionBuffer>>,
+    pending: Option<Readback>,
+    frames: SyncSender<Vec<u8>>,
+}
+
+impl Export {
+    /// Start accepting clients on the address, like 127.0.0.1:7777.
+    pub fn new(display: &Display, address: &str, stride: u32) -> Result<Self> {
+        let listener = TcpListener::bind(address).with_context(|| {
+            format!("unable to bind the export socket {}", address)
+        })?;
+        listener
+            .set_nonblocking(true)
+            .context("unable to make the export socket non-blocking")?;
+        log::info!("exporting particle positions on {}", address);
+
+        // only one frame is buffered, frames are dropped rather than stalling
+        // the render loop while a client is slow
+        let (frames, receiver) = mpsc::sync_channel(1);
+        thread::Builder::new()
+            .name("particle export".to_owned())
+            .spawn(move || serve(listener, receiver))
+            .context("unable to spawn the export thread")?;
+
+        Ok(Self {
+            stride: stride.max(1),
+            pipeline: pipeline::create_gather_pipeline(&display.device)?,
+            positions: None,
+            pending: None,
+            frames,
+        })
+    }
+
+    /// Send the previous readback if the gpu has finished it, then start
+    /// the next one behind the outstanding ticks. Never waits for the gpu.
+    pub fn update(
+        &mut self,
+        display: &Display,
+        particles: &mut Particles,
+    ) -> Result<()> {
+        if let Some(readback) = &self.pending {
+            match readback.future.wait(Some(Duration::from_secs(0))) {
+                Err(FlushError::Timeout) => return Ok(()),
+                result => result
+                    .map_err(ParticleError::submission)
+                    .context("unable to read back the particle positions")?,
+            }
+            let readback = self.pending.take().unwrap();
+            self.send(&readback.positions)?;
+            self.positions = Some(readback.positions);
+        }
+
+        self.pending = Some(self.start_readback(display, particles)?);
+        Ok(())
+    }
+
+    /// Gather every stride'th particle position into a cpu visible buffer.
+    fn start_readback(
+        &mut self,
+        display: &Display,
+        particles: &mut Particles,
+    ) -> Result<Readback> {
+        let count = (particles.particle_count() / self.stride).max(1);
+        let positions = match self.positions.take() {
+            Some(positions) if positions.len() == count as usize => positions,
+            _ => CpuAccessibleBuffer::from_iter(
+                display.device.clone(),
+                BufferUsage {
+                    storage_buffer: true,
+                    ..BufferUsage::none()
+                },
+                true,
+                (0..count).map(|_| [0.0, 0.0]),
+            )
+            .context("unable to create the export buffer")?,
+        };
+
+        let descriptor_set = pipeline::create_gather_descriptor_set(
+            &self.pipeline,
+            particles.vertex_buffer(),
+            &positions,
+        )?;
+        let groups =
+            (count + pipeline::LOCAL_SIZE_X - 1) / pipeline::LOCAL_SIZE_X;
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+            display.device.clone(),
+            display.compute_queue.family(),
+        )
+        .context("unable to create the gather command buffer builder")?;
+        builder
+            .dispatch(
+                [groups, 1, 1],
+                self.pipeline.clone(),
+                descriptor_set,
+                pipeline::PushConstants {
+                    stride: self.stride,
+                },
+            )
+            .context("unable to dispatch the gather pipeline")?;
+        let commands = builder
+            .build()
+            .context("unable to build the gather command buffer")?;
+
+        let future = particles
+            .then_execute_compute(display, commands)
+            .context("unable to submit the gather command buffer")?;
+
+        Ok(Readback { positions, future })
+    }
+
+    /// Encode the positions and hand them to the export thread.
+    fn send(&self, positions: &PositionBuffer) -> Result<()> {
+        let positions = positions
+            .read()
+            .context("unable to map the export buffer")?;
+        let mut frame = Vec::with_capacity(4 + positions.len() * 8);
+        frame.extend_from_slice(&(positions.len() as u32).to_le_bytes());
+        for [x, y] in positions.iter() {
+            frame.extend_from_slice(&x.to_le_bytes());
+            frame.extend_from_slice(&y.to_le_bytes());
+        }
+
+        match self.frames.try_send(frame) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => {
+                anyhow::bail!("the export thread has stopped")
+            }
+        }
+    }
+}
+
+/// Accept clients and write every frame to each of them, dropping clients
+/// once they disconnect.
+fn serve(listener: TcpListener, frames: Receiver<Vec<u8>>) {
+    let mut clients: Vec<TcpStream> = vec![];
+    for frame in frames {
+        while let Ok((client, address)) = listener.accept() {
+            if client.set_nonblocking(false).is_ok() {
+                log::info!("export client connected from {}", address);
+                clients.push(client);
+            }
+        }
+        clients.retain(|client| {
+            let mut writer: &TcpStream = client;
+            writer.write_all(&frame).is_ok()
+        });
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{application::particles::VertexBuffer, error::ParticleError};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use vulkano::{
+    buffer::CpuAccessibleBuffer,
+    descriptor::{
+        descriptor_set::PersistentDescriptorSet, DescriptorSet,
+        PipelineLayoutAbstract,
+    },
+    device::Device,
+    pipeline::{ComputePipeline, ComputePipelineAbstract},
+};
+
+pub type PushConstants = gather_shader::ty::PushConstants;
+
+/// The gather shader's workgroup size, must match `local_size_x`.
+pub const LOCAL_SIZE_X: u32 = 64;
+
+pub fn create_gather_pipeline(
+    device: &Arc<Device>,
+) -> Result<Arc<dyn ComputePipelineAbstract + Send + Sync>> {
+    let gather = gather_shader::Shader::load(device.clone())
+        .map_err(ParticleError::shader_load("gather"))?;
+    Ok(Arc::new(
+        ComputePipeline::new(
+            device.clone(),
+            &gather.main_entry_point(),
+            &(),
+            None,
+        )
+        .context("unable to build the gather pipeline")?,
+    ))
+}
+
+pub fn create_gather_descriptor_set(
+    pipeline: &Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    vertices: &Arc<VertexBuffer>,
+    positions: &Arc<CpuAccessibleBuffer<[[f32; 2]]>>,
+) -> Result<Arc<dyn DescriptorSet + Send + Sync>> {
+    let layout = pipeline
+        .descriptor_set_layout(0)
+        .context("unable to get the gather pipeline's descriptor layout")?;
+    Ok(Arc::new(
+        PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(vertices.clone())
+            .context("unable to bind the particle buffer")?
+            .add_buffer(positions.clone())
+            .context("unable to bind the positions buffer")?
+            .build()
+            .context("unable to build the gather descriptor set")?,
+    ))
+}
+
+mod gather_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        src: r#"
+        #version 450
+
+        layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+        // must match the Vertex used by the particle compute shader
+        struct Vertex {
+            vec2 pos;
+            vec2 vel;
+            vec2 home;
+            uint dead;
+            uint species;
+        };
+
+        layout(set = 0, binding = 0) readonly buffer Data {
+            Vertex vertices[];
+        } data;
+
+        layout(set = 0, binding = 1) writeonly buffer Positions {
+            vec2 positions[];
+        } gathered;
+
+        layout(push_constant) uniform PushConstants {
+            uint stride;
+        } pc;
+
+        // copy the position of every stride'th particle into a tightly
+        // packed buffer which is cheap to read back
+        void main() {
+            uint idx = gl_GlobalInvocationID.x;
+            if (idx >= uint(gathered.positions.length())) {
+                return;
+            }
+            gathered.positions[idx] = data.vertices[idx * pc.stride].pos;
+        }
+        "#
+    }
+}
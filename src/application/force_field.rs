@@ -0,0 +1,179 @@
+mod pipeline;
+
+use super::{
+    kinematic_particle,
+    particles::{self, PushConstants, Region, Simulation, View, WorldShape},
+};
+use crate::{display::Display, math::lerp};
+use anyhow::{Context, Result};
+use pipeline::LineVertex;
+use std::sync::Arc;
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{
+        AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState,
+    },
+    framebuffer::Subpass,
+    pipeline::GraphicsPipelineAbstract,
+};
+
+type Vec2 = nalgebra::Vector2<f32>;
+
+/// The number of arrows drawn along each axis of the world.
+const GRID_SIZE: [usize; 2] = [32, 16];
+
+/// The longest an arrow can be, in world units.
+const MAX_ARROW_LENGTH: f32 = 0.1;
+
+/// A debug layer which draws arrows showing the direction and magnitude of
+/// the forces acting on particles across the world.
+pub struct ForceField {
+    region: Region,
+    shape: WorldShape,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl ForceField {
+    pub fn new(
+        display: &Display,
+        region: Region,
+        shape: WorldShape,
+    ) -> Result<Self> {
+        let pipeline = pipeline::create_line_pipeline(
+            &display.device,
+            shape.letterbox(region.viewport(display.swapchain.dimensions())),
+            &display.render_pass,
+        )?;
+        Ok(Self {
+            region,
+            shape,
+            pipeline,
+        })
+    }
+
+    pub fn rebuild_swapchain_resources(
+        &mut self,
+        display: &Display,
+    ) -> Result<()> {
+        self.pipeline = pipeline::create_line_pipeline(
+            &display.device,
+            self.shape.letterbox(
+                self.region.viewport(display.swapchain.dimensions()),
+            ),
+            &display.render_pass,
+        )?;
+        Ok(())
+    }
+
+    /// Draw the force field produced by the given compute parameters.
+    ///
+    /// Forces are evaluated with the CPU reference model so the arrows
+    /// always agree with the physics in the compute shader.
+    pub fn draw(
+        &self,
+        display: &Display,
+        push_constants: &PushConstants,
+        simulation: &Simulation,
+        view: &View,
+    ) -> Result<AutoCommandBuffer> {
+        let lines = build_arrows(push_constants, simulation);
+        let vertex_buffer = CpuAccessibleBuffer::from_iter(
+            display.device.clone(),
+            BufferUsage::vertex_buffer(),
+            false,
+            lines.into_iter(),
+        )
+        .context("unable to create the force field vertex buffer")?;
+
+        let constants = pipeline::PushConstants {
+            projection: particles::world_projection(simulation.bounds, view)
+                .into(),
+        };
+
+        let mut builder =
+            AutoCommandBufferBuilder::secondary_graphics_one_time_submit(
+                display.device.clone(),
+                display.graphics_queue.family(),
+                Subpass::from(display.render_pass.clone(), 0).with_context(
+                    || "unable to select subpass for the force field",
+                )?,
+            )
+            .with_context(|| "unable to create the command buffer builder")?;
+        builder
+            .draw(
+                self.pipeline.clone(),
+                &DynamicState::none(),
+                vec![vertex_buffer],
+                (),
+                constants,
+            )
+            .with_context(|| "unable to issue the force field draw command")?;
+        builder
+            .build()
+            .with_context(|| "unable to build the command buffer")
+    }
+}
+
+/// Build a line list with one arrow per grid cell.
+fn build_arrows(
+    push_constants: &PushConstants,
+    simulation: &Simulation,
+) -> Vec<LineVertex> {
+    let [columns, rows] = GRID_SIZE;
+    let [half_width, half_height] = simulation.bounds;
+    let mut lines = Vec::with_capacity(columns * rows * 6);
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let pos = Vec2::new(
+                lerp(
+                    (column as f32 + 0.5) / columns as f32,
+                    -half_width,
+                    half_width,
+                ),
+                lerp(
+                    (row as f32 + 0.5) / rows as f32,
+                    -half_height,
+                    half_height,
+                ),
+            );
+            // the arrows show the forces on the first species
+            let force = kinematic_particle::acceleration(
+                pos,
+                0,
+                push_constants,
+                simulation,
+            );
+            let magnitude = force.norm();
+            if !magnitude.is_finite() || magnitude < 1e-6 {
+                continue;
+            }
+
+            // compress the huge range of the inverse-square law so both near
+            // and far arrows are readable
+            let scale = magnitude / (1.0 + magnitude);
+            let dir = force / magnitude;
+            let tip = pos + dir * scale * MAX_ARROW_LENGTH;
+            let side = Vec2::new(-dir.y, dir.x) * 0.3;
+            let head = MAX_ARROW_LENGTH * 0.3 * scale;
+            let color = [scale, 1.0 - scale, 0.2, 0.8];
+
+            for (a, b) in &[
+                (pos, tip),
+                (tip, tip - (dir + side) * head),
+                (tip, tip - (dir - side) * head),
+            ] {
+                lines.push(LineVertex {
+                    pos: [a.x, a.y],
+                    color,
+                });
+                lines.push(LineVertex {
+                    pos: [b.x, b.y],
+                    color,
+                });
+            }
+        }
+    }
+
+    lines
+}
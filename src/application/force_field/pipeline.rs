@@ -0,0 +1,90 @@
+use crate::error::ParticleError;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use vulkano::{
+    device::Device,
+    framebuffer::{RenderPassAbstract, Subpass},
+    pipeline::{
+        viewport::Viewport, GraphicsPipeline, GraphicsPipelineAbstract,
+    },
+};
+
+type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
+pub type PushConstants = vertex_shader::ty::PushConstants;
+
+#[derive(Default, Debug, Copy, Clone)]
+pub struct LineVertex {
+    pub pos: [f32; 2],
+    pub color: [f32; 4],
+}
+vulkano::impl_vertex!(LineVertex, pos, color);
+
+pub fn create_line_pipeline(
+    device: &Arc<Device>,
+    viewport: Viewport,
+    render_pass: &Arc<DynRenderPass>,
+) -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>> {
+    let vert = vertex_shader::Shader::load(device.clone())
+        .map_err(ParticleError::shader_load("line vertex"))?;
+    let frag = fragment_shader::Shader::load(device.clone())
+        .map_err(ParticleError::shader_load("line fragment"))?;
+
+    let pipeline = GraphicsPipeline::start()
+        .vertex_input_single_buffer::<LineVertex>()
+        .vertex_shader(vert.main_entry_point(), ())
+        .fragment_shader(frag.main_entry_point(), ())
+        .viewports(vec![viewport])
+        .depth_clamp(false)
+        .depth_write(false)
+        .blend_alpha_blending()
+        .line_list()
+        .render_pass(
+            Subpass::from(render_pass.clone(), 0)
+                .context("could not create the line pipeline subpass")?,
+        )
+        .build(device.clone())
+        .context("could not create the line pipeline")?;
+
+    Ok(Arc::new(pipeline))
+}
+
+mod vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            layout(location = 0) in vec2 pos;
+            layout(location = 1) in vec4 color;
+
+            layout(location = 0) out vec4 vertColor;
+
+            layout(push_constant) uniform PushConstants {
+                mat4 projection;
+            } pc;
+
+            void main() {
+                vertColor = color;
+                gl_Position = pc.projection * vec4(pos, 0.0, 1.0);
+            }
+            "#
+    }
+}
+
+mod fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            layout(location = 0) in vec4 fragColor;
+            layout(location = 0) out vec4 outColor;
+
+            void main() {
+               outColor = fragColor;
+            }
+            "#
+    }
+}
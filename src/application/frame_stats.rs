@@ -0,0 +1,54 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How far back the rolling average looks.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Tracks when recent frames finished so the framerate can be shown.
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    times: VecDeque<Instant>,
+}
+
+impl FrameStats {
+    /// Note that a frame finished at the given time.
+    pub fn record(&mut self, now: Instant) {
+        self.times.push_back(now);
+        while let Some(&oldest) = self.times.front() {
+            if now - oldest <= WINDOW || self.times.len() <= 2 {
+                break;
+            }
+            self.times.pop_front();
+        }
+    }
+
+    /// The framerate implied by the time between the last two frames.
+    pub fn instantaneous_fps(&self) -> f32 {
+        let mut recent = self.times.iter().rev();
+        match (recent.next(), recent.next()) {
+            (Some(&last), Some(&previous)) => fps(last - previous, 1),
+            _ => 0.0,
+        }
+    }
+
+    /// The average framerate over roughly the last second.
+    pub fn average_fps(&self) -> f32 {
+        match (self.times.front(), self.times.back()) {
+            (Some(&first), Some(&last)) if self.times.len() > 1 => {
+                fps(last - first, self.times.len() - 1)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+fn fps(elapsed: Duration, frames: usize) -> f32 {
+    let seconds = elapsed.as_secs_f32();
+    if seconds > 0.0 {
+        frames as f32 / seconds
+    } else {
+        0.0
+    }
+}
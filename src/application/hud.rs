@@ -0,0 +1,281 @@
+mod pipeline;
+
+use crate::{display::Display, error::ParticleError};
+use anyhow::{Context, Result};
+use glyph_brush::{
+    ab_glyph::FontArc, BrushAction, BrushError, GlyphBrush, GlyphBrushBuilder,
+    Section, Text,
+};
+use pipeline::GlyphVertex;
+use std::sync::Arc;
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{
+        AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState,
+    },
+    descriptor::{
+        descriptor_set::PersistentDescriptorSet, DescriptorSet,
+        PipelineLayoutAbstract,
+    },
+    format::Format,
+    image::{Dimensions, ImmutableImage, MipmapsCount},
+    pipeline::{viewport::Viewport, GraphicsPipelineAbstract},
+    sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode},
+    sync::GpuFuture,
+};
+
+/// Both triangles of a single glyph's quad.
+type GlyphQuad = [GlyphVertex; 6];
+
+const FONT: &[u8] = include_bytes!("../../assets/fonts/DejaVuSansMono.ttf");
+
+/// The height of the hud's text, in pixels.
+const TEXT_SCALE: f32 = 18.0;
+
+/// The distance between the hud and the top left corner of the window, in
+/// pixels.
+const MARGIN: f32 = 16.0;
+
+const TEXT_COLOR: [f32; 4] = [0.9, 0.9, 0.9, 0.9];
+
+/// A read-only text overlay drawn on top of the scene.
+///
+/// Glyphs are rasterized on the CPU by glyph_brush into a coverage cache,
+/// which is uploaded to an image whenever new glyphs are added to it. The
+/// text is laid out again only when it changes.
+pub struct Hud {
+    brush: GlyphBrush<GlyphQuad>,
+    cache: Vec<u8>,
+    cache_changed: bool,
+    quads: Vec<GlyphQuad>,
+    sampler: Arc<Sampler>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    descriptor_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+}
+
+impl Hud {
+    pub fn new(display: &Display) -> Result<Self> {
+        let font = FontArc::try_from_slice(FONT)
+            .context("unable to load the hud font")?;
+        let brush = GlyphBrushBuilder::using_font(font).build();
+        let (width, height) = brush.texture_dimensions();
+        let sampler = Sampler::new(
+            display.device.clone(),
+            Filter::Linear,
+            Filter::Linear,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )
+        .context("unable to create the glyph sampler")?;
+
+        Ok(Self {
+            brush,
+            cache: vec![0; (width * height) as usize],
+            cache_changed: true,
+            quads: vec![],
+            sampler,
+            pipeline: create_pipeline(display)?,
+            descriptor_set: None,
+        })
+    }
+
+    pub fn rebuild_swapchain_resources(
+        &mut self,
+        display: &Display,
+    ) -> Result<()> {
+        self.pipeline = create_pipeline(display)?;
+        self.descriptor_set = None;
+        Ok(())
+    }
+
+    /// Draw the text in the top left corner of the window. Returns nothing
+    /// when there's nothing visible to draw.
+    ///
+    /// The command buffer is built for the display's overlay subpass.
+    pub fn draw(
+        &mut self,
+        display: &Display,
+        text: &str,
+    ) -> Result<Option<AutoCommandBuffer>> {
+        self.brush.queue(
+            Section::default()
+                .with_screen_position((MARGIN, MARGIN))
+                .add_text(
+                    Text::new(text)
+                        .with_scale(TEXT_SCALE)
+                        .with_color(TEXT_COLOR),
+                ),
+        );
+        self.process_queued();
+        if self.quads.is_empty() {
+            return Ok(None);
+        }
+        let descriptor_set = self.upload_cache(display)?;
+
+        let vertex_buffer = CpuAccessibleBuffer::from_iter(
+            display.device.clone(),
+            BufferUsage::vertex_buffer(),
+            false,
+            self.quads.iter().flatten().copied(),
+        )
+        .context("unable to create the hud vertex buffer")?;
+
+        let [width, height] = display.swapchain.dimensions();
+        let constants = pipeline::PushConstants {
+            screen_size: [width as f32, height as f32],
+        };
+
+        let mut builder =
+            AutoCommandBufferBuilder::secondary_graphics_one_time_submit(
+                display.device.clone(),
+                display.graphics_queue.family(),
+                display.overlay_subpass()?,
+            )
+            .with_context(|| "unable to create the command buffer builder")?;
+        builder
+            .draw(
+                self.pipeline.clone(),
+                &DynamicState::none(),
+                vec![vertex_buffer],
+                descriptor_set,
+                constants,
+            )
+            .with_context(|| "unable to issue the hud draw command")?;
+        Ok(Some(
+            builder
+                .build()
+                .with_context(|| "unable to build the command buffer")?,
+        ))
+    }
+
+    /// Lay out the queued text, growing the glyph cache until everything
+    /// fits.
+    fn process_queued(&mut self) {
+        loop {
+            let (cache_width, _) = self.brush.texture_dimensions();
+            let cache = &mut self.cache;
+            let cache_changed = &mut self.cache_changed;
+            let action = self.brush.process_queued(
+                |rect, pixels| {
+                    let width = rect.width() as usize;
+                    for (row, line) in pixels.chunks(width).enumerate() {
+                        let start = (rect.min[1] as usize + row)
+                            * cache_width as usize
+                            + rect.min[0] as usize;
+                        cache[start..start + width].copy_from_slice(line);
+                    }
+                    *cache_changed = true;
+                },
+                to_quad,
+            );
+            match action {
+                Ok(BrushAction::Draw(quads)) => {
+                    self.quads = quads;
+                    return;
+                }
+                Ok(BrushAction::ReDraw) => return,
+                Err(BrushError::TextureTooSmall {
+                    suggested: (width, height),
+                }) => {
+                    log::debug!(
+                        "growing the glyph cache to {}x{}",
+                        width,
+                        height
+                    );
+                    self.brush.resize_texture(width, height);
+                    self.cache = vec![0; (width * height) as usize];
+                    self.cache_changed = true;
+                }
+            }
+        }
+    }
+
+    /// Copy the glyph cache into an image if it has changed since the last
+    /// upload.
+    fn upload_cache(
+        &mut self,
+        display: &Display,
+    ) -> Result<Arc<dyn DescriptorSet + Send + Sync>> {
+        if let (false, Some(set)) = (self.cache_changed, &self.descriptor_set) {
+            return Ok(set.clone());
+        }
+
+        let (width, height) = self.brush.texture_dimensions();
+        let (image, future) = ImmutableImage::from_iter(
+            self.cache.iter().copied(),
+            Dimensions::Dim2d { width, height },
+            MipmapsCount::One,
+            Format::R8Unorm,
+            display.graphics_queue.clone(),
+        )
+        .context("unable to create the glyph cache image")?;
+        future
+            .then_signal_fence_and_flush()
+            .map_err(ParticleError::submission)
+            .context("unable to upload the glyph cache")?
+            .wait(None)
+            .map_err(ParticleError::submission)
+            .context("glyph cache upload timeout")?;
+
+        let layout = self
+            .pipeline
+            .descriptor_set_layout(0)
+            .context("unable to get the hud descriptor set layout")?;
+        let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_sampled_image(image, self.sampler.clone())
+                .context("unable to bind the glyph cache")?
+                .build()
+                .context("unable to build the hud descriptor set")?,
+        );
+        self.descriptor_set = Some(set.clone());
+        self.cache_changed = false;
+        Ok(set)
+    }
+}
+
+fn create_pipeline(
+    display: &Display,
+) -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>> {
+    let [width, height] = display.swapchain.dimensions();
+    let viewport = Viewport {
+        origin: [0.0, 0.0],
+        dimensions: [width as f32, height as f32],
+        depth_range: 0.0..1.0,
+    };
+    pipeline::create_text_pipeline(
+        &display.device,
+        viewport,
+        display.overlay_subpass()?,
+    )
+}
+
+/// Build the two triangles which cover a glyph.
+fn to_quad(glyph: glyph_brush::GlyphVertex) -> GlyphQuad {
+    let pixels = glyph.pixel_coords;
+    let uv = glyph.tex_coords;
+    let color = glyph.extra.color;
+    let corner = |x: f32, y: f32, u: f32, v: f32| GlyphVertex {
+        pos: [x, y],
+        uv: [u, v],
+        color,
+    };
+    let top_left = corner(pixels.min.x, pixels.min.y, uv.min.x, uv.min.y);
+    let top_right = corner(pixels.max.x, pixels.min.y, uv.max.x, uv.min.y);
+    let bottom_left = corner(pixels.min.x, pixels.max.y, uv.min.x, uv.max.y);
+    let bottom_right = corner(pixels.max.x, pixels.max.y, uv.max.x, uv.max.y);
+    [
+        top_left,
+        top_right,
+        bottom_left,
+        top_right,
+        bottom_right,
+        bottom_left,
+    ]
+}
@@ -0,0 +1,101 @@
+use crate::error::ParticleError;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use vulkano::{
+    device::Device,
+    framebuffer::{RenderPassAbstract, Subpass},
+    pipeline::{
+        viewport::Viewport, GraphicsPipeline, GraphicsPipelineAbstract,
+    },
+};
+
+type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
+pub type PushConstants = vertex_shader::ty::PushConstants;
+
+/// A corner of a glyph's quad, positioned in pixels from the top left of
+/// the window.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct GlyphVertex {
+    pub pos: [f32; 2],
+    pub uv: [f32; 2],
+    pub color: [f32; 4],
+}
+vulkano::impl_vertex!(GlyphVertex, pos, uv, color);
+
+pub fn create_text_pipeline(
+    device: &Arc<Device>,
+    viewport: Viewport,
+    subpass: Subpass<Arc<DynRenderPass>>,
+) -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>> {
+    let vert = vertex_shader::Shader::load(device.clone())
+        .map_err(ParticleError::shader_load("text vertex"))?;
+    let frag = fragment_shader::Shader::load(device.clone())
+        .map_err(ParticleError::shader_load("text fragment"))?;
+
+    let pipeline = GraphicsPipeline::start()
+        .vertex_input_single_buffer::<GlyphVertex>()
+        .vertex_shader(vert.main_entry_point(), ())
+        .fragment_shader(frag.main_entry_point(), ())
+        .viewports(vec![viewport])
+        .depth_clamp(false)
+        .depth_write(false)
+        .blend_alpha_blending()
+        .triangle_list()
+        .render_pass(subpass)
+        .build(device.clone())
+        .context("could not create the text pipeline")?;
+
+    Ok(Arc::new(pipeline))
+}
+
+mod vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            layout(location = 0) in vec2 pos;
+            layout(location = 1) in vec2 uv;
+            layout(location = 2) in vec4 color;
+
+            layout(location = 0) out vec2 fragUv;
+            layout(location = 1) out vec4 fragColor;
+
+            layout(push_constant) uniform PushConstants {
+                vec2 screen_size;
+            } pc;
+
+            void main() {
+                fragUv = uv;
+                fragColor = color;
+                // pixels from the top left, to vulkan's clip space which
+                // also has y pointing down
+                gl_Position = vec4(pos / pc.screen_size * 2.0 - 1.0, 0.0, 1.0);
+            }
+            "#
+    }
+}
+
+mod fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            layout(location = 0) in vec2 fragUv;
+            layout(location = 1) in vec4 fragColor;
+
+            layout(set = 0, binding = 0) uniform sampler2D glyphs;
+
+            layout(location = 0) out vec4 outColor;
+
+            void main() {
+                // the glyph cache only stores coverage
+                float coverage = texture(glyphs, fragUv).r;
+                outColor = vec4(fragColor.rgb, fragColor.a * coverage);
+            }
+            "#
+    }
+}
@@ -0,0 +1,271 @@
+use super::particles::{PushConstants, Simulation, Vertex};
+use crate::math::clamp;
+
+type Vec2 = nalgebra::Vector2<f32>;
+
+/// The acceleration applied by the brush, must match `BRUSH_FORCE` in the
+/// compute shader.
+pub const BRUSH_FORCE: f32 = 5.0;
+
+/// A CPU reference for the compute shader's per-particle update.
+///
+/// This documents the intended physics and can be used to check the shader
+/// against a known result without a GPU. Jitter and the sink are not
+/// modelled because they depend on the shader's hash function, and the
+/// force texture is not modelled because it's only on the GPU.
+///
+/// Only the forces are used outside of tests, by the force field overlay.
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Particle {
+    pub pos: Vec2,
+    pub vel: Vec2,
+    pub home: Vec2,
+    pub species: u32,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl Particle {
+    /// Create a particle of the first species whose home is its starting
+    /// position.
+    pub fn new(pos: Vec2, vel: Vec2) -> Self {
+        Self {
+            pos,
+            vel,
+            home: pos,
+            species: 0,
+        }
+    }
+
+    /// Advance the particle by one tick, mirroring `main()` in the compute
+    /// shader.
+    pub fn integrate(&mut self, pc: &PushConstants, sim: &Simulation) {
+        let magnetic =
+            Vec2::new(-self.vel.y, self.vel.x) * sim.magnetic_strength;
+        self.vel += (acceleration(self.pos, self.species, pc, sim) + magnetic)
+            * pc.timestep;
+        if pc.relax_enabled != 0 {
+            self.vel +=
+                (self.home - self.pos) * sim.relax_strength * pc.timestep;
+        }
+        self.clamp_vel(sim);
+        if sim.drag_mode == 1 {
+            let speed = self.vel.norm();
+            self.vel -= self.vel * speed * sim.drag_coeff * pc.timestep;
+        } else {
+            self.vel.component_mul_assign(&Vec2::from(sim.damping));
+        }
+        if sim.slow_radius > 0.0 {
+            let diff = Vec2::from(pc.slow_center) - self.pos;
+            if diff.dot(&diff) < sim.slow_radius * sim.slow_radius {
+                self.vel *= sim.slow_factor;
+            }
+        }
+        self.pos += self.vel * pc.timestep;
+        self.clamp_pos(sim);
+    }
+
+    /// Mirrors `clamp_velocity` in the compute shader.
+    fn clamp_vel(&mut self, sim: &Simulation) {
+        if self.vel.dot(&self.vel) > sim.max_vel * sim.max_vel {
+            self.vel = self.vel.normalize() * sim.max_vel;
+        }
+    }
+
+    /// Mirrors `clamp_to_bounds` in the compute shader.
+    fn clamp_pos(&mut self, sim: &Simulation) {
+        if sim.boundary_shape == 0 && sim.wrap != 0 {
+            self.pos.x = wrap(self.pos.x, sim.bounds[0]);
+            self.pos.y = wrap(self.pos.y, sim.bounds[1]);
+            return;
+        }
+        if sim.boundary_shape == 1 {
+            let dist = self.pos.norm();
+            if dist > sim.boundary_radius {
+                self.pos *= sim.boundary_radius / dist;
+            }
+            return;
+        }
+        self.pos.x = clamp(self.pos.x, -sim.bounds[0], sim.bounds[0]);
+        self.pos.y = clamp(self.pos.y, -sim.bounds[1], sim.bounds[1]);
+    }
+}
+
+impl From<Particle> for Vertex {
+    /// Convert the reference particle into the layout the compute shader
+    /// steps, so CPU results can be uploaded and compared against the GPU.
+    fn from(particle: Particle) -> Self {
+        Self {
+            home: particle.home.into(),
+            ..Vertex::new(
+                particle.pos.into(),
+                particle.vel.into(),
+                particle.species,
+            )
+        }
+    }
+}
+
+/// Wrap x into [-bound, bound), matching glsl's `mod` which always has the
+/// sign of the divisor.
+#[cfg_attr(not(test), allow(dead_code))]
+fn wrap(x: f32, bound: f32) -> f32 {
+    (x + bound).rem_euclid(2.0 * bound) - bound
+}
+
+/// Mirrors `swirl` in the compute shader.
+fn swirl(dir: Vec2, dist: f32, sim: &Simulation) -> Vec2 {
+    Vec2::new(-dir.y, dir.x) * sim.swirl / (dist + sim.eps)
+}
+
+/// The acceleration applied to a particle of the species at the given
+/// position, mirrors the forces in the compute shader.
+pub fn acceleration(
+    pos: Vec2,
+    species: u32,
+    pc: &PushConstants,
+    sim: &Simulation,
+) -> Vec2 {
+    let mut total = Vec2::zeros();
+    let strength = sim.strength * sim.species_strength[species as usize];
+    if pc.enabled != 0 {
+        let diff = Vec2::from(pc.attractor) - pos;
+        let dir = diff.normalize();
+        let d2 = diff.dot(&diff);
+        if sim.brush_mode == 0 {
+            total += strength * dir / (d2 + sim.eps);
+            total += swirl(dir, diff.norm(), sim);
+        } else if d2 < sim.brush_radius * sim.brush_radius && d2 > 1e-12 {
+            let sign = if sim.brush_mode == 1 { 1.0 } else { -1.0 };
+            total += sign * strength * BRUSH_FORCE * dir;
+        }
+    }
+    for attractor in &sim.attractors[..sim.attractor_count as usize] {
+        let diff = Vec2::new(attractor[0], attractor[1]) - pos;
+        total += strength * diff.normalize() / (diff.dot(&diff) + sim.eps);
+        total += swirl(diff.normalize(), diff.norm(), sim);
+    }
+    if pc.repel_enabled != 0 {
+        let diff = Vec2::from(pc.repulsor) - pos;
+        total -= strength * diff.normalize() / (diff.dot(&diff) + sim.eps);
+    }
+    if pc.lasso_enabled != 0 {
+        let diff = Vec2::from(pc.lasso_center) - pos;
+        if diff.dot(&diff) < sim.lasso_radius * sim.lasso_radius {
+            total += diff * sim.lasso_strength;
+        }
+    }
+    for wave in &sim.shockwaves[..sim.shockwave_count as usize] {
+        let diff = pos - Vec2::new(wave[0], wave[1]);
+        let dist = diff.norm();
+        if dist > 1e-6 && (dist - wave[2]).abs() < sim.shockwave_thickness {
+            total += diff / dist * wave[3];
+        }
+    }
+    if sim.center_gravity != 0.0 && pos.dot(&pos) > 1e-12 {
+        total += -pos.normalize() * sim.center_gravity;
+    }
+    total += Vec2::from(sim.gravity);
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A simulation with no forces, so only the velocity limit and damping
+    /// act on a particle.
+    fn still_simulation() -> Simulation {
+        Simulation {
+            bounds: [10.0, 10.0],
+            damping: [1.0, 1.0],
+            max_vel: 100.0,
+            ..Default::default()
+        }
+    }
+
+    fn push_constants(timestep: f32) -> PushConstants {
+        PushConstants {
+            timestep,
+            ..Default::default()
+        }
+    }
+
+    fn assert_near(actual: Vec2, expected: Vec2) {
+        assert!(
+            (actual - expected).norm() < 1e-5,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn integrate_clamps_velocity_to_the_speed_limit() {
+        let sim = Simulation {
+            max_vel: 1.0,
+            ..still_simulation()
+        };
+        let mut particle = Particle::new(Vec2::zeros(), Vec2::new(3.0, 4.0));
+        particle.integrate(&push_constants(0.5), &sim);
+        assert_near(particle.vel, Vec2::new(0.6, 0.8));
+        assert_near(particle.pos, Vec2::new(0.3, 0.4));
+    }
+
+    #[test]
+    fn integrate_damps_each_axis_after_clamping() {
+        let sim = Simulation {
+            damping: [0.5, 0.8],
+            ..still_simulation()
+        };
+        let mut particle = Particle::new(Vec2::zeros(), Vec2::new(2.0, 1.0));
+        particle.integrate(&push_constants(0.25), &sim);
+        assert_near(particle.vel, Vec2::new(1.0, 0.8));
+        assert_near(particle.pos, Vec2::new(0.25, 0.2));
+
+        // the limit applies before damping, so a clamped particle still
+        // slows down
+        let sim = Simulation {
+            max_vel: 1.0,
+            ..sim
+        };
+        let mut particle = Particle::new(Vec2::zeros(), Vec2::new(2.0, 0.0));
+        particle.integrate(&push_constants(0.25), &sim);
+        assert_near(particle.vel, Vec2::new(0.5, 0.0));
+    }
+
+    #[test]
+    fn one_step_towards_an_attractor_follows_the_inverse_square_law() {
+        let mut attractors = still_simulation().attractors;
+        attractors[0] = [2.0, 0.0, 0.0, 0.0];
+        let sim = Simulation {
+            strength: 1.0,
+            species_strength: [1.0, 1.0],
+            attractors,
+            attractor_count: 1,
+            ..still_simulation()
+        };
+        let mut particle = Particle::new(Vec2::zeros(), Vec2::zeros());
+        particle.integrate(&push_constants(0.1), &sim);
+        // a unit of strength at a distance of 2 accelerates by 1/4
+        assert_near(particle.vel, Vec2::new(0.025, 0.0));
+        assert_near(particle.pos, Vec2::new(0.0025, 0.0));
+    }
+
+    #[test]
+    fn one_step_past_the_edge_clamps_or_wraps() {
+        let sim = Simulation {
+            bounds: [1.0, 1.0],
+            ..still_simulation()
+        };
+        let start = Particle::new(Vec2::new(0.95, 0.0), Vec2::new(1.0, 0.0));
+        let mut particle = start;
+        particle.integrate(&push_constants(0.1), &sim);
+        assert_near(particle.pos, Vec2::new(1.0, 0.0));
+
+        let sim = Simulation { wrap: 1, ..sim };
+        let mut particle = start;
+        particle.integrate(&push_constants(0.1), &sim);
+        assert_near(particle.pos, Vec2::new(-0.95, 0.0));
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::{Context, Result};
+use rosc::{OscPacket, OscType};
+use std::{
+    net::{IpAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// The latest attractor controls received from an external OSC controller.
+///
+/// Each field is cleared once the main loop consumes it so only new values
+/// override local input.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct OscState {
+    /// The attractor position normalized to [0, 1] on each axis, with y
+    /// pointing up.
+    pub xy: Option<[f32; 2]>,
+
+    /// The attractor strength.
+    pub strength: Option<f32>,
+
+    /// Whether the attractor is pulling, like holding the mouse button.
+    pub active: Option<bool>,
+}
+
+pub type SharedOscState = Arc<Mutex<OscState>>;
+
+/// Listen for OSC messages on a background thread.
+///
+/// Recognized addresses are `/attractor/xy` (two floats),
+/// `/attractor/strength` (one float), and `/attractor/active` (one number).
+pub fn spawn_listener(address: IpAddr, port: u16) -> Result<SharedOscState> {
+    let socket = UdpSocket::bind((address, port)).with_context(|| {
+        format!("unable to bind the osc port {}:{}", address, port)
+    })?;
+    log::info!("listening for osc messages on {}:{}", address, port);
+
+    let state = SharedOscState::default();
+    let shared = state.clone();
+    thread::Builder::new()
+        .name("osc listener".to_owned())
+        .spawn(move || {
+            let mut buffer = [0u8; rosc::decoder::MTU];
+            loop {
+                let size = match socket.recv_from(&mut buffer) {
+                    Ok((size, _)) => size,
+                    Err(error) => {
+                        log::error!("osc socket failed {:?}", error);
+                        return;
+                    }
+                };
+                match rosc::decoder::decode(&buffer[..size]) {
+                    Ok(packet) => handle_packet(&shared, packet),
+                    Err(error) => {
+                        log::warn!("ignoring malformed osc packet {:?}", error)
+                    }
+                }
+            }
+        })
+        .context("unable to spawn the osc listener thread")?;
+
+    Ok(state)
+}
+
+fn handle_packet(state: &SharedOscState, packet: OscPacket) {
+    match packet {
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                handle_packet(state, packet);
+            }
+        }
+        OscPacket::Message(message) => {
+            let mut state = match state.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            let args: Vec<f32> =
+                message.args.iter().filter_map(as_float).collect();
+            match (message.addr.as_str(), args.as_slice()) {
+                ("/attractor/xy", [x, y]) => state.xy = Some([*x, *y]),
+                ("/attractor/strength", [strength]) => {
+                    state.strength = Some(*strength)
+                }
+                ("/attractor/active", [active]) => {
+                    state.active = Some(*active != 0.0)
+                }
+                (addr, _) => {
+                    log::debug!("unhandled osc message {} {:?}", addr, args)
+                }
+            }
+        }
+    }
+}
+
+fn as_float(arg: &OscType) -> Option<f32> {
+    match arg {
+        OscType::Float(value) => Some(*value),
+        OscType::Double(value) => Some(*value as f32),
+        OscType::Int(value) => Some(*value as f32),
+        OscType::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
@@ -0,0 +1,481 @@
+use crate::math::clamp;
+use serde::Serialize;
+
+/// How particles lose velocity over time.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum DragMode {
+    /// Multiply the velocity by `damping` every tick.
+    Linear,
+
+    /// Slow particles in proportion to the square of their speed, scaled by
+    /// `drag_coeff`, so fast particles slow down more than slow ones.
+    Quadratic,
+}
+
+impl DragMode {
+    /// The value the compute shader uses to identify this mode.
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            DragMode::Linear => 0,
+            DragMode::Quadratic => 1,
+        }
+    }
+}
+
+/// How the cursor pushes particles around.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum BrushMode {
+    /// A point attractor whose pull falls off with the inverse square of the
+    /// distance.
+    Point,
+
+    /// A uniform pull toward the cursor for particles within `brush_radius`.
+    Attract,
+
+    /// A uniform push away from the cursor for particles within
+    /// `brush_radius`.
+    Repel,
+}
+
+impl BrushMode {
+    /// The value the compute shader uses to identify this mode.
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            BrushMode::Point => 0,
+            BrushMode::Attract => 1,
+            BrushMode::Repel => 2,
+        }
+    }
+}
+
+/// The shape of the wall which keeps particles inside the world.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum BoundaryShape {
+    /// The edges of the visible world.
+    Rect,
+
+    /// A circle centered on the origin, like a petri dish.
+    Circle,
+}
+
+impl BoundaryShape {
+    /// The value the compute shader uses to identify this shape.
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            BoundaryShape::Rect => 0,
+            BoundaryShape::Circle => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for BoundaryShape {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "rect" => Ok(BoundaryShape::Rect),
+            "circle" => Ok(BoundaryShape::Circle),
+            _ => anyhow::bail!(
+                "unknown boundary shape {:?}, expected rect or circle",
+                name
+            ),
+        }
+    }
+}
+
+/// Tunable simulation parameters which are forwarded to the compute shader
+/// on every tick.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Parameters {
+    /// Scales the pull of the attractor.
+    pub strength: f32,
+
+    /// Softens the attractor's singularity. Smaller values give a more
+    /// violent pull close to the attractor.
+    pub eps: f32,
+
+    /// The fraction of each particle's velocity which is kept every tick,
+    /// for the x and y axes respectively. Unequal values give anisotropic
+    /// motion, like particles settling into horizontal bands.
+    pub damping: [f32; 2],
+
+    /// The fastest a particle can move, in world units per second. Also
+    /// sets the speed at which particles are drawn with their fastest
+    /// color.
+    pub max_vel: f32,
+
+    /// Selects between linear damping and quadratic drag.
+    pub drag_mode: DragMode,
+
+    /// The strength of the quadratic drag.
+    pub drag_coeff: f32,
+
+    /// How far particles are randomly nudged, the largest nudge in a tick is
+    /// this many world units times the square root of the timestep. Keeps
+    /// particles from collapsing onto a single point. Zero disables the
+    /// jitter entirely.
+    pub jitter_strength: f32,
+
+    /// Particles which come within this distance of the attractor are
+    /// respawned on the edge of the world. Zero disables the sink.
+    pub sink_radius: f32,
+
+    /// Pull every particle toward the world origin.
+    pub center_gravity_enabled: bool,
+
+    /// The strength of the constant pull toward the origin.
+    pub center_gravity: f32,
+
+    /// A constant acceleration applied to every particle, in world units
+    /// per second squared. Pointing it down makes particles pile up on the
+    /// bottom edge like sand.
+    pub gravity: [f32; 2],
+
+    /// Pushes particles around the attractors at right angles to their
+    /// pull, so they orbit instead of collapsing to a point. Positive values
+    /// swirl counter-clockwise, zero disables it.
+    pub swirl: f32,
+
+    /// Turns particles at right angles to their velocity, like a charge
+    /// moving through a magnetic field. This makes particles orbit rather
+    /// than collapse, and combined with center gravity gives galaxy-like
+    /// rotating discs. Zero disables the force.
+    pub magnetic_strength: f32,
+
+    /// The stiffness of the spring which pulls particles back to their
+    /// starting positions while relaxing.
+    pub relax_strength: f32,
+
+    /// Limit how quickly particles consumed by the sink are respawned.
+    /// Without the limit they respawn immediately.
+    pub spawn_limited: bool,
+
+    /// The most particles respawned per second while spawning is limited.
+    pub spawn_rate: f32,
+
+    /// Never let more than this many particles be alive at once. Particles
+    /// consumed by the sink wait to respawn until there's room.
+    pub max_alive: Option<u32>,
+
+    /// Selects between the point attractor and the brush.
+    pub brush_mode: BrushMode,
+
+    /// The radius, in world units, of the region affected by the brush.
+    pub brush_radius: f32,
+
+    /// Only particles within this radius of the cursor are grabbed by the
+    /// lasso.
+    pub lasso_radius: f32,
+
+    /// The stiffness of the spring which pulls grabbed particles toward the
+    /// cursor.
+    pub lasso_strength: f32,
+
+    /// The radius, in world units, of the slow zone.
+    pub slow_radius: f32,
+
+    /// The extra damping applied each tick to particles inside the slow
+    /// zone.
+    pub slow_factor: f32,
+
+    /// The shape of the world's boundary.
+    pub boundary_shape: BoundaryShape,
+
+    /// The radius of the circular boundary. When unset the circle is fit to
+    /// the visible world.
+    pub boundary_radius: Option<f32>,
+
+    /// Particles leaving the rectangular boundary reappear on the opposite
+    /// edge rather than being clamped to it.
+    pub wrap: bool,
+
+    /// Scales `strength` for each species. A negative value makes the
+    /// cursor push that species away rather than pull it in.
+    pub species_strength: [f32; 2],
+
+    /// The outward acceleration given by a shockwave when it's fired, fading
+    /// to nothing as the ring expands.
+    pub shockwave_strength: f32,
+
+    /// How quickly a shockwave's ring expands, in world units per second.
+    pub shockwave_speed: f32,
+
+    /// Particles within this distance of a shockwave's ring are kicked.
+    pub shockwave_thickness: f32,
+
+    /// Scales the forces read from the force texture.
+    pub force_texture_strength: f32,
+}
+
+/// The jitter used when it's toggled on at runtime, a nudge of up to 0.002
+/// world units per 15ms tick.
+pub const DEFAULT_JITTER: f32 = 0.016;
+
+/// The magnetic strength used when it's toggled on at runtime.
+pub const DEFAULT_MAGNETIC_STRENGTH: f32 = 2.0;
+
+/// The spawn rate, in particles per second, used when spawning is limited
+/// without a configured rate.
+pub const DEFAULT_SPAWN_RATE: f32 = 10000.0;
+
+/// The sink radius used when the sink is toggled on at runtime.
+pub const DEFAULT_SINK_RADIUS: f32 = 0.05;
+
+/// The range of brush radii which can be selected with the scroll wheel.
+pub const MIN_BRUSH_RADIUS: f32 = 0.01;
+pub const MAX_BRUSH_RADIUS: f32 = 2.0;
+
+/// The default speed limit for particles.
+pub const DEFAULT_MAX_VEL: f32 = 5.0;
+
+/// The smallest eps allowed, anything smaller lets the acceleration blow up
+/// close to the attractor.
+pub const MIN_EPS: f32 = 0.001;
+
+/// How much each press of shift+arrow changes the gravity, and the most it
+/// can reach on either axis.
+pub const GRAVITY_STEP: f32 = 0.25;
+pub const MAX_GRAVITY: f32 = 10.0;
+
+/// How much each press of Q changes the swirl, and the most it can reach in
+/// either direction.
+pub const SWIRL_STEP: f32 = 0.1;
+pub const MAX_SWIRL: f32 = 5.0;
+
+/// The range the attractor's strength can be adjusted within from the
+/// keyboard.
+pub const MIN_STRENGTH: f32 = 0.1;
+pub const MAX_STRENGTH: f32 = 10.0;
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            strength: 1.0,
+            eps: 0.1,
+            damping: [0.98, 0.98],
+            max_vel: DEFAULT_MAX_VEL,
+            drag_mode: DragMode::Linear,
+            drag_coeff: 0.5,
+            jitter_strength: 0.0,
+            sink_radius: 0.0,
+            center_gravity_enabled: false,
+            center_gravity: 0.5,
+            gravity: [0.0, 0.0],
+            swirl: 0.0,
+            magnetic_strength: 0.0,
+            relax_strength: 20.0,
+            spawn_limited: false,
+            spawn_rate: DEFAULT_SPAWN_RATE,
+            max_alive: None,
+            brush_mode: BrushMode::Point,
+            brush_radius: 0.2,
+            lasso_radius: 0.25,
+            lasso_strength: 60.0,
+            slow_radius: 0.3,
+            slow_factor: 0.9,
+            boundary_shape: BoundaryShape::Rect,
+            boundary_radius: None,
+            wrap: false,
+            species_strength: [1.0, -1.0],
+            shockwave_strength: 40.0,
+            shockwave_speed: 1.5,
+            shockwave_thickness: 0.05,
+            force_texture_strength: 1.0,
+        }
+    }
+}
+
+impl Parameters {
+    /// Switch the jitter between disabled and the default strength.
+    pub fn toggle_jitter(&mut self) {
+        self.jitter_strength = if self.jitter_strength > 0.0 {
+            0.0
+        } else {
+            DEFAULT_JITTER
+        };
+        log::info!("jitter strength {}", self.jitter_strength);
+    }
+
+    /// Switch the magnetic force between disabled and the default strength.
+    pub fn toggle_magnetic(&mut self) {
+        self.magnetic_strength = if self.magnetic_strength != 0.0 {
+            0.0
+        } else {
+            DEFAULT_MAGNETIC_STRENGTH
+        };
+        log::info!("magnetic strength {}", self.magnetic_strength);
+    }
+
+    /// Switch between respawning particles immediately and respawning them
+    /// at `spawn_rate`.
+    pub fn toggle_spawn_limit(&mut self) {
+        self.spawn_limited = !self.spawn_limited;
+        log::info!(
+            "spawn limited {} rate {}",
+            self.spawn_limited,
+            self.spawn_rate
+        );
+    }
+
+    /// The number of particles which may respawn during a tick of the given
+    /// length. The fraction of a particle left over is kept in `carry` for
+    /// the next tick, so rates below one particle per tick still add up to
+    /// `spawn_rate` over time.
+    pub fn spawn_budget(&self, timestep: f32, carry: &mut f32) -> u32 {
+        if !self.spawn_limited {
+            *carry = 0.0;
+            return u32::MAX;
+        }
+        let budget = self.spawn_rate * timestep + *carry;
+        let whole = budget.floor();
+        *carry = budget - whole;
+        whole as u32
+    }
+
+    /// Switch the attractor between pulling particles and consuming them.
+    pub fn toggle_sink(&mut self) {
+        self.sink_radius = if self.sink_radius > 0.0 {
+            0.0
+        } else {
+            DEFAULT_SINK_RADIUS
+        };
+        log::info!("sink radius {}", self.sink_radius);
+    }
+
+    /// Switch between linear damping and quadratic drag.
+    pub fn toggle_drag_mode(&mut self) {
+        self.drag_mode = match self.drag_mode {
+            DragMode::Linear => DragMode::Quadratic,
+            DragMode::Quadratic => DragMode::Linear,
+        };
+        log::info!("drag mode {:?}", self.drag_mode);
+    }
+
+    /// The center gravity to send to the compute shader.
+    pub fn effective_center_gravity(&self) -> f32 {
+        if self.center_gravity_enabled {
+            self.center_gravity
+        } else {
+            0.0
+        }
+    }
+
+    /// Switch the constant pull toward the origin on or off.
+    pub fn toggle_center_gravity(&mut self) {
+        self.center_gravity_enabled = !self.center_gravity_enabled;
+        log::info!(
+            "center gravity enabled {} strength {}",
+            self.center_gravity_enabled,
+            self.center_gravity
+        );
+    }
+
+    /// Scale the strength of the pull toward the origin.
+    pub fn scale_center_gravity(&mut self, factor: f32) {
+        self.center_gravity =
+            (self.center_gravity * factor).max(0.01).min(50.0);
+        log::info!("center gravity strength {}", self.center_gravity);
+    }
+
+    /// Add a step of gravity in the given direction, each axis is kept
+    /// within MAX_GRAVITY.
+    pub fn nudge_gravity(&mut self, x: f32, y: f32) {
+        for (axis, step) in self.gravity.iter_mut().zip(&[x, y]) {
+            *axis =
+                clamp(*axis + step * GRAVITY_STEP, -MAX_GRAVITY, MAX_GRAVITY);
+        }
+        log::info!("gravity {:?}", self.gravity);
+    }
+
+    /// Add a step of swirl, a negative direction turns it clockwise.
+    pub fn nudge_swirl(&mut self, direction: f32) {
+        self.swirl =
+            clamp(self.swirl + direction * SWIRL_STEP, -MAX_SWIRL, MAX_SWIRL);
+        log::info!("swirl {}", self.swirl);
+    }
+
+    /// Turn gravity off.
+    pub fn clear_gravity(&mut self) {
+        self.gravity = [0.0, 0.0];
+        log::info!("gravity {:?}", self.gravity);
+    }
+
+    /// Scale the attractor's pull, keeping it between MIN_STRENGTH and
+    /// MAX_STRENGTH.
+    pub fn scale_strength(&mut self, factor: f32) {
+        self.strength =
+            clamp(self.strength * factor, MIN_STRENGTH, MAX_STRENGTH);
+        log::info!("strength {}", self.strength);
+    }
+
+    /// Scale the softening factor, keeping it above MIN_EPS.
+    pub fn scale_eps(&mut self, factor: f32) {
+        self.eps = (self.eps * factor).max(MIN_EPS);
+        log::info!("eps {}", self.eps);
+    }
+
+    /// Switch the boundary between the rectangle and the circle.
+    pub fn toggle_boundary_shape(&mut self) {
+        self.boundary_shape = match self.boundary_shape {
+            BoundaryShape::Rect => BoundaryShape::Circle,
+            BoundaryShape::Circle => BoundaryShape::Rect,
+        };
+        log::info!("boundary shape {:?}", self.boundary_shape);
+    }
+
+    /// Switch the rectangular boundary between clamping and wrapping.
+    pub fn toggle_wrap(&mut self) {
+        self.wrap = !self.wrap;
+        log::info!("wrap around {}", self.wrap);
+    }
+
+    /// Cycle between the point attractor and the two brush modes.
+    pub fn cycle_brush_mode(&mut self) {
+        self.brush_mode = match self.brush_mode {
+            BrushMode::Point => BrushMode::Attract,
+            BrushMode::Attract => BrushMode::Repel,
+            BrushMode::Repel => BrushMode::Point,
+        };
+        log::info!("brush mode {:?}", self.brush_mode);
+    }
+
+    /// Scale the brush radius, keeping it within a usable range.
+    pub fn scale_brush_radius(&mut self, factor: f32) {
+        self.brush_radius = clamp(
+            self.brush_radius * factor,
+            MIN_BRUSH_RADIUS,
+            MAX_BRUSH_RADIUS,
+        );
+        log::info!("brush radius {}", self.brush_radius);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_budget_carries_the_fraction_between_ticks() {
+        let parameters = Parameters {
+            spawn_limited: true,
+            spawn_rate: 2.0,
+            ..Parameters::default()
+        };
+        // half a particle per tick, which rounding would turn into none or
+        // one every tick
+        let mut carry = 0.0;
+        let spawned: Vec<u32> = (0..4)
+            .map(|_| parameters.spawn_budget(0.25, &mut carry))
+            .collect();
+        assert_eq!(spawned, vec![0, 1, 0, 1]);
+        assert!(carry < 1.0);
+    }
+
+    #[test]
+    fn unlimited_spawning_has_no_budget() {
+        let parameters = Parameters::default();
+        let mut carry = 0.5;
+        assert_eq!(parameters.spawn_budget(1.0 / 60.0, &mut carry), u32::MAX);
+        assert_eq!(carry, 0.0);
+    }
+}
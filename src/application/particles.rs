@@ -1,153 +1,799 @@
 mod pipeline;
+#[cfg(feature = "hot-reload")]
+mod spirv;
 
-use crate::display::Display;
-use anyhow::{Context, Result};
-use pipeline::Transform;
-use rand::{thread_rng, Rng};
-use std::sync::Arc;
+use super::parameters::DEFAULT_MAX_VEL;
+use crate::{display::Display, error::ParticleError, math::clamp};
+use anyhow::{anyhow, ensure, Context, Result};
+use pipeline::{BlendMode, ForceTexture, Palette, SpawnCounters, Transform};
+use rand::{
+    distributions::{WeightedError, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use vulkano::{
-    buffer::{BufferAccess, BufferUsage, ImmutableBuffer},
+    buffer::{
+        BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool,
+        ImmutableBuffer,
+    },
     command_buffer::{
         AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState,
     },
     descriptor::descriptor_set::DescriptorSet,
     framebuffer::Subpass,
-    pipeline::{vertex::BufferlessVertices, ComputePipelineAbstract},
-    sync::GpuFuture,
+    pipeline::{
+        vertex::BufferlessVertices, viewport::Viewport, ComputePipelineAbstract,
+    },
+    sync::{FenceSignalFuture, GpuFuture},
 };
 
 type Mat4 = nalgebra::Matrix4<f32>;
+pub type Vertex = pipeline::compute_shader::ty::Vertex;
 pub type PushConstants = pipeline::PushConstants;
+pub type Simulation = pipeline::Simulation;
+pub type VertexBuffer = pipeline::VertexBuffer;
+
+impl Vertex {
+    /// A live particle whose home is its starting position.
+    pub fn new(pos: [f32; 2], vel: [f32; 2], species: u32) -> Self {
+        Self {
+            pos,
+            vel,
+            home: pos,
+            species,
+            ..Default::default()
+        }
+    }
+}
+
+/// The compute shader's workgroup size, must match `local_size_x`.
+pub const LOCAL_SIZE_X: u32 = 64;
+
+/// The number of particles simulated at startup.
+pub const DEFAULT_PARTICLE_COUNT: u32 = 131072 * LOCAL_SIZE_X;
+
+/// The part of the swapchain image which a set of particles is drawn into.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Region {
+    Full,
+    LeftHalf,
+    RightHalf,
+}
+
+impl Region {
+    /// Build the viewport which covers this region of the swapchain extent.
+    pub fn viewport(&self, extent: [u32; 2]) -> Viewport {
+        let [width, height] = [extent[0] as f32, extent[1] as f32];
+        let (origin, dimensions) = match self {
+            Region::Full => ([0.0, 0.0], [width, height]),
+            Region::LeftHalf => ([0.0, 0.0], [width / 2.0, height]),
+            Region::RightHalf => ([width / 2.0, 0.0], [width / 2.0, height]),
+        };
+        Viewport {
+            origin,
+            dimensions,
+            depth_range: 0.0..1.0,
+        }
+    }
+}
+
+/// The shape of the world which particles move through, centered on the
+/// origin.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WorldShape {
+    /// Half the height of the world, in world units.
+    pub half_height: f32,
+
+    /// The ratio of the world's width to its height. When unset the world
+    /// takes on the aspect ratio of the viewport it's drawn into.
+    pub aspect: Option<f32>,
+}
+
+impl Default for WorldShape {
+    fn default() -> Self {
+        Self {
+            half_height: 1.0,
+            aspect: None,
+        }
+    }
+}
+
+impl WorldShape {
+    /// The half-width and half-height of the world when drawn into the
+    /// viewport.
+    pub fn half_extents(&self, viewport: &Viewport) -> [f32; 2] {
+        let [width, height] = viewport.dimensions;
+        let aspect = self.aspect.unwrap_or(width / height);
+        [aspect * self.half_height, self.half_height]
+    }
+
+    /// The largest part of the viewport which has the world's aspect ratio,
+    /// centered. A world with no aspect of its own fills the viewport, any
+    /// other is letterboxed rather than stretched to fit.
+    pub fn letterbox(&self, viewport: Viewport) -> Viewport {
+        let aspect = match self.aspect {
+            Some(aspect) => aspect,
+            None => return viewport,
+        };
+        let [width, height] = viewport.dimensions;
+        let dimensions = if width > aspect * height {
+            [aspect * height, height]
+        } else {
+            [width, width / aspect]
+        };
+        Viewport {
+            origin: [
+                viewport.origin[0] + (width - dimensions[0]) / 2.0,
+                viewport.origin[1] + (height - dimensions[1]) / 2.0,
+            ],
+            dimensions,
+            depth_range: viewport.depth_range,
+        }
+    }
+}
+
+/// How the world is framed within the viewport.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct View {
+    /// Magnifies the world around the view's center, values above one zoom
+    /// in.
+    pub zoom: f32,
+
+    /// The point in the world which is shown at the center of the viewport.
+    pub pan: [f32; 2],
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            pan: [0.0, 0.0],
+        }
+    }
+}
+
+impl View {
+    /// The half-width and half-height of the part of a world with the
+    /// given half-extents which is visible.
+    pub fn visible_half_extents(&self, half_extents: [f32; 2]) -> [f32; 2] {
+        [half_extents[0] / self.zoom, half_extents[1] / self.zoom]
+    }
+}
+
+/// Controls how particles are laid out whenever they're reset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitialLayout {
+    /// Seeds the layout so every reset produces the same pattern. When
+    /// unset each reset picks, and logs, a new random seed so a layout worth
+    /// keeping can be reproduced with --seed.
+    pub seed: Option<u64>,
+
+    /// The fraction of particles which belong to the second species.
+    pub species_mix: f32,
+
+    /// Place particles more densely where this grayscale image is brighter,
+    /// rather than in a spiral. The image is read again on every reset.
+    pub image: Option<PathBuf>,
+}
+
+impl Default for InitialLayout {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            species_mix: 0.0,
+            image: None,
+        }
+    }
+}
+
+/// Settings which control how particles look on screen.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct RenderSettings {
+    /// Draw each particle as a soft-edged disc rather than a hard point.
+    pub soft_points: bool,
+
+    /// Draw the negative of each particle's color. Must be paired with an
+    /// inverted clear color.
+    pub invert_colors: bool,
+
+    /// Skip drawing particles which are moving slower than
+    /// `cull_threshold`. Saves vertex work for dense fields which have
+    /// settled.
+    pub cull_static: bool,
+
+    /// The speed, in world units per second, below which a particle counts
+    /// as static.
+    pub cull_threshold: f32,
+
+    /// How particles are blended into the image.
+    pub blend_mode: BlendMode,
+
+    /// The size of each particle, in pixels. Soft points are drawn larger
+    /// so their falloff is visible.
+    pub point_size: f32,
+
+    /// The color ramp particles are drawn with.
+    pub palette: Palette,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            soft_points: false,
+            invert_colors: false,
+            cull_static: false,
+            cull_threshold: 0.01,
+            blend_mode: BlendMode::AlphaOver,
+            point_size: 1.0,
+            palette: Palette::default(),
+        }
+    }
+}
+
+/// How much larger than `point_size` a particle drawn as a soft disc is.
+const SOFT_POINT_SIZE: f32 = 4.0;
 
 pub struct Particles {
+    region: Region,
+    shape: WorldShape,
+    settings: RenderSettings,
+    world_bounds: [f32; 2],
+    particle_count: u32,
+    /// The speed limit the transform was last built with.
+    max_vel: f32,
+    view: View,
+
     pipeline: Arc<pipeline::ConcreteGraphicsPipeline>,
-    descriptor_set: Arc<dyn DescriptorSet + Send + Sync>,
+    /// Holds the viewport, which follows the swapchain's size without
+    /// rebuilding the pipeline.
+    dynamic_state: DynamicState,
+    /// Holds the transform uniform, which is written again whenever the
+    /// view or the window changes.
+    transform_pool: CpuBufferPool<Transform>,
+    /// Draws from the vertex buffer with the same index.
+    descriptor_sets: [Arc<dyn DescriptorSet + Send + Sync>; 2],
 
     compute_pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
-    compute_descriptor_set: Arc<dyn DescriptorSet + Send + Sync>,
+    /// Steps from the vertex buffer with the same index into the other.
+    compute_descriptor_sets: [Arc<dyn DescriptorSet + Send + Sync>; 2],
+    /// Holds the simulation parameters, which are written again every tick.
+    simulation_pool: CpuBufferPool<Simulation>,
+
+    /// The compute shader reads one of these and writes the other, they
+    /// swap roles every tick.
+    vertex_buffers: [Arc<VertexBuffer>; 2],
+    /// The index of the vertex buffer holding the latest step.
+    current: usize,
+    counters: SpawnCounters,
+    force_texture: ForceTexture,
+    layout: InitialLayout,
 
-    vertex_buffer: Arc<dyn BufferAccess + Send + Sync>,
+    /// The compute dispatches which may still be running on the gpu, they
+    /// signal a semaphore when they finish. Later dispatches are chained
+    /// onto them, and so is anything on another queue which reads the
+    /// vertex buffers.
+    previous_compute: Option<Box<dyn GpuFuture>>,
 }
 
 impl Particles {
-    pub fn new(display: &Display) -> Result<Self> {
+    pub fn new(
+        display: &Display,
+        region: Region,
+        shape: WorldShape,
+        particle_count: u32,
+        layout: InitialLayout,
+        force_texture: Option<&Path>,
+    ) -> Result<Self, ParticleError> {
+        let push_constants_size = std::mem::size_of::<PushConstants>();
+        let max_push_constants_size = display
+            .device
+            .physical_device()
+            .limits()
+            .max_push_constants_size();
+        if push_constants_size > max_push_constants_size as usize {
+            return Err(anyhow!(
+                "the compute shader needs {} bytes of push constants but the \
+                 device only supports {}",
+                push_constants_size,
+                max_push_constants_size
+            )
+            .into());
+        }
+
+        let settings = RenderSettings::default();
         let pipeline = pipeline::create_graphics_pipeline(
             &display.device,
-            display.swapchain.dimensions(),
             &display.render_pass,
+            settings.blend_mode,
         )?;
 
-        let vertex_buffer = Self::initialize_vertices(display)?;
-
-        let transform = Transform {
-            projection: Mat4::identity().into(),
+        let viewport =
+            shape.letterbox(region.viewport(display.swapchain.dimensions()));
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![viewport.clone()]),
+            ..DynamicState::none()
         };
-        let descriptor_set = pipeline::create_transform_descriptor_set(
+        let world_bounds = shape.half_extents(&viewport);
+
+        let particle_count =
+            Self::supported_particle_count(display, particle_count);
+        let vertex_buffers = Self::initialize_vertices(
+            display,
+            particle_count,
+            &layout,
+            world_bounds,
+        )?;
+        let max_vel = DEFAULT_MAX_VEL;
+        let view = View::default();
+        let transform =
+            build_transform(world_bounds, &view, &settings, display, max_vel);
+        let transform_pool =
+            CpuBufferPool::uniform_buffer(display.device.clone());
+        let descriptor_sets = pipeline::create_transform_descriptor_sets(
             &pipeline,
-            &display.graphics_queue,
-            &vertex_buffer,
+            &transform_pool,
+            &vertex_buffers,
             transform,
         )?;
 
+        let counters = SpawnCounters::new(&display.device, particle_count)?;
         let compute_pipeline =
             pipeline::create_compute_pipeline(&display.device)?;
-        let compute_descriptor_set = pipeline::create_compute_descriptor_set(
+        let force_texture = ForceTexture::load(
+            &display.device,
+            &display.compute_queue,
+            force_texture,
+        )?;
+        let compute_descriptor_sets = create_compute_descriptor_sets(
             &compute_pipeline,
-            &vertex_buffer,
+            &vertex_buffers,
+            &counters,
+            &force_texture,
         )?;
+        let simulation_pool =
+            CpuBufferPool::uniform_buffer(display.device.clone());
 
         Ok(Self {
+            region,
+            shape,
+            settings,
+            world_bounds,
+            particle_count,
+            max_vel,
+            view,
             pipeline,
-            descriptor_set,
+            dynamic_state,
+            transform_pool,
+            descriptor_sets,
             compute_pipeline,
-            compute_descriptor_set,
-            vertex_buffer,
+            compute_descriptor_sets,
+            simulation_pool,
+            vertex_buffers,
+            current: 0,
+            counters,
+            force_texture,
+            layout,
+            previous_compute: None,
         })
     }
 
-    pub fn reset_vertices(&mut self, display: &Display) -> Result<()> {
-        self.vertex_buffer = Self::initialize_vertices(display)?;
+    /// The half-extents of the world visible in this simulation's viewport.
+    /// Particles are kept within these bounds.
+    pub fn world_bounds(&self) -> [f32; 2] {
+        self.world_bounds
+    }
+
+    /// The part of the swapchain image the particles are drawn into, within
+    /// their region.
+    pub fn viewport(&self, display: &Display) -> Viewport {
+        self.shape
+            .letterbox(self.region.viewport(display.swapchain.dimensions()))
+    }
+
+    /// The number of particles being simulated.
+    pub fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+
+    /// The storage buffer holding every particle's vertex as of the latest
+    /// tick.
+    pub fn vertex_buffer(&self) -> &Arc<VertexBuffer> {
+        &self.vertex_buffers[self.current]
+    }
+
+    /// Reallocate the particle buffer to hold a new number of particles.
+    ///
+    /// The count is rounded down to a whole number of compute workgroups and
+    /// capped at the largest storage buffer the device supports.
+    pub fn resize(
+        &mut self,
+        display: &Display,
+        count: u32,
+    ) -> Result<(), ParticleError> {
+        self.particle_count = Self::supported_particle_count(display, count);
+        self.reset_vertices(display)
+    }
+
+    /// The largest number of particles which fit in a single storage buffer
+    /// on this device.
+    fn max_particle_count(display: &Display) -> u32 {
+        let max_range = display
+            .device
+            .physical_device()
+            .limits()
+            .max_storage_buffer_range();
+        let max_count = max_range / std::mem::size_of::<Vertex>() as u32;
+        max_count - (max_count % LOCAL_SIZE_X)
+    }
+
+    /// Round the count down to a whole number of compute workgroups and cap
+    /// it at the device's storage buffer limit, warning when it's capped.
+    fn supported_particle_count(display: &Display, count: u32) -> u32 {
+        let max_count = Self::max_particle_count(display);
+        if count > max_count {
+            log::warn!(
+                "{} particles exceeds the device's storage buffer limit of {} \
+                bytes, using {} particles instead",
+                count,
+                max_count as usize * std::mem::size_of::<Vertex>(),
+                max_count
+            );
+        }
+
+        let count = count.min(max_count);
+        let count = (count - (count % LOCAL_SIZE_X)).max(LOCAL_SIZE_X);
+        log::info!("simulating {} particles", count);
+        count
+    }
+
+    pub fn reset_vertices(
+        &mut self,
+        display: &Display,
+    ) -> Result<(), ParticleError> {
+        self.wait_for_compute()?;
+        let vertex_buffers = Self::initialize_vertices(
+            display,
+            self.particle_count,
+            &self.layout,
+            self.world_bounds,
+        )?;
+        Ok(self.replace_vertices(display, vertex_buffers)?)
+    }
+
+    /// Replace the particles with the rows of a csv written by
+    /// `export_positions`, resizing the simulation to match the file.
+    ///
+    /// Each particle's home is where it was loaded, so relaxing pulls the
+    /// particles back into the saved state.
+    pub fn load_positions(
+        &mut self,
+        display: &Display,
+        path: &Path,
+    ) -> Result<(), ParticleError> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read {:?}", path))?;
+        let vertices = parse_positions(&text)
+            .with_context(|| format!("malformed particles in {:?}", path))?;
+        let count = vertices.len() as u32;
+        if count == 0 || count % LOCAL_SIZE_X != 0 {
+            return Err(anyhow!(
+                "{:?} holds {} particles, the count must be a positive \
+                 multiple of {}",
+                path,
+                count,
+                LOCAL_SIZE_X
+            )
+            .into());
+        }
+        if count != self.particle_count {
+            log::info!(
+                "resizing from {} to {} particles to match {:?}",
+                self.particle_count,
+                count,
+                path
+            );
+        }
+
+        self.wait_for_compute()?;
+        let vertex_buffers = Self::upload(display, &vertices)?;
+        self.particle_count = count;
+        Ok(self.replace_vertices(display, vertex_buffers)?)
+    }
+
+    /// Start simulating from new vertex buffers, rebuilding everything which
+    /// refers to the old ones.
+    fn replace_vertices(
+        &mut self,
+        display: &Display,
+        vertex_buffers: [Arc<VertexBuffer>; 2],
+    ) -> Result<()> {
+        self.vertex_buffers = vertex_buffers;
+        self.current = 0;
+        self.counters =
+            SpawnCounters::new(&display.device, self.particle_count)?;
         self.rebuild_swapchain_resources(display)?;
-        self.compute_descriptor_set = pipeline::create_compute_descriptor_set(
+        self.compute_descriptor_sets = create_compute_descriptor_sets(
             &self.compute_pipeline,
-            &self.vertex_buffer,
+            &self.vertex_buffers,
+            &self.counters,
+            &self.force_texture,
         )?;
         Ok(())
     }
 
+    /// Build both vertex buffers with the same initial layout.
     fn initialize_vertices(
         display: &Display,
-    ) -> Result<Arc<dyn BufferAccess + Send + Sync>> {
-        let mut rng = thread_rng();
-        let max = 131072 * 64;
-        let step = 2.0 * std::f32::consts::PI / max as f32;
-        let vertices = (0..max).map(|i| {
+        count: u32,
+        layout: &InitialLayout,
+        world_bounds: [f32; 2],
+    ) -> Result<[Arc<VertexBuffer>; 2]> {
+        if let Some(path) = &layout.image {
+            return Self::initialize_vertices_from_image(
+                display,
+                count,
+                layout,
+                world_bounds,
+                path,
+            );
+        }
+        let step = 2.0 * std::f32::consts::PI / count as f32;
+        Self::upload_vertices(display, count, layout, |i, rng| {
             let radius = rng.gen_range(0.2..1.0);
             let angle = i as f32 * step;
-            pipeline::compute_shader::ty::Vertex {
-                pos: [radius * angle.cos(), radius * angle.sin()],
-                vel: [0.0, 0.0],
-                ..Default::default()
-            }
-        });
+            [radius * angle.cos(), radius * angle.sin()]
+        })
+    }
 
-        let (buffer, future) = ImmutableBuffer::from_iter(
-            vertices,
-            BufferUsage::all(),
-            display.compute_queue.clone(),
-        )
-        .context("unable to build vertex buffer for compute")?;
-        future
-            .then_signal_fence_and_flush()
-            .context("unable to upload vertex data for initialization")?
-            .wait(None)
-            .context(
-                "interruped while waiting for vertex upload to complete",
-            )?;
+    /// Build both vertex buffers with particles scattered over a grayscale
+    /// image, more densely where it's brighter.
+    ///
+    /// The image is scaled to fit the world and centered, leaving empty
+    /// bands along the sides which don't match its aspect ratio. Each
+    /// particle picks a pixel with a probability proportional to its
+    /// brightness, so dark images don't slow down sampling.
+    fn initialize_vertices_from_image(
+        display: &Display,
+        count: u32,
+        layout: &InitialLayout,
+        world_bounds: [f32; 2],
+        path: &Path,
+    ) -> Result<[Arc<VertexBuffer>; 2]> {
+        let image = image::open(path)
+            .with_context(|| {
+                format!("unable to decode the seed image {:?}", path)
+            })?
+            .into_luma8();
+        let (width, height) = image.dimensions();
+        let pixels = brightness_weights(&image, path)?;
+        log::info!("seeding particles from {:?}, {}x{}", path, width, height);
+
+        let [half_width, half_height] = world_bounds;
+        let scale = (2.0 * half_width / width as f32)
+            .min(2.0 * half_height / height as f32);
+        Self::upload_vertices(display, count, layout, |_, rng| {
+            let pixel = rng.sample(&pixels) as u32;
+            let (x, y) = (pixel % width, pixel / width);
+            // spread particles across the pixel so they don't line up on a
+            // grid, image rows run down while y runs up
+            let px = x as f32 + rng.gen::<f32>() - width as f32 / 2.0;
+            let py = y as f32 + rng.gen::<f32>() - height as f32 / 2.0;
+            [px * scale, -py * scale]
+        })
+    }
 
-        Ok(Arc::new(buffer))
+    /// Upload two identical vertex buffers, with each particle starting at
+    /// rest where `position` places it.
+    fn upload_vertices<F>(
+        display: &Display,
+        count: u32,
+        layout: &InitialLayout,
+        mut position: F,
+    ) -> Result<[Arc<VertexBuffer>; 2]>
+    where
+        F: FnMut(u32, &mut StdRng) -> [f32; 2],
+    {
+        let seed = layout.seed.unwrap_or_else(rand::random);
+        log::info!("laying out particles with seed {}", seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let vertices: Vec<Vertex> = (0..count)
+            .map(|i| {
+                let pos = position(i, &mut rng);
+                let species = rng.gen::<f32>() < layout.species_mix;
+                Vertex::new(pos, [0.0, 0.0], if species { 1 } else { 0 })
+            })
+            .collect();
+        Self::upload(display, &vertices)
+    }
+
+    /// Upload the vertices into two identical vertex buffers.
+    fn upload(
+        display: &Display,
+        vertices: &[Vertex],
+    ) -> Result<[Arc<VertexBuffer>; 2]> {
+        let count = vertices.len() as u64;
+        let max_count = Self::max_particle_count(display);
+        ensure!(
+            count <= max_count as u64,
+            "{} particles need {} bytes which exceeds the device's \
+            max_storage_buffer_range, at most {} particles are supported",
+            count,
+            count * std::mem::size_of::<Vertex>() as u64,
+            max_count
+        );
+
+        let upload = || -> Result<Arc<VertexBuffer>> {
+            let (buffer, future) = ImmutableBuffer::from_iter(
+                vertices.iter().copied(),
+                BufferUsage::all(),
+                display.compute_queue.clone(),
+            )
+            .context("unable to build vertex buffer for compute")?;
+            future
+                .then_signal_fence_and_flush()
+                .context("unable to upload vertex data for initialization")?
+                .wait(None)
+                .context(
+                    "interruped while waiting for vertex upload to complete",
+                )?;
+            Ok(buffer)
+        };
+        Ok([upload()?, upload()?])
     }
 
     pub fn rebuild_swapchain_resources(
         &mut self,
         display: &Display,
-    ) -> Result<()> {
+    ) -> Result<(), ParticleError> {
+        self.wait_for_compute()?;
+        self.dynamic_state.viewports = Some(vec![self.viewport(display)]);
+        if !pipeline::is_compatible(&self.pipeline, &display.render_pass) {
+            // a new color format or sample count
+            self.rebuild_pipeline(display)?;
+        }
+        Ok(self.update_transform(display)?)
+    }
+
+    fn rebuild_pipeline(&mut self, display: &Display) -> Result<()> {
         self.pipeline = pipeline::create_graphics_pipeline(
             &display.device,
-            display.swapchain.dimensions(),
             &display.render_pass,
+            self.settings.blend_mode,
         )?;
+        Ok(())
+    }
 
-        const WORLD_SIZE: f32 = 2.0;
-        let [width, height] = display.swapchain.dimensions();
-        let aspect = width as f32 / height as f32;
-        let world_width = aspect * WORLD_SIZE;
-        let transform = Transform {
-            projection: Mat4::new_orthographic(
-                -world_width / 2.0,
-                world_width / 2.0,
-                WORLD_SIZE / 2.0,
-                -WORLD_SIZE / 2.0,
-                1.0,
-                -1.0,
-            )
-            .into(),
-        };
-        self.descriptor_set = pipeline::create_transform_descriptor_set(
+    /// Replace the compute pipeline with one built from the given source.
+    /// The current pipeline is kept when the source doesn't compile.
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_compute_shader(
+        &mut self,
+        display: &Display,
+        source: &str,
+    ) -> Result<(), ParticleError> {
+        let compute_pipeline = pipeline::create_compute_pipeline_from_source(
+            &display.device,
+            source,
+        )?;
+        self.wait_for_compute()?;
+        self.compute_descriptor_sets = create_compute_descriptor_sets(
+            &compute_pipeline,
+            &self.vertex_buffers,
+            &self.counters,
+            &self.force_texture,
+        )?;
+        self.compute_pipeline = compute_pipeline;
+        Ok(())
+    }
+
+    /// The settings which control how particles are drawn.
+    pub fn settings(&self) -> RenderSettings {
+        self.settings
+    }
+
+    /// Replace the settings which control how particles are drawn.
+    pub fn set_settings(
+        &mut self,
+        display: &Display,
+        settings: RenderSettings,
+    ) -> Result<(), ParticleError> {
+        let blend_changed = settings.blend_mode != self.settings.blend_mode;
+        self.settings = settings;
+        if blend_changed {
+            // blending is baked into the pipeline
+            self.rebuild_pipeline(display)?;
+        }
+        Ok(self.update_transform(display)?)
+    }
+
+    /// Change how the world is framed in the viewport.
+    pub fn set_view(
+        &mut self,
+        display: &Display,
+        view: View,
+    ) -> Result<(), ParticleError> {
+        self.view = view;
+        Ok(self.update_transform(display)?)
+    }
+
+    /// Rebuild the transform descriptor set using the current swapchain
+    /// extent, view, and render settings.
+    fn update_transform(&mut self, display: &Display) -> Result<()> {
+        let viewport = self.viewport(display);
+        self.world_bounds = self.shape.half_extents(&viewport);
+        let transform = build_transform(
+            self.world_bounds,
+            &self.view,
+            &self.settings,
+            display,
+            self.max_vel,
+        );
+        self.descriptor_sets = pipeline::create_transform_descriptor_sets(
             &self.pipeline,
-            &display.graphics_queue,
-            &self.vertex_buffer,
+            &self.transform_pool,
+            &self.vertex_buffers,
             transform,
         )?;
-
         Ok(())
     }
 
+    /// Dispatch one simulation step. The step is queued behind any earlier
+    /// dispatch and this returns without waiting for it to finish. Call
+    /// `wait_for_compute` before reading the vertex buffer on the cpu, or
+    /// `join_compute` to have another queue wait for it on the gpu.
     pub fn tick(
-        &self,
+        &mut self,
         display: &Display,
         push_constants: PushConstants,
-    ) -> Result<()> {
+        simulation: Simulation,
+    ) -> Result<(), ParticleError> {
+        // a circle with no radius of its own fits inside the visible world
+        let [half_width, half_height] = self.world_bounds;
+        let boundary_radius = if simulation.boundary_radius > 0.0 {
+            simulation.boundary_radius
+        } else {
+            half_width.min(half_height)
+        };
+        let simulation = Simulation {
+            bounds: self.world_bounds,
+            boundary_radius,
+            force_texture_strength: if self.force_texture.loaded {
+                simulation.force_texture_strength
+            } else {
+                0.0
+            },
+            ..simulation
+        };
+        debug_assert_eq!(
+            self.particle_count % LOCAL_SIZE_X,
+            0,
+            "the particle count must be a whole number of workgroups"
+        );
+        if simulation.max_vel != self.max_vel {
+            // the vertex shader colors particles by their fraction of the
+            // speed limit
+            self.max_vel = simulation.max_vel;
+            self.update_transform(display)?;
+        }
+        let simulation_set = pipeline::create_simulation_descriptor_set(
+            &self.compute_pipeline,
+            &self.simulation_pool,
+            simulation,
+        )?;
+        let groups = dispatch_groups(self.particle_count);
+        debug_assert_eq!(
+            self.vertex_buffer().size() / std::mem::size_of::<Vertex>(),
+            self.particle_count as usize,
+            "the vertex buffer must hold exactly the particle count"
+        );
+
         let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
             display.device.clone(),
             display.compute_queue.family(),
@@ -156,10 +802,15 @@ impl Particles {
             "unable to create the compute command buffer builder"
         })?;
         builder
+            .fill_buffer(self.counters.spawned.clone(), 0)
+            .with_context(|| "unable to reset the spawn counter")?
             .dispatch(
-                [131072, 1, 1],
+                [groups, 1, 1],
                 self.compute_pipeline.clone(),
-                self.compute_descriptor_set.clone(),
+                (
+                    self.compute_descriptor_sets[self.current].clone(),
+                    simulation_set,
+                ),
                 push_constants,
             )
             .with_context(|| "unable to dispatch the compute pipeline")?;
@@ -167,22 +818,146 @@ impl Particles {
             .build()
             .with_context(|| "unable to build the comput command buffer")?;
 
-        vulkano::sync::now(display.device.clone())
+        let future = self
+            .take_compute(display)
             .then_execute(display.compute_queue.clone(), commands)
+            .map_err(ParticleError::submission)
             .with_context(|| "unable to execute compute commands")?
+            .then_signal_semaphore_and_flush()
+            .map_err(ParticleError::submission)
+            .with_context(|| "unable to flush the compute commands")?;
+        self.previous_compute = Some(future.boxed());
+        self.current = 1 - self.current;
+
+        Ok(())
+    }
+
+    /// The outstanding compute dispatches, or a future which is already
+    /// complete when there aren't any.
+    fn take_compute(&mut self, display: &Display) -> Box<dyn GpuFuture> {
+        match self.previous_compute.take() {
+            Some(mut previous) => {
+                previous.cleanup_finished();
+                previous
+            }
+            None => vulkano::sync::now(display.device.clone()).boxed(),
+        }
+    }
+
+    /// Join the outstanding compute dispatches onto the future, so a
+    /// submission chained after it waits for them on the gpu instead of
+    /// the cpu. The caller has to wait for that submission before the next
+    /// tick, which starts a new chain.
+    pub fn join_compute(
+        &mut self,
+        future: Box<dyn GpuFuture>,
+    ) -> Box<dyn GpuFuture> {
+        match self.previous_compute.take() {
+            Some(previous) => future.join(previous).boxed(),
+            None => future,
+        }
+    }
+
+    /// Execute commands on the compute queue after the outstanding
+    /// dispatches, with later ticks queued behind them so they can read the
+    /// vertex buffer. The returned future's fence is signalled once the
+    /// commands have finished.
+    pub fn then_execute_compute(
+        &mut self,
+        display: &Display,
+        commands: AutoCommandBuffer,
+    ) -> Result<Arc<FenceSignalFuture<Box<dyn GpuFuture>>>, ParticleError> {
+        let future = Arc::new(
+            self.take_compute(display)
+                .then_execute(display.compute_queue.clone(), commands)
+                .map_err(ParticleError::submission)
+                .with_context(|| "unable to execute compute commands")?
+                .boxed()
+                .then_signal_fence_and_flush()
+                .map_err(ParticleError::submission)
+                .with_context(|| "unable to flush the compute commands")?,
+        );
+        // the fence only tells the cpu, other queues wait on a semaphore
+        let chained = future
+            .clone()
+            .then_signal_semaphore_and_flush()
+            .map_err(ParticleError::submission)
+            .with_context(|| "unable to flush the compute commands")?;
+        self.previous_compute = Some(chained.boxed());
+        Ok(future)
+    }
+
+    /// Block until every outstanding compute dispatch has finished.
+    pub fn wait_for_compute(&mut self) -> Result<(), ParticleError> {
+        if let Some(previous) = self.previous_compute.take() {
+            previous
+                .then_signal_fence_and_flush()
+                .map_err(ParticleError::submission)
+                .with_context(|| "unable to flush the compute commands")?
+                .wait(None)
+                .map_err(ParticleError::submission)
+                .with_context(|| {
+                    "error while waiting for the compute pipeline to execute"
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Write the position, velocity and species of every particle to a csv
+    /// file, one row per particle, after the latest tick has finished.
+    pub fn export_positions(
+        &mut self,
+        display: &Display,
+        path: &Path,
+    ) -> Result<(), ParticleError> {
+        self.wait_for_compute()?;
+        let staging = CpuAccessibleBuffer::from_iter(
+            display.device.clone(),
+            BufferUsage::transfer_destination(),
+            true,
+            (0..self.particle_count).map(|_| Vertex::default()),
+        )
+        .context("unable to create the particle staging buffer")?;
+
+        // the vertex buffers are created with every usage, including
+        // transfer_source, so they can be copied from directly
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+            display.device.clone(),
+            display.compute_queue.family(),
+        )
+        .context("unable to create the particle copy command buffer builder")?;
+        builder
+            .copy_buffer(self.vertex_buffer().clone(), staging.clone())
+            .context("unable to copy the particles to the staging buffer")?;
+        let commands = builder
+            .build()
+            .context("unable to build the particle copy command buffer")?;
+        vulkano::sync::now(display.device.clone())
+            .then_execute(display.compute_queue.clone(), commands)
+            .map_err(ParticleError::submission)
+            .context("unable to execute the particle copy command buffer")?
             .then_signal_fence_and_flush()
-            .with_context(|| {
-                "error while waiting for the compute pipeline to execute"
-            })?
+            .map_err(ParticleError::submission)
+            .context("unable to submit the particle copy command buffer")?
             .wait(None)
-            .with_context(|| {
-                "error while waiting for the cpu to be notified"
-            })?;
+            .map_err(ParticleError::submission)
+            .context("error while waiting for the particle copy")?;
 
-        Ok(())
+        let vertices = staging
+            .read()
+            .context("unable to map the particle staging buffer")?;
+        let write = || -> std::io::Result<()> {
+            let mut csv = BufWriter::new(File::create(path)?);
+            write_positions(&mut csv, &vertices)?;
+            csv.flush()
+        };
+        Ok(write().with_context(|| format!("unable to write {:?}", path))?)
     }
 
-    pub fn draw(&self, display: &Display) -> Result<AutoCommandBuffer> {
+    pub fn draw(
+        &self,
+        display: &Display,
+    ) -> Result<AutoCommandBuffer, ParticleError> {
         let mut builder =
             AutoCommandBufferBuilder::secondary_graphics_one_time_submit(
                 display.device.clone(),
@@ -193,20 +968,340 @@ impl Particles {
             )
             .with_context(|| "unable to create the command buffer builder")?;
         let vertices = BufferlessVertices {
-            vertices: 131072 * 64,
+            vertices: self.particle_count as usize,
             instances: 1,
         };
         builder
             .draw(
                 self.pipeline.clone(),
-                &DynamicState::none(),
+                &self.dynamic_state,
                 vertices,
-                vec![self.descriptor_set.clone()],
+                vec![self.descriptor_sets[self.current].clone()],
                 (),
             )
             .with_context(|| "unable to issue draw command")?;
-        builder
+        Ok(builder
             .build()
-            .with_context(|| "unable to build the command buffer")
+            .with_context(|| "unable to build the command buffer")?)
+    }
+}
+
+/// A distribution over the image's pixels, in row order, weighted by their
+/// brightness.
+fn brightness_weights(
+    image: &image::GrayImage,
+    path: &Path,
+) -> Result<WeightedIndex<u64>, ParticleError> {
+    let weights = image.pixels().map(|pixel| u64::from(pixel[0]));
+    WeightedIndex::new(weights).map_err(|error| match error {
+        WeightedError::AllWeightsZero | WeightedError::NoItem => {
+            ParticleError::BlackSeedImage(path.to_owned())
+        }
+        error => anyhow!(error)
+            .context("unable to weight the seed image's pixels")
+            .into(),
+    })
+}
+
+/// Write a header and then one csv row per particle.
+fn write_positions<W: Write>(
+    csv: &mut W,
+    vertices: &[Vertex],
+) -> std::io::Result<()> {
+    writeln!(csv, "pos_x,pos_y,vel_x,vel_y,species")?;
+    for vertex in vertices {
+        writeln!(
+            csv,
+            "{},{},{},{},{}",
+            vertex.pos[0],
+            vertex.pos[1],
+            vertex.vel[0],
+            vertex.vel[1],
+            vertex.species
+        )?;
+    }
+    Ok(())
+}
+
+/// Parse the rows written by `Particles::export_positions`, the header is
+/// optional and blank lines are skipped. Files saved before the species
+/// column was added load every particle as species 0.
+fn parse_positions(text: &str) -> Result<Vec<Vertex>> {
+    let mut vertices = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && line.starts_with("pos_x")) {
+            continue;
+        }
+        let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+        ensure!(
+            columns.len() == 4 || columns.len() == 5,
+            "line {} has {} columns instead of \
+             pos_x,pos_y,vel_x,vel_y,species",
+            index + 1,
+            columns.len()
+        );
+        let values = columns[..4]
+            .iter()
+            .map(|value| value.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .with_context(|| format!("unable to parse line {}", index + 1))?;
+        let species = columns
+            .get(4)
+            .map(|value| value.parse::<u32>())
+            .transpose()
+            .with_context(|| {
+                format!("unable to parse the species on line {}", index + 1)
+            })?
+            .unwrap_or(0);
+        vertices.push(Vertex::new(
+            [values[0], values[1]],
+            [values[2], values[3]],
+            species,
+        ));
+    }
+    Ok(vertices)
+}
+
+/// Create the compute descriptor sets which step each vertex buffer into
+/// the other.
+fn create_compute_descriptor_sets(
+    compute_pipeline: &Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    buffers: &[Arc<VertexBuffer>; 2],
+    counters: &SpawnCounters,
+    force_texture: &ForceTexture,
+) -> Result<[Arc<dyn DescriptorSet + Send + Sync>; 2]> {
+    Ok([
+        pipeline::create_compute_descriptor_set(
+            compute_pipeline,
+            &buffers[0],
+            &buffers[1],
+            counters,
+            force_texture,
+        )?,
+        pipeline::create_compute_descriptor_set(
+            compute_pipeline,
+            &buffers[1],
+            &buffers[0],
+            counters,
+            force_texture,
+        )?,
+    ])
+}
+
+/// The number of workgroups needed to cover every particle. Rounds up so a
+/// partial workgroup is never dropped, the shader skips invocations past the
+/// end of the buffer.
+fn dispatch_groups(particle_count: u32) -> u32 {
+    (particle_count + LOCAL_SIZE_X - 1) / LOCAL_SIZE_X
+}
+
+/// The left, right, bottom, and top edges of the visible world, in the
+/// order `Mat4::new_orthographic` takes them.
+///
+/// Vulkan's clip space has y pointing down, so "bottom", which maps to -1,
+/// is the top of the window and is the larger of the two y values.
+pub fn orthographic_bounds(
+    half_extents: [f32; 2],
+    view: &View,
+) -> (f32, f32, f32, f32) {
+    let [half_width, half_height] = view.visible_half_extents(half_extents);
+    let [x, y] = view.pan;
+    (
+        x - half_width,
+        x + half_width,
+        y + half_height,
+        y - half_height,
+    )
+}
+
+/// Map a world with the given half-extents, framed by the view, onto the
+/// whole viewport.
+pub fn world_projection(half_extents: [f32; 2], view: &View) -> Mat4 {
+    let (left, right, bottom, top) = orthographic_bounds(half_extents, view);
+    Mat4::new_orthographic(left, right, bottom, top, 1.0, -1.0)
+}
+
+/// Build the transform uniform which maps the world into the viewport.
+///
+/// Gamma is encoded manually when the display's swapchain format does not
+/// perform sRGB encoding on write, and the point size is kept within the
+/// device's supported range.
+fn build_transform(
+    half_extents: [f32; 2],
+    view: &View,
+    settings: &RenderSettings,
+    display: &Display,
+    max_vel: f32,
+) -> Transform {
+    let manual_gamma = display.needs_manual_gamma();
+    let [min_size, max_size] =
+        display.device.physical_device().limits().point_size_range();
+    let point_size = if settings.soft_points {
+        settings.point_size * SOFT_POINT_SIZE
+    } else {
+        settings.point_size
+    };
+    Transform {
+        projection: world_projection(half_extents, view).into(),
+        point_size: clamp(point_size, min_size, max_size),
+        soft_points: if settings.soft_points { 1 } else { 0 },
+        invert_colors: if settings.invert_colors { 1 } else { 0 },
+        manual_gamma: if manual_gamma { 1 } else { 0 },
+        cull_static: if settings.cull_static { 1 } else { 0 },
+        cull_threshold: settings.cull_threshold,
+        max_vel,
+        palette: settings.palette.shader_id(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where a world position lands in normalized device coordinates.
+    fn to_ndc(projection: &Mat4, [x, y]: [f32; 2]) -> [f32; 2] {
+        let clip = projection * nalgebra::Vector4::new(x, y, 0.0, 1.0);
+        [clip.x / clip.w, clip.y / clip.w]
+    }
+
+    fn assert_near(actual: [f32; 2], expected: [f32; 2]) {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+        assert!(
+            close(actual[0], expected[0]) && close(actual[1], expected[1]),
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn seed_images_are_sampled_by_brightness() {
+        let mut image = image::GrayImage::new(4, 3);
+        image.put_pixel(2, 1, image::Luma([10]));
+        let pixels = brightness_weights(&image, Path::new("seed.png")).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert_eq!(rng.sample(&pixels), 4 + 2);
+        }
+
+        let black = image::GrayImage::new(4, 3);
+        assert!(matches!(
+            brightness_weights(&black, Path::new("black.png")),
+            Err(ParticleError::BlackSeedImage(_))
+        ));
+    }
+
+    #[test]
+    fn positions_round_trip_through_csv() {
+        let vertices = vec![
+            Vertex::new([0.5, -0.25], [1.0, 0.0], 0),
+            Vertex::new([-1.5, 2.0], [0.0, -0.125], 1),
+        ];
+        let mut csv = vec![];
+        write_positions(&mut csv, &vertices).unwrap();
+        let parsed = parse_positions(&String::from_utf8(csv).unwrap()).unwrap();
+
+        assert_eq!(parsed.len(), vertices.len());
+        for (parsed, vertex) in parsed.iter().zip(&vertices) {
+            assert_eq!(parsed.pos, vertex.pos);
+            assert_eq!(parsed.vel, vertex.vel);
+            assert_eq!(parsed.home, vertex.pos);
+            assert_eq!(parsed.species, vertex.species);
+        }
+    }
+
+    #[test]
+    fn positions_without_a_species_column_are_species_zero() {
+        let parsed =
+            parse_positions("pos_x,pos_y,vel_x,vel_y\n1,2,3,4\n").unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].pos, [1.0, 2.0]);
+        assert_eq!(parsed[0].species, 0);
+    }
+
+    #[test]
+    fn orthographic_bounds_fill_square_portrait_and_ultrawide_windows() {
+        for &extent in &[[800, 800], [600, 1000], [3440, 1440]] {
+            let viewport = Region::Full.viewport(extent);
+            let half_extents = WorldShape::default().half_extents(&viewport);
+            let [half_width, half_height] = half_extents;
+            assert_near(
+                [half_width / half_height, 1.0],
+                [extent[0] as f32 / extent[1] as f32, 1.0],
+            );
+
+            let view = View::default();
+            let (left, right, bottom, top) =
+                orthographic_bounds(half_extents, &view);
+            assert_eq!((left, right), (-half_width, half_width));
+            assert_eq!((bottom, top), (half_height, -half_height));
+
+            let projection = world_projection(half_extents, &view);
+            assert_near(to_ndc(&projection, [0.0, 0.0]), [0.0, 0.0]);
+            // the top of the world is the top of the window, which is -1 in
+            // vulkan's clip space
+            assert_near(
+                to_ndc(&projection, [-half_width, half_height]),
+                [-1.0, -1.0],
+            );
+            assert_near(
+                to_ndc(&projection, [half_width, -half_height]),
+                [1.0, 1.0],
+            );
+        }
+    }
+
+    #[test]
+    fn dispatch_groups_cover_partial_workgroups() {
+        assert_eq!(dispatch_groups(1), 1);
+        assert_eq!(dispatch_groups(LOCAL_SIZE_X - 1), 1);
+        assert_eq!(dispatch_groups(LOCAL_SIZE_X), 1);
+        assert_eq!(dispatch_groups(LOCAL_SIZE_X + 1), 2);
+        assert_eq!(dispatch_groups(10 * LOCAL_SIZE_X + 37), 11);
+    }
+
+    #[test]
+    fn viewports_follow_the_resized_extent() {
+        // the pipeline's viewport is dynamic, so after a resize this is all
+        // that changes where particles are drawn
+        for &[width, height] in &[[1280, 720], [300, 900]] {
+            let [w, h] = [width as f32, height as f32];
+            let full = Region::Full.viewport([width, height]);
+            assert_eq!((full.origin, full.dimensions), ([0.0, 0.0], [w, h]));
+            let left = Region::LeftHalf.viewport([width, height]);
+            assert_eq!(
+                (left.origin, left.dimensions),
+                ([0.0, 0.0], [w / 2.0, h])
+            );
+            let right = Region::RightHalf.viewport([width, height]);
+            assert_eq!(
+                (right.origin, right.dimensions),
+                ([w / 2.0, 0.0], [w / 2.0, h])
+            );
+        }
+    }
+
+    #[test]
+    fn worlds_with_their_own_aspect_are_letterboxed() {
+        let square = WorldShape {
+            half_height: 1.0,
+            aspect: Some(1.0),
+        };
+        let wide = square.letterbox(Region::Full.viewport([1280, 720]));
+        assert_eq!(wide.origin, [280.0, 0.0]);
+        assert_eq!(wide.dimensions, [720.0, 720.0]);
+        let tall = square.letterbox(Region::RightHalf.viewport([800, 1000]));
+        assert_eq!(tall.origin, [400.0, 300.0]);
+        assert_eq!(tall.dimensions, [400.0, 400.0]);
+
+        // so the world isn't stretched
+        let [half_width, half_height] = square.half_extents(&wide);
+        assert_eq!(half_width, half_height);
+
+        let fill = WorldShape::default();
+        let viewport = fill.letterbox(Region::Full.viewport([1280, 720]));
+        assert_eq!(viewport.dimensions, [1280.0, 720.0]);
     }
 }
@@ -1,17 +1,25 @@
+use crate::error::ParticleError;
 use anyhow::{Context, Result};
-use std::sync::Arc;
+use serde::Serialize;
+use std::{path::Path, sync::Arc};
 use vulkano::{
-    buffer::{BufferAccess, BufferUsage, ImmutableBuffer},
+    buffer::{
+        BufferUsage, CpuAccessibleBuffer, CpuBufferPool, ImmutableBuffer,
+    },
     descriptor::{
         descriptor_set::PersistentDescriptorSet, DescriptorSet,
         PipelineLayoutAbstract,
     },
     device::{Device, Queue},
-    framebuffer::{RenderPassAbstract, Subpass},
+    format::Format,
+    framebuffer::{RenderPassAbstract, RenderPassCompatible, Subpass},
+    image::{Dimensions, ImmutableImage, MipmapsCount},
     pipeline::{
-        vertex::BufferlessDefinition, viewport::Viewport, ComputePipeline,
-        ComputePipelineAbstract, GraphicsPipeline,
+        blend::{AttachmentBlend, BlendFactor, BlendOp},
+        vertex::BufferlessDefinition,
+        ComputePipeline, ComputePipelineAbstract, GraphicsPipeline,
     },
+    sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode},
     sync::GpuFuture,
 };
 
@@ -23,100 +31,345 @@ pub type ConcreteGraphicsPipeline = GraphicsPipeline<
 type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
 pub type Transform = vertex_shader::ty::Transform;
 pub type PushConstants = compute_shader::ty::PushConstants;
+pub type Simulation = compute_shader::ty::Simulation;
+pub type VertexBuffer = ImmutableBuffer<[compute_shader::ty::Vertex]>;
 
-/// Create a transform descriptor set for the pipeline using the data in the
-/// transform object.
-pub fn create_transform_descriptor_set(
+/// Create a transform descriptor set for each of the vertex buffers using
+/// the data in the transform object. Both sets share the same uniform
+/// buffer.
+///
+/// The transform is written into the next buffer from the pool, so nothing
+/// waits on the gpu. Buffers still in use by earlier frames are kept alive
+/// by their descriptor sets until those frames finish.
+pub fn create_transform_descriptor_sets(
     pipeline: &Arc<ConcreteGraphicsPipeline>,
-    graphics_queue: &Arc<Queue>,
-    buffer: &Arc<dyn BufferAccess + Send + Sync>,
+    transform_pool: &CpuBufferPool<Transform>,
+    buffers: &[Arc<VertexBuffer>; 2],
     transform: Transform,
-) -> Result<Arc<dyn DescriptorSet + Send + Sync>> {
-    let (uniform_buffer, future) = ImmutableBuffer::from_data(
-        transform,
-        BufferUsage::uniform_buffer(),
-        graphics_queue.clone(),
-    )
-    .context("unable to create the uniform buffer")?;
-    future
-        .then_signal_fence_and_flush() // wait for the GPU to finish the operation
-        .context("error while waiting for uniform buffer to be written")?
-        .wait(None) // wait for the CPU to hear about it
-        .context("uniform buffer timeout")?;
+) -> Result<[Arc<dyn DescriptorSet + Send + Sync>; 2]> {
+    let uniform_buffer = transform_pool
+        .next(transform)
+        .context("unable to allocate the transform buffer")?;
 
     let layout = pipeline
         .descriptor_set_layout(0)
         .context("unable to get the pipeline's transform descriptor set")?;
-    Ok(Arc::new(
-        PersistentDescriptorSet::start(layout.clone())
-            .add_buffer(uniform_buffer)
-            .context("unable to bind the transform buffer")?
-            .add_buffer(buffer.clone())
-            .context("unable to bind the veretx buffer")?
-            .build()
-            .context("unable to build the persistent descriptor set")?,
-    ))
+    let create = |buffer: &Arc<VertexBuffer>| {
+        let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_buffer(uniform_buffer.clone())
+                .context("unable to bind the transform buffer")?
+                .add_buffer(buffer.clone())
+                .context("unable to bind the veretx buffer")?
+                .build()
+                .context("unable to build the persistent descriptor set")?,
+        );
+        Ok::<_, anyhow::Error>(set)
+    };
+    Ok([create(&buffers[0])?, create(&buffers[1])?])
 }
 
+/// The counters which the compute shader uses to budget respawns.
+pub struct SpawnCounters {
+    /// Particles respawned during the current tick.
+    pub spawned: Arc<CpuAccessibleBuffer<u32>>,
+
+    /// Particles which are currently alive.
+    pub alive: Arc<CpuAccessibleBuffer<u32>>,
+}
+
+impl SpawnCounters {
+    /// Create the counters for a buffer of particles which are all alive.
+    pub fn new(device: &Arc<Device>, particle_count: u32) -> Result<Self> {
+        let usage = BufferUsage {
+            storage_buffer: true,
+            transfer_destination: true,
+            ..BufferUsage::none()
+        };
+        let spawned =
+            CpuAccessibleBuffer::from_data(device.clone(), usage, false, 0)
+                .context("unable to create the spawn counter")?;
+        let alive = CpuAccessibleBuffer::from_data(
+            device.clone(),
+            usage,
+            false,
+            particle_count,
+        )
+        .context("unable to create the alive counter")?;
+        Ok(Self { spawned, alive })
+    }
+}
+
+/// An image of forces which the compute shader samples at each particle's
+/// position. The red and green channels hold the x and y force, with 128
+/// meaning none and 1 and 255 meaning full strength either way.
+pub struct ForceTexture {
+    pub image: Arc<ImmutableImage<Format>>,
+    pub sampler: Arc<Sampler>,
+
+    /// False when there's no image to sample and a placeholder is bound.
+    pub loaded: bool,
+}
+
+impl ForceTexture {
+    /// Load the image at the path, or a neutral placeholder when there's no
+    /// path so the compute shader always has something bound.
+    pub fn load(
+        device: &Arc<Device>,
+        queue: &Arc<Queue>,
+        path: Option<&Path>,
+    ) -> Result<Self> {
+        let (pixels, width, height) = match path {
+            Some(path) => {
+                let image = image::open(path)
+                    .with_context(|| {
+                        format!("unable to read the force texture {:?}", path)
+                    })?
+                    .into_rgba8();
+                let (width, height) = image.dimensions();
+                log::info!(
+                    "sampling forces from {:?}, {}x{}",
+                    path,
+                    width,
+                    height
+                );
+                (image.into_raw(), width, height)
+            }
+            None => (vec![128, 128, 0, 255], 1, 1),
+        };
+
+        let (image, future) = ImmutableImage::from_iter(
+            pixels.into_iter(),
+            Dimensions::Dim2d { width, height },
+            MipmapsCount::One,
+            Format::R8G8B8A8Unorm,
+            queue.clone(),
+        )
+        .context("unable to create the force texture")?;
+        future
+            .then_signal_fence_and_flush()
+            .context("unable to upload the force texture")?
+            .wait(None)
+            .context("force texture upload timeout")?;
+
+        let sampler = Sampler::new(
+            device.clone(),
+            Filter::Linear,
+            Filter::Linear,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )
+        .context("unable to create the force texture sampler")?;
+
+        Ok(Self {
+            image,
+            sampler,
+            loaded: path.is_some(),
+        })
+    }
+}
+
+/// Create a compute descriptor set which reads the particles from `input`
+/// and writes the next step into `output`.
 pub fn create_compute_descriptor_set(
     pipeline: &Arc<dyn ComputePipelineAbstract + Send + Sync>,
-    buffer: &Arc<dyn BufferAccess + Send + Sync>,
+    input: &Arc<VertexBuffer>,
+    output: &Arc<VertexBuffer>,
+    counters: &SpawnCounters,
+    force_texture: &ForceTexture,
 ) -> Result<Arc<dyn DescriptorSet + Send + Sync>> {
     let layout = pipeline
         .descriptor_set_layout(0)
         .context("unable to get the compute pipeline's descriptor layout")?;
     Ok(Arc::new(
         PersistentDescriptorSet::start(layout.clone())
-            .add_buffer(buffer.clone())
-            .context("unable to bind the compute buffer")?
+            .add_buffer(input.clone())
+            .context("unable to bind the compute input buffer")?
+            .add_buffer(output.clone())
+            .context("unable to bind the compute output buffer")?
+            .add_buffer(counters.spawned.clone())
+            .context("unable to bind the spawn counter")?
+            .add_buffer(counters.alive.clone())
+            .context("unable to bind the alive counter")?
+            .add_sampled_image(
+                force_texture.image.clone(),
+                force_texture.sampler.clone(),
+            )
+            .context("unable to bind the force texture")?
             .build()
             .context("unable to build the compute descriptor set")?,
     ))
 }
 
+/// Write the simulation parameters into the next buffer from the pool and
+/// bind them as the compute shader's second descriptor set.
+///
+/// Like the transform, nothing waits on the gpu. Dispatches which are still
+/// running keep their own buffer alive through their descriptor set.
+pub fn create_simulation_descriptor_set(
+    pipeline: &Arc<dyn ComputePipelineAbstract + Send + Sync>,
+    simulation_pool: &CpuBufferPool<Simulation>,
+    simulation: Simulation,
+) -> Result<Arc<dyn DescriptorSet + Send + Sync>> {
+    let uniform_buffer = simulation_pool
+        .next(simulation)
+        .context("unable to allocate the simulation buffer")?;
+    let layout = pipeline
+        .descriptor_set_layout(1)
+        .context("unable to get the compute pipeline's simulation layout")?;
+    Ok(Arc::new(
+        PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(uniform_buffer)
+            .context("unable to bind the simulation buffer")?
+            .build()
+            .context("unable to build the simulation descriptor set")?,
+    ))
+}
+
+/// How each particle's color is combined with what's already been drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum BlendMode {
+    /// Standard alpha blending, overlapping particles stay at their own
+    /// brightness.
+    AlphaOver,
+
+    /// Particles add their color to the image, so dense areas accumulate
+    /// into a bright glow.
+    Additive,
+}
+
+impl BlendMode {
+    /// The other blend mode.
+    pub fn toggle(&self) -> Self {
+        match self {
+            BlendMode::AlphaOver => BlendMode::Additive,
+            BlendMode::Additive => BlendMode::AlphaOver,
+        }
+    }
+
+    fn attachment_blend(&self) -> AttachmentBlend {
+        match self {
+            BlendMode::AlphaOver => AttachmentBlend::alpha_blending(),
+            BlendMode::Additive => AttachmentBlend {
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::SrcAlpha,
+                color_destination: BlendFactor::One,
+                alpha_op: BlendOp::Add,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::One,
+                ..AttachmentBlend::alpha_blending()
+            },
+        }
+    }
+}
+
+/// The color ramp particles are drawn with, from slow to fast.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum Palette {
+    /// Blue for fast particles, warm colors for the second species.
+    Species,
+
+    /// Deep blue through to white.
+    Ice,
+
+    /// Dark red through orange and yellow.
+    Fire,
+
+    /// Purple through teal to yellow, roughly following viridis.
+    Viridis,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Species
+    }
+}
+
+impl Palette {
+    /// The next palette, used to cycle through the palettes at runtime.
+    pub fn next(&self) -> Self {
+        match self {
+            Palette::Species => Palette::Ice,
+            Palette::Ice => Palette::Fire,
+            Palette::Fire => Palette::Viridis,
+            Palette::Viridis => Palette::Species,
+        }
+    }
+
+    /// The value the vertex shader switches on.
+    pub fn shader_id(&self) -> i32 {
+        match self {
+            Palette::Species => 0,
+            Palette::Ice => 1,
+            Palette::Fire => 2,
+            Palette::Viridis => 3,
+        }
+    }
+}
+
+/// Create the pipeline which draws the particles. The viewport is dynamic,
+/// so the pipeline only needs to be rebuilt when the render pass changes in
+/// a way which makes it incompatible.
 pub fn create_graphics_pipeline(
     device: &Arc<Device>,
-    swapchain_extent: [u32; 2],
     render_pass: &Arc<DynRenderPass>,
+    blend_mode: BlendMode,
 ) -> Result<Arc<ConcreteGraphicsPipeline>> {
     let vert = vertex_shader::Shader::load(device.clone())
-        .context("unable to load the vertex shader")?;
+        .map_err(ParticleError::shader_load("vertex"))?;
     let frag = fragment_shader::Shader::load(device.clone())
-        .context("unable to load the fragment shader")?;
-
-    let dimensions = [swapchain_extent[0] as f32, swapchain_extent[1] as f32];
-    let viewport = Viewport {
-        dimensions,
-        origin: [0.0, 0.0],
-        depth_range: 0.0..1.0,
-    };
+        .map_err(ParticleError::shader_load("fragment"))?;
 
     let pipeline = GraphicsPipeline::start()
         .vertex_input(BufferlessDefinition {})
         .vertex_shader(vert.main_entry_point(), ())
         .fragment_shader(frag.main_entry_point(), ())
-        .viewports(vec![viewport])
+        .viewports_dynamic_scissors_irrelevant(1)
         .depth_clamp(false)
         .polygon_mode_fill()
         .depth_write(false)
-        .sample_shading_disabled()
-        .blend_alpha_blending()
+        .blend_collective(blend_mode.attachment_blend())
         .point_list()
         .render_pass(
             Subpass::from(render_pass.clone(), 0)
                 .context("could not create the pipeline subpass")?,
-        )
-        .build(device.clone())
-        .context("could not create the graphics pipeline")?;
+        );
+
+    // Shading every sample stops small points from shimmering as they move
+    // across the multisample pattern, but multiplies the fragment work by
+    // the sample count.
+    let pipeline = if device.enabled_features().sample_rate_shading {
+        pipeline.sample_shading_enabled(1.0)
+    } else {
+        pipeline.sample_shading_disabled()
+    }
+    .build(device.clone())
+    .context("could not create the graphics pipeline")?;
 
     Ok(Arc::new(pipeline))
 }
 
+/// True when the pipeline can draw inside the render pass, which holds as
+/// long as the attachments' formats and sample counts haven't changed.
+pub fn is_compatible(
+    pipeline: &ConcreteGraphicsPipeline,
+    render_pass: &Arc<DynRenderPass>,
+) -> bool {
+    let built_for: &DynRenderPass = &**pipeline.render_pass();
+    built_for.is_compatible_with(&**render_pass)
+}
+
 pub fn create_compute_pipeline(
     device: &Arc<Device>,
 ) -> Result<Arc<dyn ComputePipelineAbstract + Send + Sync>> {
     let compute = compute_shader::Shader::load(device.clone())
-        .context("unable to load the compute shader")?;
+        .map_err(ParticleError::shader_load("compute"))?;
     Ok(Arc::new(
         ComputePipeline::new(
             device.clone(),
@@ -128,35 +381,219 @@ pub fn create_compute_pipeline(
     ))
 }
 
+/// Check that a reloaded compute module fits the built in shader's layout,
+/// which the pipeline is built with and the rest of the app binds against.
+///
+/// Every descriptor it declares has to be one the built in shader has, and
+/// no block may read past the end of the structs which fill it. The vertex
+/// buffers have to be arrays of exactly the Vertex struct the app uploads.
+/// Bindings it doesn't use are fine.
+#[cfg(feature = "hot-reload")]
+fn check_compute_interface(words: &[u32]) -> Result<()> {
+    use super::{
+        spirv::{self, DescriptorKind},
+        LOCAL_SIZE_X,
+    };
+    use anyhow::ensure;
+    use std::mem::size_of;
+
+    const DESCRIPTORS: &[(u32, u32, DescriptorKind)] = &[
+        (0, 0, DescriptorKind::StorageBuffer),
+        (0, 1, DescriptorKind::StorageBuffer),
+        (0, 2, DescriptorKind::StorageBuffer),
+        (0, 3, DescriptorKind::StorageBuffer),
+        (0, 4, DescriptorKind::CombinedImageSampler),
+        (1, 0, DescriptorKind::UniformBuffer),
+    ];
+    const VERTEX_BUFFERS: &[(u32, u32)] = &[(0, 0), (0, 1)];
+
+    let interface = spirv::reflect(words)
+        .context("unable to read the compute shader's interface")?;
+    ensure!(
+        interface.local_size == Some([LOCAL_SIZE_X, 1, 1]),
+        "the compute shader's workgroup size is {:?}, it must be [{}, 1, 1]",
+        interface.local_size,
+        LOCAL_SIZE_X
+    );
+    ensure!(
+        interface.push_constants_size as usize <= size_of::<PushConstants>(),
+        "the compute shader reads {} bytes of push constants but only {} \
+         are pushed",
+        interface.push_constants_size,
+        size_of::<PushConstants>()
+    );
+    for descriptor in &interface.descriptors {
+        let expected = (descriptor.set, descriptor.binding, descriptor.kind);
+        ensure!(
+            DESCRIPTORS.contains(&expected),
+            "the compute shader declares a {:?} at set {} binding {} which \
+             the built in shader doesn't have",
+            descriptor.kind,
+            descriptor.set,
+            descriptor.binding
+        );
+        if let Some(size) = descriptor.size {
+            ensure!(
+                size as usize <= size_of::<Simulation>(),
+                "the compute shader's simulation block is {} bytes but \
+                 only {} are written",
+                size,
+                size_of::<Simulation>()
+            );
+        }
+        if VERTEX_BUFFERS.contains(&(descriptor.set, descriptor.binding)) {
+            let vertex = size_of::<compute_shader::ty::Vertex>() as u32;
+            ensure!(
+                descriptor.array_stride == Some(vertex),
+                "the compute shader's buffer at set {} binding {} isn't an \
+                 array of {} byte vertices",
+                descriptor.set,
+                descriptor.binding,
+                vertex
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Compile compute shader source at runtime and build a pipeline from it.
+///
+/// The module is checked against the built in compute shader's layout,
+/// which is what the rest of the app binds, and rejected if it doesn't fit.
+#[cfg(feature = "hot-reload")]
+pub fn create_compute_pipeline_from_source(
+    device: &Arc<Device>,
+    src: &str,
+) -> Result<Arc<dyn ComputePipelineAbstract + Send + Sync>> {
+    use std::ffi::CStr;
+    use vulkano::{
+        descriptor::descriptor::ShaderStages, pipeline::shader::ShaderModule,
+    };
+
+    let mut compiler = shaderc::Compiler::new()
+        .context("unable to create the shader compiler")?;
+    let artifact = compiler
+        .compile_into_spirv(
+            src,
+            shaderc::ShaderKind::Compute,
+            "compute",
+            "main",
+            None,
+        )
+        .context("unable to compile the compute shader")?;
+    if artifact.get_num_warnings() > 0 {
+        log::warn!("{}", artifact.get_warning_messages());
+    }
+
+    check_compute_interface(artifact.as_binary())?;
+
+    // Safe because shaderc only produces valid spir-v. The entry point is
+    // given the built in shader's layout without vulkano checking it, which
+    // is why the module's interface was checked above.
+    let module = unsafe {
+        ShaderModule::from_words(device.clone(), artifact.as_binary())
+    }
+    .context("unable to create the compute shader module")?;
+    let entry_point = unsafe {
+        module.compute_entry_point(
+            CStr::from_bytes_with_nul_unchecked(b"main\0"),
+            compute_shader::Layout(ShaderStages {
+                compute: true,
+                ..ShaderStages::none()
+            }),
+        )
+    };
+    Ok(Arc::new(
+        ComputePipeline::new(device.clone(), &entry_point, &(), None)
+            .context("unable to build the reloaded compute pipeline")?,
+    ))
+}
+
 mod vertex_shader {
     vulkano_shaders::shader! {
         ty: "vertex",
+        types_meta: { #[derive(Copy, Clone, Default)] },
         src: r#"
             #version 450
             #extension GL_ARB_separate_shader_objects : enable
-            #define MAX_VEL 5.0
 
             layout(location = 0) out vec4 vertColor;
+            layout(location = 1) flat out int softPoints;
+            layout(location = 2) flat out int invertColors;
+            layout(location = 3) flat out int manualGamma;
 
             struct Vertex {
                 vec2 pos;
                 vec2 vel;
+                vec2 home;
+                uint dead;
+                uint species;
             };
 
             layout(set = 0, binding = 0) uniform Transform {
                 mat4 projection;
+                float point_size;
+                int soft_points;
+                int invert_colors;
+                int manual_gamma;
+                int cull_static;
+                float cull_threshold;
+                // the compute shader's speed limit, particles moving this
+                // fast are drawn with the brightest color
+                float max_vel;
+                // 0 keeps the per species ramp, see Palette for the others
+                int palette;
             } ubo;
 
-            layout(set = 0, binding = 1) buffer Data {
+            layout(set = 0, binding = 1) readonly buffer Data {
                 Vertex vertices[];
             } data;
 
+            // Map the normalized speed onto the selected palette. Only the
+            // first palette tells the species apart.
+            vec4 ramp(float scale, uint species) {
+                float inv = 1.0 - scale;
+                switch (ubo.palette) {
+                    case 1:
+                        return vec4(mix(vec3(0.0, 0.05, 0.3), vec3(1.0), scale), 0.1);
+                    case 2:
+                        return vec4(
+                            clamp(scale * 3.0, 0.0, 1.0),
+                            clamp(scale * 3.0 - 1.0, 0.0, 1.0),
+                            clamp(scale * 3.0 - 2.0, 0.0, 1.0),
+                            0.1);
+                    case 3: {
+                        vec3 low = mix(vec3(0.27, 0.0, 0.33), vec3(0.13, 0.57, 0.55),
+                            clamp(scale * 2.0, 0.0, 1.0));
+                        return vec4(mix(low, vec3(0.99, 0.91, 0.14),
+                            clamp(scale * 2.0 - 1.0, 0.0, 1.0)), 0.1);
+                    }
+                    default:
+                        if (species == 1u) {
+                            // the second species is drawn with a warm palette
+                            // so the two are easy to tell apart
+                            return vec4(scale, inv/3.0, inv/4.0, 0.1);
+                        }
+                        return vec4(inv/4.0, inv/3.0, scale, 0.1);
+                }
+            }
+
             void main() {
                 Vertex vertex = data.vertices[gl_VertexIndex];
-                float scale = length(vertex.vel) / MAX_VEL;
-                float inv = 1.0 - scale;
-                vertColor = vec4(inv/4.0, inv/3.0, scale, 0.1);
+                float speed = length(vertex.vel);
+                vertColor = ramp(speed / ubo.max_vel, vertex.species);
                 gl_Position = ubo.projection * vec4(vertex.pos, 0.0, 1.0);
+                bool is_static =
+                    ubo.cull_static == 1 && speed < ubo.cull_threshold;
+                if (is_static || vertex.dead == 1u) {
+                    // outside of the clip volume, so the point is discarded
+                    // before rasterization
+                    gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+                }
+                gl_PointSize = ubo.point_size;
+                softPoints = ubo.soft_points;
+                invertColors = ubo.invert_colors;
+                manualGamma = ubo.manual_gamma;
             }
             "#
     }
@@ -170,10 +607,33 @@ mod fragment_shader {
             #extension GL_ARB_separate_shader_objects : enable
 
             layout(location = 0) in vec4 fragColor;
+            layout(location = 1) flat in int softPoints;
+            layout(location = 2) flat in int invertColors;
+            layout(location = 3) flat in int manualGamma;
             layout(location = 0) out vec4 outColor;
 
             void main() {
-               outColor = fragColor;
+                vec4 color = fragColor;
+                if (softPoints == 1) {
+                    // fade the alpha radially across the point's sprite
+                    vec2 coord = gl_PointCoord * 2.0 - 1.0;
+                    float r2 = dot(coord, coord);
+                    if (r2 > 1.0) {
+                        discard;
+                    }
+                    color.a *= exp(-4.0 * r2);
+                }
+                if (invertColors == 1) {
+                    // alpha blending an inverted color over an inverted
+                    // background gives exactly the inverted image
+                    color.rgb = 1.0 - color.rgb;
+                }
+                if (manualGamma == 1) {
+                    // the swapchain stores these values as-is, so encode
+                    // them approximately as sRGB here
+                    color.rgb = pow(color.rgb, vec3(1.0 / 2.2));
+                }
+                outColor = color;
             }
             "#
     }
@@ -185,37 +645,280 @@ pub mod compute_shader {
         types_meta: { #[derive(Copy, Clone, Default)] },
         src: r#"
         #version 450
-        #define eps 0.1
-        #define damping (0.98)
-        #define MAX_VEL 5.0
+        // keep in sync with the reference model in kinematic_particle.rs
+        #define BRUSH_FORCE 5.0
+        // keep in sync with MAX_SHOCKWAVES in application.rs
+        #define MAX_SHOCKWAVES 4
+        // keep in sync with MAX_ATTRACTORS in application.rs
+        #define MAX_ATTRACTORS 8
 
         layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
 
         struct Vertex {
             vec2 pos;
             vec2 vel;
+            // where the particle started, used to relax back into the
+            // initial pattern
+            vec2 home;
+            // set when the particle has been consumed by the sink and is
+            // waiting for the spawn budget to respawn it
+            uint dead;
+            // selects the per-species response to the cursor
+            uint species;
         };
 
-        layout(set = 0, binding = 0) buffer Data {
+        // The particles as of the previous tick.
+        layout(set = 0, binding = 0) readonly buffer Input {
             Vertex vertices[];
-        } data;
+        } previous;
+
+        // The particles after this tick. Every particle is written so no
+        // stale data is left behind from two ticks ago.
+        layout(set = 0, binding = 1) writeonly buffer Output {
+            Vertex vertices[];
+        } next;
+
+        // The number of particles respawned this tick, reset before every
+        // dispatch.
+        layout(set = 0, binding = 2) buffer SpawnCounter {
+            uint spawned;
+        } spawn_counter;
+
+        // The number of particles which aren't dead.
+        layout(set = 0, binding = 3) buffer AliveCounter {
+            uint alive;
+        } alive_counter;
 
+        // A user supplied image of forces covering the world.
+        layout(set = 0, binding = 4) uniform sampler2D force_texture;
+
+        // The parameters which are set from the ui and the config file,
+        // refilled from a buffer pool every tick. Uniform blocks follow
+        // std140 layout rules, which vulkano mirrors in the generated rust
+        // struct with padding fields: bools are four bytes wide, vec2s are
+        // aligned to eight bytes, and vec4s and array elements to sixteen.
+        layout(set = 1, binding = 0) uniform Simulation {
+            float jitter_strength;
+            float sink_radius;
+            float eps;
+            vec2 damping;
+            uint drag_mode;
+            float drag_coeff;
+            vec2 bounds;
+            float center_gravity;
+            float strength;
+            uint brush_mode;
+            float brush_radius;
+            float magnetic_strength;
+            float relax_strength;
+            uint max_alive;
+            float lasso_radius;
+            float lasso_strength;
+            float slow_radius;
+            float slow_factor;
+            uint boundary_shape;
+            float boundary_radius;
+            vec2 species_strength;
+            // xy is the center, z the current radius, and w the strength of
+            // the outward kick
+            vec4 shockwaves[MAX_SHOCKWAVES];
+            uint shockwave_count;
+            float shockwave_thickness;
+            float force_texture_strength;
+            float max_vel;
+            // when set particles leaving the rectangle reappear on the
+            // opposite edge instead of being clamped, circles always clamp
+            int wrap;
+            // attractors placed with the mouse, they pull like the cursor's
+            // point attractor regardless of the brush mode. Only xy is used,
+            // std140 pads every array element out to a vec4 anyway.
+            vec4 attractors[MAX_ATTRACTORS];
+            uint attractor_count;
+            // a constant acceleration applied to every particle, like
+            // gravity pulling sand toward the bottom edge
+            vec2 gravity;
+            // the tangential pull around the attractors, positive values
+            // swirl counter-clockwise
+            float swirl;
+        } sim;
+
+        // The values which follow the input from tick to tick. Push
+        // constants follow std430 layout rules and Vulkan only promises 128
+        // bytes of them, so anything larger belongs in Simulation.
         layout(push_constant) uniform PushConstants {
             bool enabled;
-            vec2 attractor;
             float timestep;
+            vec2 attractor;
+            uint seed;
+            uint spawn_budget;
+            vec2 lasso_center;
+            vec2 slow_center;
+            // the right mouse button pushes particles away from the
+            // repulsor with the same falloff as the attractor's pull, it's
+            // independent of `enabled` so both can be held at once
+            vec2 repulsor;
+            bool relax_enabled;
+            bool lasso_enabled;
+            bool repel_enabled;
         } pc;
 
+        // A cheap integer hash mapped onto [0, 1].
+        float hash(uint x) {
+            x ^= x >> 16;
+            x *= 0x7feb352du;
+            x ^= x >> 15;
+            x *= 0x846ca68bu;
+            x ^= x >> 16;
+            return float(x) / 4294967295.0;
+        }
+
+        // A per-particle random offset which changes every tick. It scales
+        // with the square root of the timestep, like a random walk, so the
+        // spread over a second doesn't depend on the tick length.
+        vec2 jitter(uint idx) {
+            uint key = idx * 2u + pc.seed * 0x9e3779b9u;
+            vec2 r = vec2(hash(key), hash(key + 1u));
+            return (r * 2.0 - 1.0) * sim.jitter_strength * sqrt(pc.timestep);
+        }
+
+        // A point on the edge of the world where a particle reappears after
+        // being consumed by the sink.
+        vec2 respawn_position(uint idx) {
+            uint key = idx * 2u + pc.seed * 0x9e3779b9u + 0x5bd1e995u;
+            float t = hash(key) * 2.0 - 1.0;
+            uint side = uint(hash(key + 1u) * 4.0) % 4u;
+            if (side == 0u) {
+                return vec2(-sim.bounds.x, t * sim.bounds.y);
+            } else if (side == 1u) {
+                return vec2(sim.bounds.x, t * sim.bounds.y);
+            } else if (side == 2u) {
+                return vec2(t * sim.bounds.x, -sim.bounds.y);
+            } else {
+                return vec2(t * sim.bounds.x, sim.bounds.y);
+            }
+        }
+
+        // Bring a dead particle back on the edge of the world if the tick's
+        // spawn budget and the alive cap allow it. The cap is checked before
+        // the counters are updated, so it can be exceeded by a few particles
+        // which respawn at the same time.
+        void try_respawn(uint idx, inout Vertex vertex) {
+            if (alive_counter.alive >= sim.max_alive) {
+                return;
+            }
+            if (atomicAdd(spawn_counter.spawned, 1u) >= pc.spawn_budget) {
+                return;
+            }
+            atomicAdd(alive_counter.alive, 1u);
+            vertex.pos = respawn_position(idx);
+            vertex.vel = vec2(0.0, 0.0);
+            vertex.dead = 0u;
+        }
+
         vec2 clamp_to_bounds(vec2 pos) {
+            if (sim.boundary_shape == 0u && sim.wrap != 0) {
+                // mod() handles particles which crossed more than a whole
+                // width in one tick, and always lands inside [-bounds,
+                // bounds) so nothing is left sitting on the far edge
+                return mod(pos + sim.bounds, 2.0 * sim.bounds) - sim.bounds;
+            }
+            if (sim.boundary_shape == 1u) {
+                // project particles outside the circle back onto its edge
+                float dist = length(pos);
+                if (dist > sim.boundary_radius) {
+                    return pos * (sim.boundary_radius / dist);
+                }
+                return pos;
+            }
             return vec2(
-                clamp(pos.x, -2.0, 2.0),
-                clamp(pos.y, -1.0, 1.0)
+                clamp(pos.x, -sim.bounds.x, sim.bounds.x),
+                clamp(pos.y, -sim.bounds.y, sim.bounds.y)
             );
         }
 
+        // A push at right angles to the direction toward an attractor, so
+        // particles orbit it rather than collapsing onto it.
+        vec2 swirl(vec2 dir, float dist) {
+            return vec2(-dir.y, dir.x) * sim.swirl / (dist + sim.eps);
+        }
+
+        // The sum of all forces acting on a particle at the given position.
+        // Mirrored by the reference model in kinematic_particle.rs.
+        vec2 acceleration(vec2 pos, uint species) {
+            vec2 total = vec2(0.0, 0.0);
+            // each species scales the cursor's pull, a negative strength
+            // turns it into a push
+            float strength = sim.strength * sim.species_strength[species];
+            if (pc.enabled) {
+                vec2 diff = pc.attractor - pos;
+                vec2 dir = normalize(diff);
+                if (sim.brush_mode == 0u) {
+                    total += strength * dir / (dot(diff, diff) + sim.eps);
+                    total += swirl(dir, length(diff));
+                } else if (dot(diff, diff) < sim.brush_radius * sim.brush_radius
+                        && dot(diff, diff) > 1e-12) {
+                    // the brush pushes uniformly inside its radius
+                    float push = sim.brush_mode == 1u ? 1.0 : -1.0;
+                    total += push * strength * BRUSH_FORCE * dir;
+                }
+            }
+            for (uint i = 0u; i < sim.attractor_count; i++) {
+                vec2 diff = sim.attractors[i].xy - pos;
+                total += strength * normalize(diff) / (dot(diff, diff) + sim.eps);
+                total += swirl(normalize(diff), length(diff));
+            }
+            if (pc.repel_enabled) {
+                vec2 diff = pc.repulsor - pos;
+                total -= strength * normalize(diff) / (dot(diff, diff) + sim.eps);
+            }
+            if (pc.lasso_enabled) {
+                // a stiff spring toward the cursor which only grabs nearby
+                // particles, nothing holds their velocity once it's released
+                // so they fling away with whatever momentum they've built up
+                vec2 diff = pc.lasso_center - pos;
+                if (dot(diff, diff) < sim.lasso_radius * sim.lasso_radius) {
+                    total += diff * sim.lasso_strength;
+                }
+            }
+            for (uint i = 0u; i < sim.shockwave_count; i++) {
+                // only particles in the band around the expanding ring are
+                // kicked, so the disturbance travels through the field
+                vec4 wave = sim.shockwaves[i];
+                vec2 diff = pos - wave.xy;
+                float dist = length(diff);
+                if (dist > 1e-6
+                        && abs(dist - wave.z) < sim.shockwave_thickness) {
+                    total += diff / dist * wave.w;
+                }
+            }
+            if (sim.force_texture_strength != 0.0) {
+                // the image covers the world with its top row at +y
+                vec2 uv = vec2(
+                    pos.x / sim.bounds.x + 1.0,
+                    1.0 - pos.y / sim.bounds.y
+                ) * 0.5;
+                // 128 is exactly no force, 255 is full strength, and both 0
+                // and 1 are full strength the other way
+                vec2 texel = textureLod(force_texture, uv, 0.0).rg;
+                vec2 force = clamp((texel * 255.0 - 128.0) / 127.0, -1.0, 1.0);
+                total += force * sim.force_texture_strength;
+            }
+            if (sim.center_gravity != 0.0 && dot(pos, pos) > 1e-12) {
+                total += -normalize(pos) * sim.center_gravity;
+            }
+            total += sim.gravity;
+            return total;
+        }
+
+        // A force at right angles to the velocity which bends particles into
+        // orbits without changing their speed.
+        vec2 magnetic(vec2 vel) {
+            return vec2(-vel.y, vel.x) * sim.magnetic_strength;
+        }
+
         vec2 clamp_velocity(vec2 vel) {
-            if (dot(vel, vel) > MAX_VEL*MAX_VEL) {
-                return normalize(vel)*MAX_VEL;
+            if (dot(vel, vel) > sim.max_vel*sim.max_vel) {
+                return normalize(vel)*sim.max_vel;
             }
             else {
                 return vel;
@@ -224,20 +927,55 @@ pub mod compute_shader {
 
         void main() {
             uint idx = gl_GlobalInvocationID.x;
-            Vertex vertex = data.vertices[idx];
+            if (idx >= uint(previous.vertices.length())) {
+                // never write past the end of the buffer if the dispatch is
+                // larger than the particle count
+                return;
+            }
+            Vertex vertex = previous.vertices[idx];
 
-            if (pc.enabled) {
-                vec2 diff = pc.attractor - vertex.pos;
-                vec2 dir = normalize(diff);
-                vec2 acceleration = dir / (dot(diff, diff) + eps);
-                vertex.vel += acceleration * pc.timestep;
+            if (vertex.dead == 0u) {
+                vertex.vel +=
+                    (acceleration(vertex.pos, vertex.species) + magnetic(vertex.vel)) * pc.timestep;
+                if (pc.relax_enabled) {
+                    // a spring which pulls the particle back to its home
+                    vertex.vel +=
+                        (vertex.home - vertex.pos) * sim.relax_strength * pc.timestep;
+                }
+                vertex.vel = clamp_velocity(vertex.vel);
+                if (sim.drag_mode == 1u) {
+                    vertex.vel -=
+                        vertex.vel * length(vertex.vel) * sim.drag_coeff * pc.timestep;
+                } else {
+                    vertex.vel *= sim.damping;
+                }
+                if (sim.slow_radius > 0.0) {
+                    // extra damping inside the slow zone calms the particles
+                    // passing through it without pulling them anywhere
+                    vec2 diff = pc.slow_center - vertex.pos;
+                    if (dot(diff, diff) < sim.slow_radius * sim.slow_radius) {
+                        vertex.vel *= sim.slow_factor;
+                    }
+                }
+                vertex.pos += vertex.vel * pc.timestep;
+                if (sim.jitter_strength > 0.0) {
+                    vertex.pos += jitter(idx);
+                }
+                if (pc.enabled && sim.sink_radius > 0.0) {
+                    vec2 diff = pc.attractor - vertex.pos;
+                    if (dot(diff, diff) < sim.sink_radius * sim.sink_radius) {
+                        vertex.dead = 1u;
+                        // adding all ones wraps around to a decrement
+                        atomicAdd(alive_counter.alive, 0xffffffffu);
+                    }
+                }
+            }
+            if (vertex.dead == 1u) {
+                try_respawn(idx, vertex);
             }
-            vertex.vel = clamp_velocity(vertex.vel);
-            vertex.vel *= damping;
-            vertex.pos += vertex.vel * pc.timestep;
             vertex.pos = clamp_to_bounds(vertex.pos);
 
-            data.vertices[idx] = vertex;
+            next.vertices[idx] = vertex;
        }
         "#
     }
@@ -0,0 +1,489 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::HashMap;
+
+const MAGIC: u32 = 0x0723_0203;
+
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ROW_MAJOR: u32 = 4;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+
+/// The kind of resource a descriptor binds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DescriptorKind {
+    UniformBuffer,
+    StorageBuffer,
+    CombinedImageSampler,
+}
+
+/// A resource the module expects to find in a descriptor set.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Descriptor {
+    pub set: u32,
+    pub binding: u32,
+    pub kind: DescriptorKind,
+
+    /// The bytes a uniform buffer's block reads, none for other kinds.
+    pub size: Option<u32>,
+
+    /// The stride of the runtime array at the end of a storage buffer's
+    /// block, none when there isn't one.
+    pub array_stride: Option<u32>,
+}
+
+/// The parts of a compute module's interface which have to agree with the
+/// pipeline layout it's built with.
+#[derive(Debug, Default, PartialEq)]
+pub struct Interface {
+    /// Every descriptor the module declares, ordered by set then binding.
+    pub descriptors: Vec<Descriptor>,
+
+    /// The bytes of push constants the module reads, 0 when it has none.
+    pub push_constants_size: u32,
+
+    /// The workgroup size, when the module declares one.
+    pub local_size: Option<[u32; 3]>,
+}
+
+/// How a matrix in a block is laid out, taken from the decorations of the
+/// struct member which holds it.
+#[derive(Debug, Copy, Clone)]
+struct MatrixLayout {
+    stride: u32,
+    row_major: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Scalar(u32),
+    Vector(u32, u32),
+    Matrix(u32, u32),
+    Array(u32, u32),
+    RuntimeArray,
+    Struct(Vec<u32>),
+    SampledImage,
+    Pointer(u32),
+}
+
+/// Just enough of a spir-v module to measure its interface.
+#[derive(Default)]
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Vec<(u32, u32)>>,
+    member_decorations: HashMap<(u32, u32), Vec<(u32, u32)>>,
+    variables: Vec<(u32, u32, u32)>,
+    local_size: Option<[u32; 3]>,
+}
+
+/// Read the descriptors, push constants, and workgroup size declared by a
+/// compute module.
+pub fn reflect(words: &[u32]) -> Result<Interface> {
+    let module = Module::parse(words)?;
+    let mut interface = Interface {
+        local_size: module.local_size,
+        ..Interface::default()
+    };
+    for &(pointer, id, storage) in &module.variables {
+        let pointee = match module.types.get(&pointer) {
+            Some(Type::Pointer(pointee)) => *pointee,
+            _ => bail!("variable %{} doesn't have a pointer type", id),
+        };
+        if storage == STORAGE_PUSH_CONSTANT {
+            interface.push_constants_size = module.size_of(pointee)?;
+            continue;
+        }
+        let kind = match storage {
+            STORAGE_UNIFORM_CONSTANT => match module.types.get(&pointee) {
+                Some(Type::SampledImage) => {
+                    DescriptorKind::CombinedImageSampler
+                }
+                _ => bail!("unsupported uniform constant %{}", id),
+            },
+            STORAGE_UNIFORM
+                if module
+                    .decoration(pointee, DECORATION_BUFFER_BLOCK)
+                    .is_some() =>
+            {
+                DescriptorKind::StorageBuffer
+            }
+            STORAGE_UNIFORM => DescriptorKind::UniformBuffer,
+            STORAGE_STORAGE_BUFFER => DescriptorKind::StorageBuffer,
+            _ => continue,
+        };
+        let size = match kind {
+            DescriptorKind::UniformBuffer => Some(module.size_of(pointee)?),
+            _ => None,
+        };
+        let array_stride = match kind {
+            DescriptorKind::StorageBuffer => {
+                module.runtime_array_stride(pointee)
+            }
+            _ => None,
+        };
+        interface.descriptors.push(Descriptor {
+            set: module
+                .decoration(id, DECORATION_DESCRIPTOR_SET)
+                .with_context(|| format!("%{} has no descriptor set", id))?,
+            binding: module
+                .decoration(id, DECORATION_BINDING)
+                .with_context(|| format!("%{} has no binding", id))?,
+            kind,
+            size,
+            array_stride,
+        });
+    }
+    interface
+        .descriptors
+        .sort_by_key(|descriptor| (descriptor.set, descriptor.binding));
+    Ok(interface)
+}
+
+impl Module {
+    fn parse(words: &[u32]) -> Result<Self> {
+        ensure!(words.len() >= 5 && words[0] == MAGIC, "not a spir-v module");
+        let mut module = Self::default();
+        let mut rest = &words[5..];
+        while !rest.is_empty() {
+            let count = (rest[0] >> 16) as usize;
+            ensure!(
+                count > 0 && count <= rest.len(),
+                "truncated spir-v instruction"
+            );
+            let (instruction, next) = rest.split_at(count);
+            module.record(instruction[0] & 0xffff, &instruction[1..]);
+            rest = next;
+        }
+        Ok(module)
+    }
+
+    /// Keep the operands of the instructions which describe the interface.
+    /// Instructions missing the operands they need are left out.
+    fn record(&mut self, opcode: u32, operands: &[u32]) {
+        let operand = |index: usize| operands.get(index).copied();
+        let types = &mut self.types;
+        let mut define = |ty: Type| {
+            if let Some(id) = operand(0) {
+                types.insert(id, ty);
+            }
+        };
+        match opcode {
+            OP_TYPE_BOOL => define(Type::Scalar(4)),
+            OP_TYPE_INT | OP_TYPE_FLOAT => {
+                define(Type::Scalar(operand(1).unwrap_or(32) / 8))
+            }
+            OP_TYPE_VECTOR | OP_TYPE_MATRIX | OP_TYPE_ARRAY => {
+                if let (Some(inner), Some(count)) = (operand(1), operand(2)) {
+                    define(match opcode {
+                        OP_TYPE_VECTOR => Type::Vector(inner, count),
+                        OP_TYPE_MATRIX => Type::Matrix(inner, count),
+                        _ => Type::Array(inner, count),
+                    });
+                }
+            }
+            OP_TYPE_RUNTIME_ARRAY => define(Type::RuntimeArray),
+            OP_TYPE_SAMPLED_IMAGE => define(Type::SampledImage),
+            OP_TYPE_STRUCT => {
+                define(Type::Struct(operands.get(1..).unwrap_or(&[]).to_vec()))
+            }
+            OP_TYPE_POINTER => {
+                if let Some(pointee) = operand(2) {
+                    define(Type::Pointer(pointee));
+                }
+            }
+            OP_CONSTANT => {
+                if let (Some(id), Some(value)) = (operand(1), operand(2)) {
+                    self.constants.insert(id, value);
+                }
+            }
+            OP_VARIABLE => {
+                if let (Some(ty), Some(id), Some(storage)) =
+                    (operand(0), operand(1), operand(2))
+                {
+                    self.variables.push((ty, id, storage));
+                }
+            }
+            OP_DECORATE => {
+                if let (Some(target), Some(decoration)) =
+                    (operand(0), operand(1))
+                {
+                    self.decorations
+                        .entry(target)
+                        .or_default()
+                        .push((decoration, operand(2).unwrap_or(0)));
+                }
+            }
+            OP_MEMBER_DECORATE => {
+                if let (Some(ty), Some(member), Some(decoration)) =
+                    (operand(0), operand(1), operand(2))
+                {
+                    self.member_decorations
+                        .entry((ty, member))
+                        .or_default()
+                        .push((decoration, operand(3).unwrap_or(0)));
+                }
+            }
+            OP_EXECUTION_MODE => {
+                if operand(1) == Some(EXECUTION_MODE_LOCAL_SIZE) {
+                    if let (Some(x), Some(y), Some(z)) =
+                        (operand(2), operand(3), operand(4))
+                    {
+                        self.local_size = Some([x, y, z]);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations
+            .get(&id)?
+            .iter()
+            .find(|(kind, _)| *kind == decoration)
+            .map(|(_, value)| *value)
+    }
+
+    fn member_decoration(
+        &self,
+        ty: u32,
+        member: u32,
+        decoration: u32,
+    ) -> Option<u32> {
+        self.member_decorations
+            .get(&(ty, member))?
+            .iter()
+            .find(|(kind, _)| *kind == decoration)
+            .map(|(_, value)| *value)
+    }
+
+    /// The stride of the runtime array which ends a block, if it has one.
+    fn runtime_array_stride(&self, block: u32) -> Option<u32> {
+        let last = match self.types.get(&block)? {
+            Type::Struct(members) => *members.last()?,
+            _ => return None,
+        };
+        match self.types.get(&last)? {
+            Type::RuntimeArray => {
+                self.decoration(last, DECORATION_ARRAY_STRIDE)
+            }
+            _ => None,
+        }
+    }
+
+    /// The bytes read through a value of the type, following the explicit
+    /// offsets and strides of block layouts. Runtime arrays count as empty.
+    fn size_of(&self, id: u32) -> Result<u32> {
+        self.size_in(id, None)
+    }
+
+    /// Like size_of, for a type inside a struct member with the given
+    /// matrix layout. Without one matrices are tightly packed columns.
+    fn size_in(&self, id: u32, matrix: Option<MatrixLayout>) -> Result<u32> {
+        let ty = self
+            .types
+            .get(&id)
+            .with_context(|| format!("unknown type %{}", id))?;
+        Ok(match ty {
+            Type::Scalar(size) => *size,
+            Type::Vector(component, count) => self.size_of(*component)? * count,
+            Type::Matrix(column, columns) => match matrix {
+                Some(MatrixLayout {
+                    stride,
+                    row_major: false,
+                }) => stride * columns,
+                Some(MatrixLayout {
+                    stride,
+                    row_major: true,
+                }) => match self.types.get(column) {
+                    Some(Type::Vector(_, rows)) => stride * rows,
+                    _ => bail!("matrix %{} doesn't have vector columns", id),
+                },
+                None => self.size_of(*column)? * columns,
+            },
+            Type::Array(element, length) => {
+                let length = self
+                    .constants
+                    .get(length)
+                    .with_context(|| format!("unknown array length %{}", id))?;
+                let stride = match self.decoration(id, DECORATION_ARRAY_STRIDE)
+                {
+                    Some(stride) => stride,
+                    None => self.size_in(*element, matrix)?,
+                };
+                stride * length
+            }
+            Type::RuntimeArray => 0,
+            Type::Struct(members) => {
+                let mut size = 0;
+                for (index, member) in members.iter().enumerate() {
+                    let index = index as u32;
+                    let offset = self
+                        .member_decoration(id, index, DECORATION_OFFSET)
+                        .unwrap_or(size);
+                    let matrix = self
+                        .member_decoration(id, index, DECORATION_MATRIX_STRIDE)
+                        .map(|stride| MatrixLayout {
+                            stride,
+                            row_major: self
+                                .member_decoration(
+                                    id,
+                                    index,
+                                    DECORATION_ROW_MAJOR,
+                                )
+                                .is_some(),
+                        });
+                    size = size.max(offset + self.size_in(*member, matrix)?);
+                }
+                size
+            }
+            Type::SampledImage | Type::Pointer(..) => {
+                bail!("%{} isn't stored in memory", id)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(src: &str) -> Vec<u32> {
+        let mut compiler = shaderc::Compiler::new().unwrap();
+        compiler
+            .compile_into_spirv(
+                src,
+                shaderc::ShaderKind::Compute,
+                "test",
+                "main",
+                None,
+            )
+            .unwrap()
+            .as_binary()
+            .to_vec()
+    }
+
+    #[test]
+    fn reflects_descriptors_push_constants_and_local_size() {
+        let words = compile(
+            r#"
+            #version 450
+            layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+            layout(set = 0, binding = 0) buffer Data { vec2 values[]; } data;
+            layout(set = 0, binding = 1) uniform sampler2D image;
+            layout(set = 1, binding = 0) uniform Params {
+                float scale;
+                vec4 points[4];
+            } params;
+            layout(push_constant) uniform PushConstants {
+                bool enabled;
+                vec2 offset;
+                float time;
+            } pc;
+            void main() {
+                data.values[0] = pc.offset * params.scale * pc.time
+                    + params.points[0].xy
+                    + textureLod(image, vec2(0.0), 0.0).rg;
+            }
+            "#,
+        );
+        let interface = reflect(&words).unwrap();
+        assert_eq!(interface.local_size, Some([64, 1, 1]));
+        // std430 puts the vec2 at 8 and the float right after it
+        assert_eq!(interface.push_constants_size, 20);
+        assert_eq!(
+            interface.descriptors,
+            vec![
+                Descriptor {
+                    set: 0,
+                    binding: 0,
+                    kind: DescriptorKind::StorageBuffer,
+                    size: None,
+                    // std430 packs vec2s 8 bytes apart
+                    array_stride: Some(8),
+                },
+                Descriptor {
+                    set: 0,
+                    binding: 1,
+                    kind: DescriptorKind::CombinedImageSampler,
+                    size: None,
+                    array_stride: None,
+                },
+                Descriptor {
+                    set: 1,
+                    binding: 0,
+                    kind: DescriptorKind::UniformBuffer,
+                    // std140 aligns the array to 16 bytes
+                    size: Some(16 + 4 * 16),
+                    array_stride: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matrices_are_measured_with_their_stride() {
+        let words = compile(
+            r#"
+            #version 450
+            layout(local_size_x = 1) in;
+            layout(set = 0, binding = 0) uniform Columns {
+                float scale;
+                mat3 rotation;
+            } columns;
+            layout(set = 0, binding = 1) uniform Rows {
+                float scale;
+                layout(row_major) mat2x4 transform;
+            } rows;
+            layout(set = 0, binding = 2) buffer Results {
+                float values[];
+            } results;
+            void main() {
+                results.values[0] = columns.scale * columns.rotation[0].x
+                    + rows.scale * rows.transform[0].x;
+            }
+            "#,
+        );
+        let sizes: Vec<Option<u32>> = reflect(&words)
+            .unwrap()
+            .descriptors
+            .iter()
+            .map(|descriptor| descriptor.size)
+            .collect();
+        // std140 pads each vec3 column to 16 bytes, and lays the row major
+        // mat2x4 out as 4 rows of 16 bytes rather than 2 columns
+        assert_eq!(sizes, vec![Some(16 + 3 * 16), Some(16 + 4 * 16), None]);
+    }
+
+    #[test]
+    fn rejects_words_which_are_not_spirv() {
+        assert!(reflect(&[0, 1, 2, 3, 4]).is_err());
+        assert!(reflect(&[MAGIC, 0, 0, 0, 0, 0x0005_0000]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+use winit::event::{ModifiersState, VirtualKeyCode};
+
+/// A single piece of user input, independent of the window it came from.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum InputEvent {
+    /// The cursor moved, normalized to [0, 1] across the simulation with y
+    /// pointing down.
+    CursorMoved([f32; 2]),
+
+    /// The left mouse button was pressed (true) or released (false).
+    LeftButton(bool),
+
+    /// The right mouse button was pressed (true) or released (false).
+    RightButton(bool),
+
+    /// The middle mouse button was released.
+    MiddleReleased,
+
+    /// The scroll wheel moved by this many lines.
+    Scrolled(f32),
+
+    /// A key was released.
+    KeyReleased(VirtualKeyCode),
+
+    /// The held modifier keys changed.
+    ModifiersChanged(ModifiersState),
+}
+
+/// An input event and the tick it was applied before.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+struct Entry {
+    tick: u32,
+    event: InputEvent,
+}
+
+/// Writes input events to a file as they happen, one json object per line.
+///
+/// Writes are buffered, call `flush` before exiting. Dropping the recorder
+/// flushes too, but the event loop exits without dropping on some
+/// platforms.
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| {
+            format!("unable to create the input recording {:?}", path)
+        })?;
+        log::info!("recording input to {:?}", path);
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Record an event which is applied before the given tick.
+    pub fn record(&mut self, tick: u32, event: InputEvent) -> Result<()> {
+        let line = serde_json::to_string(&Entry { tick, event })
+            .context("unable to serialize the input event")?;
+        writeln!(self.writer, "{}", line)
+            .context("unable to write the input event")
+    }
+
+    /// Write any buffered events to the file.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .context("unable to flush the input recording")
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            log::error!("lost the end of the input recording {:?}", error);
+        }
+    }
+}
+
+/// Input events read back from a recording, in the order they happened.
+pub struct Replay {
+    entries: VecDeque<Entry>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| {
+            format!("unable to open the input recording {:?}", path)
+        })?;
+        let mut entries = VecDeque::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("unable to read the input recording")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line).with_context(|| {
+                format!("invalid input event on line {}", number + 1)
+            })?;
+            entries.push_back(entry);
+        }
+        log::info!("replaying {} input events from {:?}", entries.len(), path);
+        Ok(Self { entries })
+    }
+
+    /// Remove and return the next event which should be applied before the
+    /// given tick.
+    pub fn next_due(&mut self, tick: u32) -> Option<InputEvent> {
+        if self.entries.front()?.tick <= tick {
+            self.entries.pop_front().map(|entry| entry.event)
+        } else {
+            None
+        }
+    }
+
+    /// True once every recorded event has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropping_the_recorder_flushes_buffered_events() {
+        let path = std::env::temp_dir()
+            .join(format!("recording-{}.jsonl", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder.record(0, InputEvent::LeftButton(true)).unwrap();
+        recorder.record(3, InputEvent::Scrolled(2.0)).unwrap();
+        drop(recorder);
+
+        let mut replay = Replay::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            replay.next_due(0),
+            Some(InputEvent::LeftButton(true))
+        ));
+        assert!(replay.next_due(2).is_none());
+        assert!(matches!(replay.next_due(3), Some(InputEvent::Scrolled(_))));
+        assert!(replay.is_finished());
+    }
+}
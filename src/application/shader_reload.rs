@@ -0,0 +1,40 @@
+use anyhow::{Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Watches a shader source file and hands back its contents whenever it's
+/// been modified.
+pub struct ShaderWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ShaderWatcher {
+    /// Watch the file at the path. The first poll always reads it.
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            modified: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file's contents if it has changed since the last poll.
+    pub fn poll(&mut self) -> Result<Option<String>> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("unable to stat {:?}", self.path))?;
+        if self.modified == Some(modified) {
+            return Ok(None);
+        }
+        self.modified = Some(modified);
+        let source = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("unable to read {:?}", self.path))?;
+        Ok(Some(source))
+    }
+}
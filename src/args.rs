@@ -0,0 +1,230 @@
+use crate::{
+    application::BoundaryShape,
+    display::{ClearColor, PreferredPresentMode, TonemapOperator},
+};
+use std::{net::IpAddr, path::PathBuf};
+use structopt::StructOpt;
+
+/// Command line arguments for the particle doodle.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "particle-doodle")]
+pub struct Args {
+    /// Load simulation parameters from this toml file. Command line flags
+    /// override the values it sets.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Allow rendering with a software rasterizer, like llvmpipe, when no
+    /// hardware device is suitable.
+    #[structopt(long)]
+    pub allow_software: bool,
+
+    /// Use the device at this index, as listed in the log at startup,
+    /// instead of preferring discrete gpus.
+    #[structopt(long)]
+    pub gpu: Option<usize>,
+
+    /// The number of swapchain images to request, clamped to what the
+    /// surface supports.
+    #[structopt(long)]
+    pub image_count: Option<u32>,
+
+    /// Draw into a float image and tonemap it for display, presenting to an
+    /// hdr swapchain format when one is available. Adjust the exposure with
+    /// ; and '.
+    #[structopt(long)]
+    pub hdr: bool,
+
+    /// The curve which maps the hdr image for display, aces or reinhard.
+    /// Only used with --hdr.
+    #[structopt(long, default_value = "aces")]
+    pub tonemap: TonemapOperator,
+
+    /// The background color as hex, like #f0f0f0 for screenshots on a light
+    /// theme. Defaults to black.
+    #[structopt(long)]
+    pub clear_color: Option<ClearColor>,
+
+    /// Exit with an error if the surface doesn't offer an sRGB format,
+    /// rather than falling back to another format with manual gamma.
+    #[structopt(long)]
+    pub require_srgb: bool,
+
+    /// Shade each multisample of a point individually. This removes the
+    /// shimmer of small points moving across the multisample pattern, at the
+    /// cost of running the fragment shader once per sample rather than once
+    /// per pixel.
+    #[structopt(long)]
+    pub sample_shading: bool,
+
+    /// The window's initial width, in logical pixels.
+    #[structopt(long, default_value = "1366")]
+    pub width: u32,
+
+    /// The window's initial height, in logical pixels.
+    #[structopt(long, default_value = "768")]
+    pub height: u32,
+
+    /// The number of particles to simulate, must be a multiple of 64.
+    /// Defaults to 8388608, or fewer when the device can't hold that many.
+    #[structopt(long)]
+    pub particles: Option<u32>,
+
+    /// The most multisamples to use per pixel, 1 disables multisampling.
+    /// Defaults to the most the device supports.
+    #[structopt(long)]
+    pub max_samples: Option<u32>,
+
+    /// The presentation mode to use when it's supported: mailbox, fifo,
+    /// fifo-relaxed, or immediate. Cycle through them at runtime with F5.
+    #[structopt(long, default_value = "mailbox")]
+    pub present_mode: PreferredPresentMode,
+
+    /// Listen for OSC messages which control the attractor on this UDP port.
+    #[structopt(long)]
+    pub osc_port: Option<u16>,
+
+    /// The address the OSC port is bound to. Only this machine can send to
+    /// the default, use 0.0.0.0 to accept messages from other machines too.
+    #[structopt(long, default_value = "127.0.0.1")]
+    pub osc_address: IpAddr,
+
+    /// Keep the window above all other windows. Toggle at runtime with T.
+    #[structopt(long)]
+    pub always_on_top: bool,
+
+    /// Render a second simulation into the right half of the window so two
+    /// parameter sets can be compared side-by-side.
+    #[structopt(long)]
+    pub compare: bool,
+
+    /// Limit rendering to at most this many frames per second. Zero leaves the
+    /// framerate uncapped.
+    #[structopt(long, default_value = "0")]
+    pub max_fps: u32,
+
+    /// The fraction of horizontal velocity kept each tick.
+    #[structopt(long)]
+    pub damping_x: Option<f32>,
+
+    /// The fraction of vertical velocity kept each tick.
+    #[structopt(long)]
+    pub damping_y: Option<f32>,
+
+    /// Respawn at most this many particles per second after they're
+    /// consumed by the sink. Toggle the limit at runtime with Y.
+    #[structopt(long)]
+    pub spawn_rate: Option<f32>,
+
+    /// Never let more than this many particles be alive at once.
+    #[structopt(long)]
+    pub max_alive: Option<u32>,
+
+    /// The velocity damping used by the right half in compare mode, on both
+    /// axes.
+    #[structopt(long)]
+    pub right_damping: Option<f32>,
+
+    /// The attractor softening factor used by the right half in compare
+    /// mode.
+    #[structopt(long)]
+    pub right_eps: Option<f32>,
+
+    /// Half the height of the world, in world units.
+    #[structopt(long, default_value = "1.0")]
+    pub world_half_height: f32,
+
+    /// The ratio of the world's width to its height. Defaults to the
+    /// window's aspect ratio, set it to 1 for a square world. A world which
+    /// doesn't match the window is letterboxed.
+    #[structopt(long)]
+    pub world_aspect: Option<f32>,
+
+    /// The fraction of particles, from 0 to 1, which belong to the second
+    /// species.
+    #[structopt(long, default_value = "0")]
+    pub species_mix: f32,
+
+    /// Seed the initial layout, and every reset, so they're reproducible.
+    /// The seed for each random layout is logged when this isn't set.
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// How strongly the cursor pulls the first species, negative values
+    /// push it away instead.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub species_a_strength: Option<f32>,
+
+    /// How strongly the cursor pulls the second species, negative values
+    /// push it away instead.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub species_b_strength: Option<f32>,
+
+    /// Add the forces painted into this image to the simulation. The red and
+    /// green channels hold the x and y force, with 128 meaning no force.
+    #[structopt(long, parse(from_os_str))]
+    pub force_field: Option<PathBuf>,
+
+    /// Start particles scattered over this grayscale image, more densely
+    /// where it's brighter, instead of in a spiral.
+    #[structopt(long, parse(from_os_str))]
+    pub seed_image: Option<PathBuf>,
+
+    /// Start from particles saved to a csv with L. Shift+L reloads them.
+    #[structopt(long, parse(from_os_str))]
+    pub load_positions: Option<PathBuf>,
+
+    /// Scales the forces read from --force-field.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub force_field_strength: Option<f32>,
+
+    /// The shape of the wall which contains the particles: rect or circle.
+    /// Toggle at runtime with E.
+    #[structopt(long, default_value = "rect")]
+    pub boundary: BoundaryShape,
+
+    /// The radius of the circular boundary, in world units. Defaults to the
+    /// largest circle which fits in the view.
+    #[structopt(long)]
+    pub boundary_radius: Option<f32>,
+
+    /// Record mouse and keyboard input to this file so the run can be
+    /// replayed later.
+    #[structopt(long, parse(from_os_str), conflicts_with = "replay-input")]
+    pub record_input: Option<PathBuf>,
+
+    /// Replay input recorded with --record-input instead of responding to
+    /// the mouse and keyboard.
+    #[structopt(long, parse(from_os_str))]
+    pub replay_input: Option<PathBuf>,
+
+    /// Run this many frames with a fixed seed and timestep without showing
+    /// the window, print a hash of the final frame, and exit.
+    #[structopt(long)]
+    pub hash_frame: Option<u32>,
+
+    /// Render this many frames with a fixed timestep without showing the
+    /// window, write each one to a png in --out-dir, and exit.
+    #[structopt(long)]
+    pub render_sequence: Option<usize>,
+
+    /// The directory that --render-sequence writes frames into.
+    #[structopt(long, parse(from_os_str), default_value = "frames")]
+    pub out_dir: PathBuf,
+
+    /// Load the compute shader's glsl from this file, and rebuild the compute
+    /// pipeline whenever the file changes. The shader must keep the built in
+    /// shader's bindings and push constants.
+    #[cfg(feature = "hot-reload")]
+    #[structopt(long, parse(from_os_str))]
+    pub compute_shader: Option<PathBuf>,
+
+    /// Stream particle positions over a tcp socket at this address, like
+    /// 127.0.0.1:7777, for external visualizers.
+    #[structopt(long)]
+    pub export_socket: Option<String>,
+
+    /// Only export every Nth particle with --export-socket.
+    #[structopt(long, default_value = "64")]
+    pub export_stride: u32,
+}
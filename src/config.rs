@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Tunable values loaded from a toml file with --config. Every key is
+/// optional, missing keys keep their defaults and command line flags take
+/// precedence over the file.
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize)]
+pub struct Config {
+    /// Scales the pull of the attractor.
+    pub strength: Option<f32>,
+
+    /// Softens the attractor's singularity.
+    pub eps: Option<f32>,
+
+    /// The fraction of velocity kept each tick, for the x and y axes.
+    pub damping: Option<[f32; 2]>,
+
+    /// The fastest a particle can move, in world units per second.
+    pub max_vel: Option<f32>,
+
+    /// The amount of simulated time covered by each tick, in milliseconds.
+    pub tick_millis: Option<u64>,
+
+    /// The most multisamples to use per pixel.
+    pub max_samples: Option<u32>,
+
+    /// A constant acceleration applied to every particle, for the x and y
+    /// axes.
+    pub gravity: Option<[f32; 2]>,
+
+    /// The background color's sRGB red, green, and blue, from 0 to 1.
+    pub clear_color: Option<[f32; 3]>,
+}
+
+/// The keys which Config understands, anything else in the file is most
+/// likely a typo.
+const KNOWN_KEYS: &[&str] = &[
+    "strength",
+    "eps",
+    "damping",
+    "max_vel",
+    "tick_millis",
+    "max_samples",
+    "gravity",
+    "clear_color",
+];
+
+impl Config {
+    /// Read the config file, or use the defaults when there's no path.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Self::default()),
+        };
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read the config {:?}", path))?;
+        let table: toml::value::Table = toml::from_str(&text)
+            .with_context(|| format!("malformed toml in {:?}", path))?;
+        for key in table.keys() {
+            if !KNOWN_KEYS.contains(&key.as_str()) {
+                log::warn!("ignoring unknown key {:?} in {:?}", key, path);
+            }
+        }
+        let config: Self = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("invalid config in {:?}", path))?;
+        log::info!("loaded {:#?} from {:?}", config, path);
+        Ok(config)
+    }
+}
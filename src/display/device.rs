@@ -1,7 +1,8 @@
-use anyhow::{Context, Result};
+use crate::error::ParticleError;
+use anyhow::Context;
 use std::sync::Arc;
 use vulkano::device::{Device, DeviceExtensions, Features, Queue};
-use vulkano::instance::{Instance, PhysicalDevice};
+use vulkano::instance::{Instance, PhysicalDevice, PhysicalDeviceType};
 use vulkano::swapchain::Surface;
 use winit::window::Window;
 
@@ -9,11 +10,15 @@ mod queue_family_indices;
 
 use queue_family_indices::QueueFamilyIndices;
 
-/// Create a logical device and command queues
+type DeviceAndQueues = (Arc<Device>, Arc<Queue>, Arc<Queue>, Arc<Queue>);
+
+/// Create a logical device and its graphics, present, and compute command
+/// queues, returned in that order.
 pub fn create_logical_device(
     surface: &Arc<Surface<Window>>,
     physical_device: &PhysicalDevice,
-) -> Result<(Arc<Device>, Arc<Queue>, Arc<Queue>, Arc<Queue>)> {
+    sample_shading: bool,
+) -> Result<DeviceAndQueues, ParticleError> {
     let indices = QueueFamilyIndices::find(surface, &physical_device)?;
     let unique_indices = indices.unique_indices();
 
@@ -22,9 +27,18 @@ pub fn create_logical_device(
         .map(|index| physical_device.queue_families().nth(*index).unwrap())
         .map(|family| (family, 1.0f32));
 
+    let mut features = required_device_features();
+    if sample_shading {
+        if physical_device.supported_features().sample_rate_shading {
+            features.sample_rate_shading = true;
+        } else {
+            log::warn!("sample shading is not supported by this device");
+        }
+    }
+
     let (device, queues) = Device::new(
         *physical_device,
-        &required_device_features(),
+        &features,
         &required_device_extensions(),
         families,
     )
@@ -36,67 +50,176 @@ pub fn create_logical_device(
     Ok((device, graphics_queue, present_queue, compute_queue))
 }
 
-/// Take the first suitable physical device
+/// True when the device presents from its graphics queue family, so frames
+/// can be drawn and presented on the same queue.
+pub fn is_single_queue_family(
+    surface: &Arc<Surface<Window>>,
+    physical_device: &PhysicalDevice,
+) -> Result<bool, ParticleError> {
+    Ok(QueueFamilyIndices::find(surface, physical_device)?.is_same_queue())
+}
+
+/// Pick the most preferred suitable physical device, discrete gpus first
+/// then integrated ones, or the device at index `gpu` when it's set.
+///
+/// Software rasterizers are only considered when no hardware device is
+/// suitable, and then only when `allow_software` is set.
 pub fn pick_physical_device<'a>(
     surface: &Arc<Surface<Window>>,
     instance: &'a Arc<Instance>,
-) -> Result<PhysicalDevice<'a>> {
+    allow_software: bool,
+    gpu: Option<usize>,
+) -> Result<PhysicalDevice<'a>, ParticleError> {
     let devices: Vec<PhysicalDevice> =
         PhysicalDevice::enumerate(&instance).collect();
 
     let names: Vec<String> = devices
         .iter()
-        .map(|properties| properties.name().to_owned())
+        .enumerate()
+        .map(|(index, properties)| {
+            format!("{}: {} ({:?})", index, properties.name(), properties.ty())
+        })
         .collect();
     log::info!("available devices {:?}", names);
 
-    devices
+    if let Some(index) = gpu {
+        let device = *devices.get(index).ok_or_else(|| {
+            ParticleError::DeviceSelection(format!(
+                "--gpu {} is out of range, the available devices are {:?}",
+                index, names
+            ))
+        })?;
+        let reasons = device_rejections(surface, &device);
+        if !reasons.is_empty() {
+            return Err(ParticleError::DeviceSelection(format!(
+                "{:?}, selected with --gpu, is not suitable - {}",
+                device.name(),
+                reasons.join(", ")
+            )));
+        }
+        log::info!("using {:?}, selected with --gpu {}", device.name(), index);
+        return Ok(device);
+    }
+
+    let mut suitable = vec![];
+    let mut rejections = vec![];
+    for device in &devices {
+        let reasons = device_rejections(surface, device);
+        if reasons.is_empty() {
+            suitable.push(*device);
+        } else {
+            let rejection =
+                format!("{:?} - {}", device.name(), reasons.join(", "));
+            log::warn!("rejected {}", rejection);
+            rejections.push(rejection);
+        }
+    }
+
+    if let Some(device) = suitable
         .iter()
-        .find(|device| is_device_suitable(&surface, &device))
-        .cloned()
-        .context("unable to pick a suitable physical device")
+        .filter(|device| device.ty() != PhysicalDeviceType::Cpu)
+        .min_by_key(|device| type_preference(device.ty()))
+    {
+        log::info!(
+            "using {:?}, the most preferred suitable device type ({:?})",
+            device.name(),
+            device.ty()
+        );
+        return Ok(*device);
+    }
+
+    let software = suitable.first().cloned().ok_or_else(|| {
+        ParticleError::DeviceSelection(if devices.is_empty() {
+            "vulkan didn't report any devices, check that a vulkan driver \
+            is installed or use a software rasterizer like lavapipe with \
+            --allow-software"
+                .to_owned()
+        } else {
+            format!(
+                "none of the available devices are suitable:\n  {}",
+                rejections.join("\n  ")
+            )
+        })
+    })?;
+    if !allow_software {
+        return Err(ParticleError::DeviceSelection(format!(
+            "the only suitable device, {:?}, is a software rasterizer. \
+            Pass --allow-software to use it anyways.",
+            software.name()
+        )));
+    }
+    log::warn!(
+        "!!! falling back to the software rasterizer {:?}, \
+        expect very poor performance !!!",
+        software.name()
+    );
+    Ok(software)
 }
 
-/// Find a device which suits the application's needs
-fn is_device_suitable(
+/// Lower is better. Discrete gpus are usually much faster than integrated
+/// ones when a laptop has both.
+fn type_preference(ty: PhysicalDeviceType) -> u32 {
+    match ty {
+        PhysicalDeviceType::DiscreteGpu => 0,
+        PhysicalDeviceType::IntegratedGpu => 1,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Other => 3,
+        PhysicalDeviceType::Cpu => 4,
+    }
+}
+
+/// Every reason the device doesn't suit the application's needs, empty
+/// when it's suitable.
+fn device_rejections(
     surface: &Arc<Surface<Window>>,
     device: &PhysicalDevice,
-) -> bool {
-    let queue_supported = QueueFamilyIndices::find(surface, device)
-        .map_or_else(
-            |error| {
-                log::warn!(
-                    "{:?} is not suitable because - {:?}",
-                    device.name(),
-                    error
-                );
-                false
-            },
-            |_indices| true,
-        );
-    let extensions_supported = check_device_extension_support(&device);
-    let swap_chain_adequate = if extensions_supported {
-        let capabilities = surface
-            .capabilities(*device)
-            .expect("unable to get surface capabilities");
-        !capabilities.supported_formats.is_empty()
-            && capabilities.present_modes.iter().next().is_some()
+) -> Vec<String> {
+    let mut reasons = vec![];
+    if let Err(error) = QueueFamilyIndices::find(surface, device) {
+        reasons.push(format!("{:#}", error));
+    }
+
+    let missing_extensions = missing_device_extensions(device);
+    if missing_extensions.is_empty() {
+        // a failed query only rules out this device, another may still work
+        match surface.capabilities(*device) {
+            Ok(capabilities) => {
+                if capabilities.supported_formats.is_empty() {
+                    reasons.push("no surface formats".to_owned());
+                }
+                if capabilities.present_modes.iter().next().is_none() {
+                    reasons.push("no present modes".to_owned());
+                }
+            }
+            Err(error) => reasons.push(format!(
+                "unable to get surface capabilities ({})",
+                error
+            )),
+        }
     } else {
-        false
-    };
-    let features_supported = check_device_feature_support(&device);
-
-    queue_supported
-        && extensions_supported
-        && swap_chain_adequate
-        && features_supported
+        reasons.push(format!(
+            "missing extensions {}",
+            missing_extensions.join(" and ")
+        ));
+    }
+
+    if !check_device_feature_support(device) {
+        reasons.push("missing the large_points feature".to_owned());
+    }
+    reasons
 }
 
-/// Check that the device supports all of the required extensions
-fn check_device_extension_support(device: &PhysicalDevice) -> bool {
-    let extensions = DeviceExtensions::supported_by_device(*device);
-    let supported = extensions.intersection(&required_device_extensions());
-    supported.khr_swapchain && supported.khr_storage_buffer_storage_class
+/// The names of the required extensions which the device doesn't support.
+fn missing_device_extensions(device: &PhysicalDevice) -> Vec<&'static str> {
+    let supported = DeviceExtensions::supported_by_device(*device);
+    let mut missing = vec![];
+    if !supported.khr_swapchain {
+        missing.push("VK_KHR_swapchain");
+    }
+    if !supported.khr_storage_buffer_storage_class {
+        missing.push("VK_KHR_storage_buffer_storage_class");
+    }
+    missing
 }
 
 /// Yield the set of required device extensions
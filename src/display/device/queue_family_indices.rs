@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use std::sync::Arc;
 use vulkano::device::{Queue, QueuesIter};
 use vulkano::instance::PhysicalDevice;
@@ -26,7 +26,10 @@ impl QueueFamilyIndices {
                 graphics = Some(i);
             }
 
-            if surface.is_supported(family)? {
+            if surface
+                .is_supported(family)
+                .context("unable to check for present support")?
+            {
                 present = Some(i);
             }
 
@@ -39,15 +42,29 @@ impl QueueFamilyIndices {
             }
         }
 
-        graphics
-            .zip(present)
-            .zip(compute)
-            .map(|((graphics_family, present_family), compute_family)| Self {
+        match (graphics, present, compute) {
+            (
+                Some(graphics_family),
+                Some(present_family),
+                Some(compute_family),
+            ) => Ok(Self {
                 graphics_family,
                 present_family,
                 compute_family,
-            })
-            .context("unable to find all required queue families for this physical device")
+            }),
+            _ => {
+                let missing: Vec<&str> = [
+                    (graphics, "graphics"),
+                    (present, "present"),
+                    (compute, "compute"),
+                ]
+                .iter()
+                .filter(|(family, _)| family.is_none())
+                .map(|(_, name)| *name)
+                .collect();
+                bail!("no queue family with {} support", missing.join(" or "))
+            }
+        }
     }
 
     /// Return the set of unique queue family indices
@@ -62,41 +79,85 @@ impl QueueFamilyIndices {
         indices
     }
 
-    /// get the graphics and present queues based on the index order returned
-    /// by unique_indices
+    /// The position in `unique_indices` of the graphics, present, and
+    /// compute families, in that order. Families which are shared share a
+    /// position, so their queues alias each other.
+    pub fn queue_positions(&self) -> [usize; 3] {
+        let indices = self.unique_indices();
+        let position = |family: usize| {
+            indices
+                .iter()
+                .position(|&index| index == family)
+                .expect("every family is in the unique indices")
+        };
+        [
+            position(self.graphics_family),
+            position(self.present_family),
+            position(self.compute_family),
+        ]
+    }
+
+    /// get the graphics, present, and compute queues, in that order, based on
+    /// the index order returned by unique_indices. Queues alias each other
+    /// when their families are shared.
     pub fn take_queues(
         &self,
-        mut queues: QueuesIter,
+        queues: QueuesIter,
     ) -> Result<(Arc<Queue>, Arc<Queue>, Arc<Queue>)> {
-        let graphics_queue = queues
-            .next()
-            .context("could not find a graphics queue for this device")?;
-
-        let present_queue = if self.is_same_queue() {
-            graphics_queue.clone()
-        } else {
-            queues.next().context(
-                "could not find a presentation queue for this device",
-            )?
+        let queues: Vec<Arc<Queue>> = queues.collect();
+        let queue = |position: usize, name: &str| {
+            queues.get(position).cloned().with_context(|| {
+                format!("could not find a {} queue for this device", name)
+            })
         };
+        let [graphics, present, compute] = self.queue_positions();
+        Ok((
+            queue(graphics, "graphics")?,
+            queue(present, "presentation")?,
+            queue(compute, "compute")?,
+        ))
+    }
 
-        let compute_queue = queues
-            .next()
-            .or_else(|| {
-                if graphics_queue.family().supports_compute() {
-                    Some(graphics_queue.clone())
-                } else if present_queue.family().supports_compute() {
-                    Some(present_queue.clone())
-                } else {
-                    None
-                }
-            })
-            .context("unable to construct a compute queue for this device")?;
+    /// True when the graphics and present families are the same.
+    pub fn is_same_queue(&self) -> bool {
+        self.graphics_family == self.present_family
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok((graphics_queue, present_queue, compute_queue))
+    fn positions(
+        graphics: usize,
+        present: usize,
+        compute: usize,
+    ) -> Vec<usize> {
+        QueueFamilyIndices {
+            graphics_family: graphics,
+            present_family: present,
+            compute_family: compute,
+        }
+        .queue_positions()
+        .to_vec()
     }
 
-    fn is_same_queue(&self) -> bool {
-        self.graphics_family == self.present_family
+    #[test]
+    fn one_family_shares_a_single_queue() {
+        assert_eq!(positions(0, 0, 0), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn separate_families_take_queues_in_role_order() {
+        assert_eq!(positions(0, 1, 2), vec![0, 1, 2]);
+        // the order follows the roles, not the family indices
+        assert_eq!(positions(2, 0, 1), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn compute_aliases_the_family_it_shares() {
+        assert_eq!(positions(0, 1, 0), vec![0, 1, 0]);
+        assert_eq!(positions(0, 1, 1), vec![0, 1, 1]);
+        assert_eq!(positions(1, 1, 0), vec![0, 0, 1]);
     }
 }
@@ -0,0 +1,149 @@
+use crate::error::ParticleError;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use vulkano::{
+    command_buffer::{
+        AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState,
+    },
+    device::Device,
+    framebuffer::{RenderPassAbstract, Subpass},
+    instance::QueueFamily,
+    pipeline::{
+        blend::AttachmentBlend,
+        vertex::{BufferlessDefinition, BufferlessVertices},
+        viewport::Viewport,
+        GraphicsPipeline, GraphicsPipelineAbstract,
+    },
+};
+
+type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
+type PushConstants = fragment_shader::ty::PushConstants;
+
+/// The full screen pass which darkens what's left of the previous frame in
+/// the scene image, so particles leave trails which fade out.
+///
+/// It's drawn first in subpass 0 instead of clearing, blending toward the
+/// background by `1 - decay` each frame, so a decay of 0 is the same as a
+/// clear. With an 8 bit swapchain format the faintest trails can round to a
+/// value which never quite reaches the background, --hdr avoids this.
+pub struct Fade {
+    device: Arc<Device>,
+    subpass: Subpass<Arc<DynRenderPass>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl Fade {
+    /// Build the fade pipeline for subpass 0 of the render pass.
+    pub fn new(
+        device: &Arc<Device>,
+        render_pass: &Arc<DynRenderPass>,
+        dimensions: [u32; 2],
+    ) -> Result<Self> {
+        let vert = vertex_shader::Shader::load(device.clone())
+            .map_err(ParticleError::shader_load("fade vertex"))?;
+        let frag = fragment_shader::Shader::load(device.clone())
+            .map_err(ParticleError::shader_load("fade fragment"))?;
+
+        let subpass = Subpass::from(render_pass.clone(), 0)
+            .context("could not create the fade pipeline subpass")?;
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+            depth_range: 0.0..1.0,
+        };
+        let pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync> =
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input(BufferlessDefinition {})
+                    .vertex_shader(vert.main_entry_point(), ())
+                    .fragment_shader(frag.main_entry_point(), ())
+                    .viewports(vec![viewport])
+                    .depth_clamp(false)
+                    .depth_write(false)
+                    .blend_collective(AttachmentBlend::alpha_blending())
+                    .triangle_list()
+                    .render_pass(subpass.clone())
+                    .build(device.clone())
+                    .context("could not create the fade pipeline")?,
+            );
+
+        Ok(Self {
+            device: device.clone(),
+            subpass,
+            pipeline,
+        })
+    }
+
+    /// Build a secondary command buffer which blends the scene toward the
+    /// background color, keeping `decay` of what was there.
+    pub fn draw(
+        &self,
+        queue_family: QueueFamily,
+        background: [f32; 4],
+        decay: f32,
+    ) -> Result<AutoCommandBuffer> {
+        let mut builder =
+            AutoCommandBufferBuilder::secondary_graphics_one_time_submit(
+                self.device.clone(),
+                queue_family,
+                self.subpass.clone(),
+            )
+            .context("unable to create the fade command buffer builder")?;
+        let constants = PushConstants {
+            color: [background[0], background[1], background[2], 1.0 - decay],
+        };
+        builder
+            .draw(
+                self.pipeline.clone(),
+                &DynamicState::none(),
+                BufferlessVertices {
+                    vertices: 3,
+                    instances: 1,
+                },
+                (),
+                constants,
+            )
+            .context("unable to draw the fade pass")?;
+        builder
+            .build()
+            .context("unable to build the fade command buffer")
+    }
+}
+
+mod vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            void main() {
+                // a single triangle which covers the whole screen
+                vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+                gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+            }
+            "#
+    }
+}
+
+mod fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            layout(push_constant) uniform PushConstants {
+                // the background color, with alpha set to how much of the
+                // previous frame is replaced
+                vec4 color;
+            } pc;
+
+            layout(location = 0) out vec4 outColor;
+
+            void main() {
+                outColor = pc.color;
+            }
+            "#
+    }
+}
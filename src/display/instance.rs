@@ -7,16 +7,27 @@ use vulkano::instance::{
 };
 
 const VALIDATION_LAYERS: &[&str] = &["VK_LAYER_KHRONOS_validation"];
-const ENABLE_VALIDATION_LAYERS: bool = cfg!(debug_assertions);
 
-pub fn create_instance() -> Result<Arc<Instance>> {
-    if ENABLE_VALIDATION_LAYERS && !check_debug_layers()? {
-        log::warn!("requested validation layers are unavailable")
-    }
+/// Set to 1 or 0 to turn validation on or off regardless of the build
+/// profile, useful for debugging problems which only show up in optimized
+/// builds.
+const VALIDATION_VAR: &str = "PARTICLE_VALIDATION";
+
+/// The least severe validation message to log: error, warning, info, or
+/// verbose. Warnings and errors are logged when it's unset.
+const SEVERITY_VAR: &str = "PARTICLE_VALIDATION_SEVERITY";
 
+pub fn create_instance() -> Result<Arc<Instance>> {
     let supported_extensions = InstanceExtensions::supported_by_core()
         .context("unable to get supported instance extensions")?;
-    let required_extensions = required_extensions();
+    let validation =
+        validation_requested() && validation_available(&supported_extensions)?;
+    let mut required_extensions = required_extensions(validation);
+
+    // needed for the extended color spaces used by hdr displays, but not
+    // required when presenting in sRGB
+    required_extensions.ext_swapchain_colorspace =
+        supported_extensions.ext_swapchain_colorspace;
     log::info!("supported extensions: {:?}", supported_extensions);
     log::info!("required extensions: {:?}", required_extensions);
 
@@ -31,7 +42,78 @@ pub fn create_instance() -> Result<Arc<Instance>> {
         engine_version: None,
     };
 
-    Ok(Instance::new(Some(&app_info), &required_extensions, None)?)
+    let layers: &[&str] = if validation { VALIDATION_LAYERS } else { &[] };
+    Ok(Instance::new(
+        Some(&app_info),
+        &required_extensions,
+        layers.iter().copied(),
+    )?)
+}
+
+/// Whether validation should be enabled, following PARTICLE_VALIDATION when
+/// it's set and debug_assertions when it isn't.
+fn validation_requested() -> bool {
+    let default = cfg!(debug_assertions);
+    match std::env::var(VALIDATION_VAR) {
+        Ok(value) => match value.as_str() {
+            "1" | "true" | "on" => true,
+            "0" | "false" | "off" => false,
+            _ => {
+                log::warn!(
+                    "ignoring {}={:?}, expected 1 or 0",
+                    VALIDATION_VAR,
+                    value
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// The severities the debug callback receives, everything at least as
+/// severe as PARTICLE_VALIDATION_SEVERITY.
+fn debug_severity() -> MessageSeverity {
+    let level = std::env::var(SEVERITY_VAR).unwrap_or_default();
+    let rank = match level.as_str() {
+        "error" => 0,
+        "" | "warning" => 1,
+        "info" => 2,
+        "verbose" => 3,
+        _ => {
+            log::warn!(
+                "ignoring {}={:?}, expected error, warning, info, or verbose",
+                SEVERITY_VAR,
+                level
+            );
+            1
+        }
+    };
+    MessageSeverity {
+        error: true,
+        warning: rank >= 1,
+        information: rank >= 2,
+        verbose: rank >= 3,
+    }
+}
+
+/// True when both the validation layers and the debug utils extension
+/// they report through are installed. Either can be missing on machines
+/// without the vulkan sdk, in which case validation is skipped with a
+/// warning rather than failing to create the instance.
+fn validation_available(supported: &InstanceExtensions) -> Result<bool> {
+    if !check_debug_layers()? {
+        log::warn!("requested validation layers are unavailable");
+        return Ok(false);
+    }
+    if !supported.ext_debug_utils {
+        log::warn!(
+            "validation layers are installed but VK_EXT_debug_utils is not, \
+             continuing without validation"
+        );
+        return Ok(false);
+    }
+    Ok(true)
 }
 
 fn check_debug_layers() -> Result<bool> {
@@ -47,25 +129,20 @@ fn check_debug_layers() -> Result<bool> {
     Ok(all_available)
 }
 
-fn required_extensions() -> InstanceExtensions {
+fn required_extensions(validation: bool) -> InstanceExtensions {
     let mut required_extensions = vulkano_win::required_extensions();
-    if ENABLE_VALIDATION_LAYERS {
-        required_extensions.ext_debug_utils = true;
-    }
+    required_extensions.ext_debug_utils = validation;
     required_extensions
 }
 
+/// Route validation messages into the log. Does nothing unless the instance
+/// was created with validation enabled.
 pub fn setup_debug_callback(instance: &Arc<Instance>) -> Option<DebugCallback> {
-    if !ENABLE_VALIDATION_LAYERS {
+    if !instance.loaded_extensions().ext_debug_utils {
         return None;
     }
 
-    let severity = MessageSeverity {
-        error: true,
-        warning: true,
-        information: true,
-        verbose: true,
-    };
+    let severity = debug_severity();
 
     let msgtype = MessageType {
         general: true,
@@ -1,30 +1,263 @@
-use anyhow::{Context, Result};
+use crate::error::ParticleError;
+use anyhow::{anyhow, Context, Result};
 use std::sync::Arc;
-use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder};
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::{
+    AutoCommandBuffer, AutoCommandBufferBuilder, SubpassContents,
+};
 use vulkano::device::{Device, Queue};
-use vulkano::format::ClearValue;
-use vulkano::framebuffer::{FramebufferAbstract, RenderPassAbstract};
-use vulkano::image::swapchain::SwapchainImage;
+use vulkano::format::{ClearValue, Format};
+use vulkano::framebuffer::{FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::{swapchain::SwapchainImage, AttachmentImage};
 use vulkano::instance::debug::DebugCallback;
 use vulkano::instance::Instance;
 use vulkano::swapchain::acquire_next_image;
-use vulkano::swapchain::{Surface, Swapchain};
-use vulkano::sync::GpuFuture;
+use vulkano::swapchain::{AcquireError, Surface, Swapchain};
+use vulkano::sync::{FlushError, GpuFuture};
 use vulkano_win::VkSurfaceBuild;
 use winit::dpi::LogicalSize;
 use winit::event_loop::EventLoop;
 use winit::window::{Window, WindowBuilder};
 
 mod device;
+mod fade;
 mod instance;
+mod offscreen;
 mod swapchain;
+mod tonemap;
 
+pub use offscreen::OffscreenTarget;
+use swapchain::SceneLayout;
+
+/// Whether the swapchain can keep being used after a frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SwapchainState {
     Optimal,
     NeedsRebuild,
 }
 
+/// The presentation mode to use when the surface supports it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PreferredPresentMode {
+    /// Low latency without tearing, frames which aren't presented in time
+    /// are replaced by newer ones.
+    Mailbox,
+
+    /// Strict vsync.
+    Fifo,
+
+    /// Vsync which tears, rather than waiting a whole refresh, when a frame
+    /// is late.
+    FifoRelaxed,
+
+    /// No vsync at all. Frames are presented as soon as they're ready and
+    /// will tear, useful for measuring maximum throughput.
+    Immediate,
+}
+
+impl Default for PreferredPresentMode {
+    fn default() -> Self {
+        PreferredPresentMode::Mailbox
+    }
+}
+
+impl PreferredPresentMode {
+    /// The next mode, used to cycle through the modes at runtime.
+    pub fn next(&self) -> Self {
+        match self {
+            PreferredPresentMode::Mailbox => PreferredPresentMode::Fifo,
+            PreferredPresentMode::Fifo => PreferredPresentMode::FifoRelaxed,
+            PreferredPresentMode::FifoRelaxed => {
+                PreferredPresentMode::Immediate
+            }
+            PreferredPresentMode::Immediate => PreferredPresentMode::Mailbox,
+        }
+    }
+}
+
+impl std::str::FromStr for PreferredPresentMode {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "mailbox" => Ok(PreferredPresentMode::Mailbox),
+            "fifo" => Ok(PreferredPresentMode::Fifo),
+            "fifo-relaxed" => Ok(PreferredPresentMode::FifoRelaxed),
+            "immediate" => Ok(PreferredPresentMode::Immediate),
+            _ => anyhow::bail!(
+                "unknown present mode {:?}, expected mailbox, fifo, \
+                 fifo-relaxed, or immediate",
+                name
+            ),
+        }
+    }
+}
+
+/// The curve which maps hdr colors into the displayable range.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TonemapOperator {
+    /// A filmic curve with a gentle toe and shoulder, which slightly
+    /// desaturates the brightest areas.
+    Aces,
+
+    /// `x / (1 + x)`, which keeps hues but compresses highlights sooner.
+    Reinhard,
+}
+
+impl Default for TonemapOperator {
+    fn default() -> Self {
+        TonemapOperator::Aces
+    }
+}
+
+impl TonemapOperator {
+    /// The value of the tonemap shader's `operator` push constant.
+    pub fn shader_id(&self) -> i32 {
+        match self {
+            TonemapOperator::Aces => 0,
+            TonemapOperator::Reinhard => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for TonemapOperator {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "aces" => Ok(TonemapOperator::Aces),
+            "reinhard" => Ok(TonemapOperator::Reinhard),
+            _ => anyhow::bail!(
+                "unknown tonemap operator {:?}, expected aces or reinhard",
+                name
+            ),
+        }
+    }
+}
+
+/// A background color, sRGB encoded like the colors a color picker gives.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClearColor(pub [f32; 4]);
+
+impl Default for ClearColor {
+    fn default() -> Self {
+        ClearColor([0.0, 0.0, 0.0, 1.0])
+    }
+}
+
+impl std::str::FromStr for ClearColor {
+    type Err = anyhow::Error;
+
+    /// Parse a hex color like `#f0f0f0`, the leading # is optional.
+    fn from_str(hex: &str) -> Result<Self> {
+        let digits = hex.trim_start_matches('#');
+        let value = u32::from_str_radix(digits, 16)
+            .ok()
+            .filter(|_| digits.len() == 6)
+            .with_context(|| {
+                format!("expected a color like #rrggbb, not {:?}", hex)
+            })?;
+        let channel = |shift: u32| ((value >> shift) & 0xff) as f32 / 255.0;
+        Ok(ClearColor([channel(16), channel(8), channel(0), 1.0]))
+    }
+}
+
+/// Options which control how the display picks and configures its device.
+#[derive(Debug, Clone, Default)]
+pub struct DisplayOptions {
+    /// Allow a software rasterizer when no hardware device is suitable.
+    pub allow_software: bool,
+
+    /// Use the physical device at this index in vulkan's list rather than
+    /// picking one.
+    pub gpu: Option<usize>,
+
+    /// The number of swapchain images to request. This is clamped to the
+    /// range supported by the surface. When unset, one more than the minimum
+    /// is used.
+    pub image_count: Option<u32>,
+
+    /// Draw into a float image and tonemap it into the swapchain, presenting
+    /// to an hdr format when the surface supports one.
+    pub hdr: bool,
+
+    /// The curve used to tonemap when hdr is enabled.
+    pub tonemap: TonemapOperator,
+
+    /// Fail instead of falling back to another format when the surface does
+    /// not offer B8G8R8A8Srgb.
+    pub require_srgb: bool,
+
+    /// Enable sample rate shading, when the device supports it, so
+    /// multisampled points have well defined coverage.
+    pub sample_shading: bool,
+
+    /// The presentation mode to use, falling back to Fifo when it isn't
+    /// supported.
+    pub present_mode: PreferredPresentMode,
+
+    /// The window's initial size, in logical pixels.
+    pub window_size: [u32; 2],
+
+    /// The most multisamples to use per pixel. The device's maximum is used
+    /// when unset, 1 disables multisampling.
+    pub max_samples: Option<u32>,
+
+    /// The background each frame is cleared to.
+    pub clear_color: ClearColor,
+}
+
+/// A subpass of the display's render pass.
+pub type RenderSubpass = Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>;
+
 pub struct Display {
+    options: DisplayOptions,
+
+    /// The background, sRGB encoded. It's converted to match the swapchain
+    /// format when frames are cleared.
+    pub clear_color: [f32; 4],
+
+    /// Clear to the negative of the background color.
+    pub invert_colors: bool,
+
+    /// Scales the scene's brightness before it's tonemapped. Only used when
+    /// the display was created with the hdr option.
+    pub exposure: f32,
+
+    /// How much of the previous frame is kept each frame, 0 clears the
+    /// screen and values closer to 1 leave longer trails.
+    pub trail_decay: f32,
+
+    tonemap: Option<tonemap::Tonemap>,
+    fade: fade::Fade,
+
+    /// The image which every framebuffer draws the scene into. It's kept
+    /// between frames so it can be faded rather than cleared.
+    scene_image: Arc<AttachmentImage>,
+
+    /// Whether the scene image is resolved into the swapchain image or
+    /// drawn into directly.
+    scene_layout: SceneLayout,
+
+    /// False until the scene image has been cleared once, its contents are
+    /// undefined after it's created.
+    scene_initialized: bool,
+
+    /// Set by capture_next_frame. The next frame is copied out of the
+    /// swapchain image before it's presented.
+    capture_requested: bool,
+
+    /// The copy of the last frame presented after capture_next_frame, in the
+    /// swapchain's format.
+    captured_frame: Option<Arc<CpuAccessibleBuffer<[u8]>>>,
+
+    /// Graphics and presentation share a queue family.
+    single_queue: bool,
+
+    /// Set when the options have changed, so the next rebuild creates a new
+    /// swapchain rather than recreating the current one.
+    options_changed: bool,
+
     // vulkan library resources
     pub instance: Arc<Instance>,
     pub debug_callback: Option<DebugCallback>,
@@ -45,7 +278,7 @@ pub struct Display {
 }
 
 impl Display {
-    pub fn create() -> Result<Self> {
+    pub fn create(options: &DisplayOptions) -> Result<Self, ParticleError> {
         let instance = instance::create_instance()
             .context("unable to create the vulkan instance")?;
         let debug_callback = instance::setup_debug_callback(&instance);
@@ -56,34 +289,61 @@ impl Display {
             .with_resizable(true)
             .with_decorations(true)
             .with_visible(false)
-            .with_inner_size(LogicalSize::new(1366, 768))
+            .with_inner_size(LogicalSize::new(
+                options.window_size[0],
+                options.window_size[1],
+            ))
             .build_vk_surface(&event_loop, instance.clone())
             .context("unable to build the main vulkan window")?;
 
-        let physical_device =
-            device::pick_physical_device(&surface, &instance)?;
+        let physical_device = device::pick_physical_device(
+            &surface,
+            &instance,
+            options.allow_software,
+            options.gpu,
+        )?;
 
+        let single_queue =
+            device::is_single_queue_family(&surface, &physical_device)?;
         let (device, graphics_queue, present_queue, compute_queue) =
-            device::create_logical_device(&surface, &physical_device)?;
+            device::create_logical_device(
+                &surface,
+                &physical_device,
+                options.sample_shading,
+            )?;
         let (swapchain, swapchain_images) = swapchain::create_swap_chain(
             &surface,
             &physical_device,
             &device,
             &graphics_queue,
             &present_queue,
+            options,
+            None,
         )?;
 
-        let render_pass =
-            swapchain::create_render_pass(&device, swapchain.format())?;
-
-        let framebuffer_images = swapchain::create_framebuffers(
+        let targets = create_render_targets(
             &device,
-            swapchain.format(),
+            &swapchain,
             &swapchain_images,
-            &render_pass,
-        );
+            options,
+        )?;
 
         Ok(Display {
+            options: options.clone(),
+            clear_color: options.clear_color.0,
+            invert_colors: false,
+            exposure: 1.0,
+            trail_decay: 0.0,
+            tonemap: targets.tonemap,
+            fade: targets.fade,
+            scene_image: targets.scene,
+            scene_layout: targets.scene_layout,
+            scene_initialized: false,
+            capture_requested: false,
+            captured_frame: None,
+            single_queue,
+            options_changed: false,
+
             // library resources
             instance,
             debug_callback,
@@ -91,10 +351,10 @@ impl Display {
             // window/surface resources
             surface,
             event_loop: Option::Some(event_loop),
-            render_pass,
+            render_pass: targets.render_pass,
             swapchain,
             swapchain_images,
-            framebuffer_images,
+            framebuffer_images: targets.framebuffers,
 
             // devices and queues
             device,
@@ -106,60 +366,222 @@ impl Display {
 
     /// Rebuild the swapchain and dependent resources based on the the
     /// window's current size.
-    pub fn rebuild_swapchain(&mut self) -> Result<()> {
-        let size = self.surface.window().inner_size();
-        let (swapchain, swapchain_images) = self
-            .swapchain
-            .recreate_with_dimensions([size.width, size.height])
-            .context("unable to recreate the swapchain")?;
-        let render_pass =
-            swapchain::create_render_pass(&self.device, swapchain.format())
-                .context("unable to recreate the render pass")?;
-        let framebuffer_images = swapchain::create_framebuffers(
+    ///
+    /// The surface capabilities are queried again because they can change
+    /// when the window moves between monitors. If the current format or
+    /// present mode is no longer supported then the swapchain is rebuilt from
+    /// scratch with freshly chosen settings.
+    pub fn rebuild_swapchain(&mut self) -> Result<(), ParticleError> {
+        let physical_device = self.device.physical_device();
+        let compatible = !self.options_changed
+            && swapchain::is_swapchain_compatible(
+                &self.surface,
+                &physical_device,
+                &self.swapchain,
+            )?;
+        let (swapchain, swapchain_images) = if compatible {
+            let size = self.surface.window().inner_size();
+            swapchain::recreate_swap_chain(
+                &self.swapchain,
+                [size.width, size.height],
+            )?
+        } else {
+            log::warn!(
+                "surface capabilities changed, rebuilding the swapchain"
+            );
+            swapchain::create_swap_chain(
+                &self.surface,
+                &physical_device,
+                &self.device,
+                &self.graphics_queue,
+                &self.present_queue,
+                &self.options,
+                Some(&self.swapchain),
+            )
+            .context("unable to rebuild the swapchain")?
+        };
+        let targets = create_render_targets(
             &self.device,
-            swapchain.format(),
+            &swapchain,
             &swapchain_images,
-            &render_pass,
-        );
+            &self.options,
+        )
+        .context("unable to recreate the render targets")?;
 
         self.swapchain = swapchain;
         self.swapchain_images = swapchain_images;
-        self.render_pass = render_pass;
-        self.framebuffer_images = framebuffer_images;
+        self.render_pass = targets.render_pass;
+        self.framebuffer_images = targets.framebuffers;
+        self.tonemap = targets.tonemap;
+        self.fade = targets.fade;
+        self.scene_image = targets.scene;
+        self.scene_layout = targets.scene_layout;
+        self.scene_initialized = false;
+        self.captured_frame = None;
+        self.options_changed = false;
 
         Ok(())
     }
 
+    /// The presentation mode which was last requested.
+    pub fn preferred_present_mode(&self) -> PreferredPresentMode {
+        self.options.present_mode
+    }
+
+    /// The most multisamples the swapchain was allowed to use.
+    pub fn max_samples(&self) -> Option<u32> {
+        self.options.max_samples
+    }
+
+    /// Request a different presentation mode. This takes effect the next
+    /// time the swapchain is rebuilt.
+    pub fn set_present_mode(&mut self, present_mode: PreferredPresentMode) {
+        self.options.present_mode = present_mode;
+        self.options_changed = true;
+    }
+
+    /// The number of images in the swapchain. Resources which are written
+    /// once per frame in flight should be sized to match.
+    pub fn image_count(&self) -> usize {
+        self.swapchain_images.len()
+    }
+
+    /// Block until the device has finished all submitted work, so no
+    /// resources are destroyed while the GPU is still using them.
+    pub fn wait_idle(&self) -> Result<(), ParticleError> {
+        // Safe because the application is single threaded, nothing else can
+        // be submitting to the device's queues while this waits.
+        unsafe { self.device.wait() }
+            .map_err(ParticleError::submission)
+            .context("unable to wait for the device to become idle")?;
+        Ok(())
+    }
+
+    /// Wait for the device to finish and remove the validation callback.
+    /// Call this once nothing else will be submitted, before the display is
+    /// dropped.
+    pub fn shutdown(&mut self) -> Result<(), ParticleError> {
+        self.wait_idle()?;
+        if self.debug_callback.take().is_some() {
+            log::debug!("removed the validation callback");
+        }
+        log::info!("device idle, shutting down");
+        Ok(())
+    }
+
+    /// True when the swapchain format stores color without sRGB encoding, so
+    /// shaders must apply gamma themselves before writing.
+    ///
+    /// Always false in hdr mode because the scene is drawn into a linear
+    /// float image and the tonemap pass handles the encoding.
+    pub fn needs_manual_gamma(&self) -> bool {
+        self.tonemap.is_none() && needs_manual_gamma(self.swapchain.format())
+    }
+
+    /// The color each frame is cleared to before drawing, in the space the
+    /// color attachments store.
+    ///
+    /// sRGB and float attachments hold linear values, so the background is
+    /// decoded for them. It's inverted in linear space, like the particles,
+    /// then encoded again when the swapchain stores values as-is.
+    pub fn encoded_clear_color(&self) -> [f32; 4] {
+        let mut color = self.clear_color;
+        for channel in &mut color[..3] {
+            // the same approximate curve the particle shader encodes with
+            let mut linear = channel.powf(2.2);
+            if self.invert_colors {
+                linear = 1.0 - linear;
+            }
+            *channel = if self.needs_manual_gamma() {
+                linear.powf(1.0 / 2.2)
+            } else {
+                linear
+            };
+        }
+        color
+    }
+
+    /// True when the scene is drawn in hdr and tonemapped.
+    pub fn is_hdr(&self) -> bool {
+        self.tonemap.is_some()
+    }
+
+    /// The subpass which draws directly into the swapchain image. Overlays
+    /// like the hud are drawn here so they skip the tonemap pass.
+    pub fn overlay_subpass(&self) -> Result<RenderSubpass, ParticleError> {
+        let index = if self.tonemap.is_some() { 1 } else { 0 };
+        Ok(Subpass::from(self.render_pass.clone(), index)
+            .context("unable to select the overlay subpass")?)
+    }
+
     /// Render the frame.
     ///
     /// @param graphics_queue_subbuffers a vector of secondary command buffers
     /// to be executed on the graphics queue
+    /// @param overlay_subbuffers secondary command buffers built for the
+    /// overlay subpass, drawn on top of everything else
+    /// @param compute the work which has to finish on the gpu before the
+    /// frame is drawn, like the dispatches which wrote the vertex buffers
     pub fn render(
         &mut self,
         graphics_queue_subbuffers: Vec<AutoCommandBuffer>,
-    ) -> Result<SwapchainState> {
+        overlay_subbuffers: Vec<AutoCommandBuffer>,
+        compute: Box<dyn GpuFuture>,
+    ) -> Result<SwapchainState, ParticleError> {
         let (image_index, suboptimal, acquire_swapchain_future) =
-            acquire_next_image(self.swapchain.clone(), None).with_context(
-                || "unable to acquire next frame for rendering",
-            )?;
+            match acquire_next_image(self.swapchain.clone(), None) {
+                Ok(acquired) => acquired,
+                Err(error) => return acquire_error_state(error),
+            };
 
+        let capture = if self.capture_requested {
+            Some(offscreen::create_readback_buffer(
+                &self.device,
+                self.swapchain.format(),
+                self.swapchain.dimensions(),
+            )?)
+        } else {
+            None
+        };
         let render_buffer = self.build_render_pass_command_buffer(
             graphics_queue_subbuffers,
+            overlay_subbuffers,
             image_index,
+            capture.as_ref(),
         )?;
 
-        acquire_swapchain_future
+        // Presenting from the graphics queue keeps the whole frame on one
+        // queue, so no semaphore or ownership transfer is needed between
+        // drawing and presenting.
+        let present_queue = if self.single_queue {
+            &self.graphics_queue
+        } else {
+            &self.present_queue
+        };
+        let flushed = acquire_swapchain_future
+            .join(compute)
             .then_execute(self.graphics_queue.clone(), render_buffer)
+            .map_err(ParticleError::submission)
             .with_context(|| "unable to execute the display command buffer")?
             .then_swapchain_present(
-                self.present_queue.clone(),
+                present_queue.clone(),
                 self.swapchain.clone(),
                 image_index,
             )
-            .then_signal_fence_and_flush()
-            .with_context(|| "unable to present, signal, and flush")?
+            .then_signal_fence_and_flush();
+        let frame = match flushed {
+            Ok(frame) => frame,
+            Err(error) => return flush_error_state(error),
+        };
+        frame
             .wait(None)
+            .map_err(ParticleError::submission)
             .with_context(|| "unable to complete the frame")?;
+        self.scene_initialized = true;
+        if capture.is_some() {
+            self.captured_frame = capture;
+            self.capture_requested = false;
+        }
 
         if suboptimal {
             Ok(SwapchainState::NeedsRebuild)
@@ -168,6 +590,152 @@ impl Display {
         }
     }
 
+    /// Create a target for rendering without the swapchain. It matches the
+    /// swapchain's size and format so pipelines built for the window can
+    /// draw into it.
+    pub fn create_offscreen_target(
+        &self,
+    ) -> Result<OffscreenTarget, ParticleError> {
+        if self.tonemap.is_some() {
+            return Err(anyhow!(
+                "offscreen rendering is not supported in hdr mode"
+            )
+            .into());
+        }
+        Ok(OffscreenTarget::new(
+            &self.device,
+            &self.render_pass,
+            self.swapchain.format(),
+            self.swapchain.dimensions(),
+        )?)
+    }
+
+    /// Render a frame into the offscreen target and read back its pixels,
+    /// tightly packed in the swapchain's format. Blocks until the GPU has
+    /// finished.
+    pub fn render_offscreen(
+        &self,
+        target: &OffscreenTarget,
+        graphics_queue_subbuffers: Vec<AutoCommandBuffer>,
+    ) -> Result<Vec<u8>, ParticleError> {
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+            self.device.clone(),
+            self.graphics_queue.family(),
+        )
+        .context("unable to create the offscreen command buffer builder")?;
+
+        let clear_color = self.encoded_clear_color();
+        builder
+            .clear_color_image(
+                target.scene.clone(),
+                ClearValue::Float(clear_color),
+            )
+            .context("unable to clear the offscreen scene image")?
+            .begin_render_pass(
+                target.framebuffer.clone(),
+                SubpassContents::SecondaryCommandBuffers,
+                self.clear_values(clear_color),
+            )
+            .context("unable to begin the offscreen render pass")?;
+        unsafe {
+            // unsafe for the same reason as when rendering to the swapchain
+            builder
+                .execute_commands_from_vec(graphics_queue_subbuffers)
+                .context("error while rendering offscreen graphics commands")?;
+        }
+        builder
+            .end_render_pass()
+            .context("unable to end the offscreen render pass")?
+            .copy_image_to_buffer(target.color.clone(), target.pixels.clone())
+            .context("unable to copy the offscreen image")?;
+        let commands = builder
+            .build()
+            .context("unable to build the offscreen command buffer")?;
+
+        vulkano::sync::now(self.device.clone())
+            .then_execute(self.graphics_queue.clone(), commands)
+            .map_err(ParticleError::submission)
+            .context("unable to execute the offscreen command buffer")?
+            .then_signal_fence_and_flush()
+            .map_err(ParticleError::submission)
+            .context("unable to submit the offscreen command buffer")?
+            .wait(None)
+            .map_err(ParticleError::submission)
+            .context("unable to complete the offscreen frame")?;
+
+        let pixels = target
+            .pixels
+            .read()
+            .context("unable to read the offscreen pixels")?;
+        Ok(pixels.to_vec())
+    }
+
+    /// Render a frame into the offscreen target and return it as an RGBA
+    /// image. Like render_offscreen the scene starts out cleared, so there
+    /// are no trails, and hdr isn't supported.
+    pub fn render_offscreen_image(
+        &self,
+        target: &OffscreenTarget,
+        graphics_queue_subbuffers: Vec<AutoCommandBuffer>,
+    ) -> Result<image::RgbaImage, ParticleError> {
+        let pixels =
+            self.render_offscreen(target, graphics_queue_subbuffers)?;
+        self.rgba_image(pixels)
+    }
+
+    /// Copy the next frame rendered out of its swapchain image before it's
+    /// presented, so it can be read back with capture_frame.
+    pub fn capture_next_frame(&mut self) -> Result<(), ParticleError> {
+        let physical_device = self.device.physical_device();
+        if !swapchain::supports_capture(&self.surface, &physical_device)? {
+            return Err(anyhow!(
+                "the surface doesn't allow copying from swapchain images"
+            )
+            .into());
+        }
+        self.capture_requested = true;
+        self.captured_frame = None;
+        Ok(())
+    }
+
+    /// The last frame presented after capture_next_frame, as an RGBA image
+    /// for saving screenshots. It's copied from the swapchain image so it's
+    /// exactly what was shown, trails, tonemap and overlays included.
+    pub fn capture_frame(&self) -> Result<image::RgbaImage, ParticleError> {
+        let captured = self
+            .captured_frame
+            .as_ref()
+            .context("no frame has been captured since capture_next_frame")?;
+        let pixels = captured
+            .read()
+            .context("unable to read the captured pixels")?
+            .to_vec();
+        self.rgba_image(pixels)
+    }
+
+    /// Convert tightly packed pixels in the swapchain's format to RGBA.
+    fn rgba_image(
+        &self,
+        mut pixels: Vec<u8>,
+    ) -> Result<image::RgbaImage, ParticleError> {
+        match self.swapchain.format() {
+            Format::B8G8R8A8Srgb | Format::B8G8R8A8Unorm => {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+            Format::R8G8B8A8Srgb | Format::R8G8B8A8Unorm => {}
+            format => {
+                return Err(
+                    anyhow!("unable to capture frames in {:?}", format).into()
+                )
+            }
+        }
+        let [width, height] = self.swapchain.dimensions();
+        Ok(image::RgbaImage::from_raw(width, height, pixels)
+            .context("the captured pixels don't match the frame size")?)
+    }
+
     /// Build a command buffer which renders the full render pass.
     ///
     /// Render passes are constructed by executing multiple subuffers.
@@ -176,8 +744,10 @@ impl Display {
     /// any data.
     fn build_render_pass_command_buffer(
         &self,
-        graphics_queue_subbuffers: Vec<AutoCommandBuffer>,
+        mut graphics_queue_subbuffers: Vec<AutoCommandBuffer>,
+        mut overlay_subbuffers: Vec<AutoCommandBuffer>,
         framebuffer_index: usize,
+        capture: Option<&Arc<CpuAccessibleBuffer<[u8]>>>,
     ) -> Result<AutoCommandBuffer> {
         let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
             self.device.clone(),
@@ -185,17 +755,40 @@ impl Display {
         )
         .with_context(|| "unable to create the command buffer builder")?;
 
+        let clear_color = self.encoded_clear_color();
+        if !self.scene_initialized {
+            builder
+                .clear_color_image(
+                    self.scene_image.clone(),
+                    ClearValue::Float(clear_color),
+                )
+                .context("unable to clear the scene image")?;
+        }
         builder
             .begin_render_pass(
                 self.framebuffer_images[framebuffer_index].clone(),
-                vulkano::command_buffer::SubpassContents::SecondaryCommandBuffers,
-                vec![
-                    ClearValue::Float([0.0, 0.0, 0.0, 1.0]),
-                    ClearValue::Float([0.0, 0.0, 0.0, 1.0]),
-                ],
+                SubpassContents::SecondaryCommandBuffers,
+                self.clear_values(clear_color),
             )
             .with_context(|| "unable to begin the render pass")?;
 
+        // the scene image is loaded rather than cleared, so fading it is
+        // always drawn first
+        graphics_queue_subbuffers.insert(
+            0,
+            self.fade.draw(
+                self.graphics_queue.family(),
+                clear_color,
+                self.trail_decay,
+            )?,
+        );
+
+        if self.tonemap.is_none() {
+            // without hdr there's only one subpass, so overlays are simply
+            // drawn after the scene
+            graphics_queue_subbuffers.append(&mut overlay_subbuffers);
+        }
+
         unsafe {
             // unsafe because vulkano does not check synchronization between
             // subbuffers and the main.
@@ -206,12 +799,235 @@ impl Display {
                 })?;
         }
 
+        if let Some(tonemap) = &self.tonemap {
+            builder
+                .next_subpass(SubpassContents::SecondaryCommandBuffers)
+                .context("unable to begin the tonemap subpass")?;
+            let mut tonemap_subbuffers = vec![tonemap.draw(
+                self.graphics_queue.family(),
+                framebuffer_index,
+                self.exposure,
+            )?];
+            tonemap_subbuffers.append(&mut overlay_subbuffers);
+            unsafe {
+                // unsafe for the same reason as the scene's subbuffers
+                builder
+                    .execute_commands_from_vec(tonemap_subbuffers)
+                    .context("error while rendering the tonemap pass")?;
+            }
+        }
+
         builder
             .end_render_pass()
             .with_context(|| "unable to end the render pass")?;
+        if self.tonemap.is_none() && self.scene_layout == SceneLayout::Direct {
+            // there's no resolve to write the swapchain image
+            let [width, height] = self.swapchain.dimensions();
+            builder
+                .copy_image(
+                    self.scene_image.clone(),
+                    [0, 0, 0],
+                    0,
+                    0,
+                    self.swapchain_images[framebuffer_index].clone(),
+                    [0, 0, 0],
+                    0,
+                    0,
+                    [width, height, 1],
+                    1,
+                )
+                .context("unable to copy the scene to the swapchain image")?;
+        }
+        if let Some(capture) = capture {
+            builder
+                .copy_image_to_buffer(
+                    self.swapchain_images[framebuffer_index].clone(),
+                    capture.clone(),
+                )
+                .context("unable to copy the frame for capture")?;
+        }
 
         builder
             .build()
             .with_context(|| "unable to build the command buffer")
     }
+
+    /// The clear values for each attachment of the render pass. Only the
+    /// resolve target of a plain render pass is cleared, the scene is
+    /// faded instead and everything else is overwritten.
+    fn clear_values(&self, clear_color: [f32; 4]) -> Vec<ClearValue> {
+        match (self.tonemap.is_some(), self.scene_layout) {
+            (true, SceneLayout::Resolved(_)) => {
+                vec![ClearValue::None, ClearValue::None, ClearValue::None]
+            }
+            (true, SceneLayout::Direct) => {
+                vec![ClearValue::None, ClearValue::None]
+            }
+            (false, SceneLayout::Resolved(_)) => {
+                vec![ClearValue::None, ClearValue::Float(clear_color)]
+            }
+            (false, SceneLayout::Direct) => vec![ClearValue::None],
+        }
+    }
+}
+
+/// Everything which depends on the swapchain's images and format.
+struct RenderTargets {
+    render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    tonemap: Option<tonemap::Tonemap>,
+    fade: fade::Fade,
+    scene: Arc<AttachmentImage>,
+    scene_layout: SceneLayout,
+}
+
+/// Build the render pass and a framebuffer for each swapchain image.
+///
+/// With hdr enabled the scene is drawn into float images which are then
+/// tonemapped into the swapchain images by a second subpass.
+fn create_render_targets(
+    device: &Arc<Device>,
+    swapchain: &Arc<Swapchain<Window>>,
+    swapchain_images: &[Arc<SwapchainImage<Window>>],
+    options: &DisplayOptions,
+) -> Result<RenderTargets> {
+    let dimensions = swapchain.dimensions();
+    let scene_layout =
+        SceneLayout::pick(&device.physical_device(), options.max_samples);
+    if options.hdr {
+        let render_pass = tonemap::create_render_pass(
+            device,
+            swapchain.format(),
+            scene_layout,
+        )?;
+        let scene = swapchain::create_scene_image(
+            device,
+            &render_pass,
+            tonemap::HDR_FORMAT,
+            dimensions,
+        )?;
+        let (framebuffers, hdr_images) = tonemap::create_framebuffers(
+            device,
+            &scene,
+            swapchain_images,
+            &render_pass,
+            scene_layout,
+        )?;
+        let tonemap = tonemap::Tonemap::new(
+            device,
+            &render_pass,
+            dimensions,
+            &hdr_images,
+            needs_manual_gamma(swapchain.format()),
+            // the float format hdr asks for is paired with the extended
+            // srgb color space
+            swapchain::is_linear_float_format(swapchain.format()),
+            options.tonemap,
+        )?;
+        let fade = fade::Fade::new(device, &render_pass, dimensions)?;
+        Ok(RenderTargets {
+            render_pass,
+            framebuffers,
+            tonemap: Some(tonemap),
+            fade,
+            scene,
+            scene_layout,
+        })
+    } else {
+        let render_pass = swapchain::create_render_pass(
+            device,
+            swapchain.format(),
+            scene_layout,
+        )?;
+        let scene = swapchain::create_scene_image(
+            device,
+            &render_pass,
+            swapchain.format(),
+            dimensions,
+        )?;
+        let framebuffers = swapchain::create_framebuffers(
+            &scene,
+            swapchain_images,
+            &render_pass,
+            scene_layout,
+        )?;
+        let fade = fade::Fade::new(device, &render_pass, dimensions)?;
+        Ok(RenderTargets {
+            render_pass,
+            framebuffers,
+            tonemap: None,
+            fade,
+            scene,
+            scene_layout,
+        })
+    }
+}
+
+/// Classify an error from acquiring a swapchain image. An out of date
+/// swapchain only needs to be rebuilt, anything else is fatal.
+fn acquire_error_state(
+    error: AcquireError,
+) -> Result<SwapchainState, ParticleError> {
+    match error {
+        AcquireError::OutOfDate => Ok(SwapchainState::NeedsRebuild),
+        _ => Err(ParticleError::submission(error)),
+    }
+}
+
+/// Classify an error from presenting a frame, like acquire_error_state.
+fn flush_error_state(
+    error: FlushError,
+) -> Result<SwapchainState, ParticleError> {
+    match error {
+        FlushError::OutOfDate => Ok(SwapchainState::NeedsRebuild),
+        _ => Err(ParticleError::submission(error)),
+    }
+}
+
+/// True when writing to the format stores values as-is, so they need to be
+/// gamma encoded by hand.
+fn needs_manual_gamma(format: Format) -> bool {
+    !swapchain::is_srgb_format(format)
+        && !swapchain::is_linear_float_format(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_out_of_date_swapchain_needs_rebuilding() {
+        assert_eq!(
+            acquire_error_state(AcquireError::OutOfDate).unwrap(),
+            SwapchainState::NeedsRebuild
+        );
+        assert_eq!(
+            flush_error_state(FlushError::OutOfDate).unwrap(),
+            SwapchainState::NeedsRebuild
+        );
+    }
+
+    #[test]
+    fn other_presentation_errors_are_fatal() {
+        for error in vec![
+            AcquireError::SurfaceLost,
+            AcquireError::DeviceLost,
+            AcquireError::Timeout,
+        ] {
+            assert!(matches!(
+                acquire_error_state(error),
+                Err(ParticleError::Submission(_))
+            ));
+        }
+        for error in vec![
+            FlushError::SurfaceLost,
+            FlushError::DeviceLost,
+            FlushError::Timeout,
+        ] {
+            assert!(matches!(
+                flush_error_state(error),
+                Err(ParticleError::Submission(_))
+            ));
+        }
+    }
 }
@@ -0,0 +1,107 @@
+use super::swapchain::{create_scene_image, SceneLayout};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    device::Device,
+    format::Format,
+    framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract},
+    image::{AttachmentImage, ImageUsage},
+};
+
+type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
+type DynFramebuffer = dyn FramebufferAbstract + Send + Sync;
+
+/// An image which frames can be rendered into instead of the swapchain, and
+/// a host visible buffer to read the result back through.
+///
+/// The framebuffer is built against the display's plain render pass, so any
+/// command buffers built for subpass 0 draw into it unchanged. The scene
+/// image is cleared for every frame, so offscreen frames have no trails.
+/// Use Display::capture_frame to read back what was actually presented.
+pub struct OffscreenTarget {
+    pub framebuffer: Arc<DynFramebuffer>,
+    pub scene: Arc<AttachmentImage>,
+
+    /// The image the frame is read back from, the scene itself when it
+    /// isn't multisampled.
+    pub color: Arc<AttachmentImage>,
+    pub pixels: Arc<CpuAccessibleBuffer<[u8]>>,
+}
+
+impl OffscreenTarget {
+    pub fn new(
+        device: &Arc<Device>,
+        render_pass: &Arc<DynRenderPass>,
+        format: Format,
+        dimensions: [u32; 2],
+        layout: SceneLayout,
+    ) -> Result<Self> {
+        let scene = create_scene_image(device, render_pass, format, dimensions)
+            .context("unable to create the offscreen scene image")?;
+        let framebuffer = Framebuffer::start(render_pass.clone())
+            .add(scene.clone())
+            .context("unable to attach the offscreen scene image")?;
+        let (framebuffer, color): (Arc<DynFramebuffer>, _) = match layout {
+            SceneLayout::Resolved(_) => {
+                let color = AttachmentImage::with_usage(
+                    device.clone(),
+                    dimensions,
+                    format,
+                    ImageUsage {
+                        color_attachment: true,
+                        transfer_source: true,
+                        ..ImageUsage::none()
+                    },
+                )
+                .context("unable to create the offscreen color image")?;
+                let framebuffer = framebuffer
+                    .add(color.clone())
+                    .context("unable to attach the offscreen color image")?
+                    .build()
+                    .context("unable to build the offscreen framebuffer")?;
+                (Arc::new(framebuffer), color)
+            }
+            // a single sampled scene is read back directly
+            SceneLayout::Direct => {
+                let framebuffer = framebuffer
+                    .build()
+                    .context("unable to build the offscreen framebuffer")?;
+                (Arc::new(framebuffer), scene.clone())
+            }
+        };
+
+        let pixels = create_readback_buffer(device, format, dimensions)
+            .context("unable to create the offscreen readback buffer")?;
+
+        Ok(Self {
+            framebuffer,
+            scene,
+            color,
+            pixels,
+        })
+    }
+}
+
+/// Create a host visible buffer which an image of the given format and size
+/// can be copied into, tightly packed.
+pub fn create_readback_buffer(
+    device: &Arc<Device>,
+    format: Format,
+    dimensions: [u32; 2],
+) -> Result<Arc<CpuAccessibleBuffer<[u8]>>> {
+    let bytes_per_pixel = format
+        .size()
+        .context("the image format has no fixed pixel size")?;
+    let len = dimensions[0] as usize * dimensions[1] as usize * bytes_per_pixel;
+    CpuAccessibleBuffer::from_iter(
+        device.clone(),
+        BufferUsage {
+            transfer_destination: true,
+            ..BufferUsage::none()
+        },
+        false,
+        (0..len).map(|_| 0u8),
+    )
+    .context("unable to create the readback buffer")
+}
@@ -1,6 +1,8 @@
+use super::{DisplayOptions, PreferredPresentMode};
+use crate::{error::ParticleError, math::clamp};
 use anyhow::{Context, Result};
 use log;
-use std::cmp::{max, min};
+use std::cmp::min;
 use std::sync::Arc;
 use vulkano::device::{Device, Queue};
 use vulkano::format::Format;
@@ -13,53 +15,128 @@ use vulkano::instance::PhysicalDevice;
 use vulkano::single_pass_renderpass;
 use vulkano::swapchain::{
     Capabilities, ColorSpace, CompositeAlpha, FullscreenExclusive, PresentMode,
-    Surface, Swapchain,
+    Surface, Swapchain, SwapchainCreationError,
 };
 use vulkano::sync::SharingMode;
 use winit::window::Window;
 
 type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
+type DynFramebuffer = dyn FramebufferAbstract + Send + Sync;
+type SwapchainAndImages =
+    (Arc<Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>);
 
-/// Build a render pass which uses the maximum multisampling level supported
-/// by this device.
+/// How the scene image, which is kept between frames so it can be faded,
+/// reaches the swapchain image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SceneLayout {
+    /// The scene has this many samples and is resolved into an attachment
+    /// of its own at the end of the first subpass.
+    Resolved(u32),
+
+    /// The scene has a single sample, which vulkan can't resolve from, so
+    /// it's the first subpass's only attachment and is drawn into directly.
+    Direct,
+}
+
+impl SceneLayout {
+    /// Use the largest sample count supported by this device which doesn't
+    /// exceed max_samples.
+    pub fn pick(
+        physical_device: &PhysicalDevice,
+        max_samples: Option<u32>,
+    ) -> Self {
+        Self::for_samples(pick_sample_count(physical_device, max_samples))
+    }
+
+    fn for_samples(samples: u32) -> Self {
+        if samples > 1 {
+            SceneLayout::Resolved(samples)
+        } else {
+            SceneLayout::Direct
+        }
+    }
+
+    /// The number of samples per pixel in the scene image.
+    pub fn samples(&self) -> u32 {
+        match self {
+            SceneLayout::Resolved(samples) => *samples,
+            SceneLayout::Direct => 1,
+        }
+    }
+}
+
+/// Build a render pass which draws the scene with the layout's sample count.
+///
+/// A multisampled scene is resolved into the swapchain image. A single
+/// sampled scene is copied there after the render pass instead.
 pub fn create_render_pass(
     device: &Arc<Device>,
     color_format: Format,
-) -> Result<Arc<DynRenderPass>> {
-    let samples = pick_sample_count(&device.physical_device());
-    log::debug!("framebuffer samples {}", samples);
-
-    let render_pass = single_pass_renderpass!(
-        device.clone(),
-        attachments: {
-            intermediary: {
-                load: Clear,
-                store: DontCare,
-                format: color_format,
-                samples: samples,
-            },
-
-            color: {
-                load: Clear,
-                store: Store,
-                format: color_format,
-                samples: 1,
-            }
-        },
-        pass: {
-            color: [intermediary],
-            depth_stencil: {}
-            resolve: [color]
-        }
-    )
-    .context("unable to create renderpass")?;
+    layout: SceneLayout,
+) -> Result<Arc<DynRenderPass>, ParticleError> {
+    log::debug!("framebuffer samples {}", layout.samples());
+    let render_pass: Arc<DynRenderPass> = match layout {
+        SceneLayout::Resolved(samples) => Arc::new(
+            single_pass_renderpass!(
+                device.clone(),
+                attachments: {
+                    intermediary: {
+                        load: Load,
+                        store: Store,
+                        format: color_format,
+                        samples: samples,
+                    },
 
-    Ok(Arc::new(render_pass))
+                    color: {
+                        load: Clear,
+                        store: Store,
+                        format: color_format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [intermediary],
+                    depth_stencil: {}
+                    resolve: [color]
+                }
+            )
+            .context("unable to create renderpass")?,
+        ),
+        SceneLayout::Direct => Arc::new(
+            single_pass_renderpass!(
+                device.clone(),
+                attachments: {
+                    scene: {
+                        load: Load,
+                        store: Store,
+                        format: color_format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [scene],
+                    depth_stencil: {}
+                }
+            )
+            .context("unable to create renderpass")?,
+        ),
+    };
+    Ok(render_pass)
 }
 
-/// Pick the largest supported sampling count for this device
-fn pick_sample_count(physical_device: &PhysicalDevice) -> u32 {
+/// Pick the largest supported sampling count for this device which doesn't
+/// exceed max_samples.
+fn pick_sample_count(
+    physical_device: &PhysicalDevice,
+    max_samples: Option<u32>,
+) -> u32 {
     let counts = physical_device.limits().framebuffer_color_sample_counts();
+    largest_sample_count(counts, max_samples.unwrap_or(u32::MAX))
+}
+
+/// The largest sample count in the vulkan sample count bitmask which is no
+/// more than the ceiling. Every device supports a single sample.
+fn largest_sample_count(counts: u32, ceiling: u32) -> u32 {
     [
         (vk_sys::SAMPLE_COUNT_64_BIT, 64),
         (vk_sys::SAMPLE_COUNT_32_BIT, 32),
@@ -69,135 +146,337 @@ fn pick_sample_count(physical_device: &PhysicalDevice) -> u32 {
         (vk_sys::SAMPLE_COUNT_2_BIT, 2),
     ]
     .iter()
-    .find(|(mask, _)| counts & *mask > 0)
+    .find(|(mask, samples)| counts & *mask > 0 && *samples <= ceiling)
     .map(|(_, samples)| *samples)
     .unwrap_or(1)
 }
 
-pub fn create_framebuffers(
+/// Create the image which the scene is drawn into. It's kept between
+/// frames, and shared by every framebuffer, so what was drawn last frame can
+/// be faded instead of cleared.
+///
+/// A single sampled scene is also copied from, or read by the tonemap pass,
+/// since it isn't resolved.
+pub fn create_scene_image(
     device: &Arc<Device>,
-    color_format: Format,
+    render_pass: &Arc<DynRenderPass>,
+    format: Format,
+    dimensions: [u32; 2],
+) -> Result<Arc<AttachmentImage>, ParticleError> {
+    let samples = render_pass
+        .num_samples(0)
+        .context("the render pass has no intermediary attachment")?;
+    Ok(AttachmentImage::multisampled_with_usage(
+        device.clone(),
+        dimensions,
+        samples,
+        format,
+        ImageUsage {
+            color_attachment: true,
+            input_attachment: samples == 1,
+            transfer_source: samples == 1,
+            transfer_destination: true,
+            ..ImageUsage::none()
+        },
+    )
+    .context("unable to create the scene image")?)
+}
+
+/// Create a framebuffer for each swapchain image, all drawing into the same
+/// scene image. A multisampled scene is resolved into the swapchain image,
+/// a single sampled scene is the framebuffer's only attachment.
+pub fn create_framebuffers(
+    scene: &Arc<AttachmentImage>,
     swapchain_images: &[Arc<SwapchainImage<Window>>],
     render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>,
-) -> Vec<Arc<dyn FramebufferAbstract + Send + Sync>> {
-    swapchain_images
-        .iter()
-        .map(|image| {
-            let intermediary = AttachmentImage::transient_multisampled(
-                device.clone(),
-                image.dimensions(),
-                render_pass.num_samples(0).unwrap(),
-                color_format,
-            )
-            .unwrap();
-            let fba: Arc<dyn FramebufferAbstract + Send + Sync> = Arc::new(
-                Framebuffer::start(render_pass.clone())
-                    .add(intermediary.clone())
-                    .unwrap()
+    layout: SceneLayout,
+) -> Result<Vec<Arc<DynFramebuffer>>, ParticleError> {
+    let mut framebuffers = vec![];
+    for image in swapchain_images {
+        let framebuffer = Framebuffer::start(render_pass.clone())
+            .add(scene.clone())
+            .context("unable to attach the scene image")?;
+        let framebuffer: Arc<DynFramebuffer> = match layout {
+            SceneLayout::Resolved(_) => Arc::new(
+                framebuffer
                     .add(image.clone())
-                    .unwrap()
+                    .context("unable to attach the swapchain image")?
+                    .build()
+                    .context("unable to build the framebuffer")?,
+            ),
+            SceneLayout::Direct => Arc::new(
+                framebuffer
                     .build()
-                    .unwrap(),
-            );
-            fba
-        })
-        .collect::<Vec<_>>()
+                    .context("unable to build the framebuffer")?,
+            ),
+        };
+        framebuffers.push(framebuffer);
+    }
+    Ok(framebuffers)
 }
 
 /// Construct a swapchain and it's owned images
+///
+/// When an old swapchain is provided, the new swapchain replaces it and can
+/// reuse its resources.
 pub fn create_swap_chain(
     surface: &Arc<Surface<Window>>,
     physical_device: &PhysicalDevice,
     logical_device: &Arc<Device>,
     graphics_queue: &Arc<Queue>,
     present_queue: &Arc<Queue>,
-) -> Result<(Arc<Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>)> {
-    let capabilities = surface.capabilities(*physical_device)?;
-    let swap_format = choose_swap_surface_format(&capabilities);
-    let swap_present_mode = choose_swap_present_mode(&capabilities);
+    options: &DisplayOptions,
+    old_swapchain: Option<&Arc<Swapchain<Window>>>,
+) -> Result<SwapchainAndImages, ParticleError> {
+    let capabilities = surface
+        .capabilities(*physical_device)
+        .context("unable to query the surface capabilities")?;
+    let swap_format = choose_swap_surface_format(&capabilities, options)?;
+    let swap_present_mode =
+        choose_swap_present_mode(&capabilities, options.present_mode);
     let swap_extent = choose_swap_extent(surface, &capabilities);
-    let swap_image_count = choose_image_count(&capabilities);
+    let swap_image_count =
+        choose_image_count(&capabilities, options.image_count);
     let sharing_mode = choose_sharing_mode(graphics_queue, present_queue);
 
+    // a single sampled scene is copied into the swapchain image, and
+    // screenshots are copied out of it
+    let supported = capabilities.supported_usage_flags;
     let image_usage = ImageUsage {
         color_attachment: true,
+        transfer_source: supported.transfer_source,
+        transfer_destination: supported.transfer_destination,
         ..ImageUsage::none()
     };
 
-    let (swapchain, images) = Swapchain::new(
-        logical_device.clone(),
-        surface.clone(),
-        swap_image_count,
-        swap_format.0,
-        swap_extent,
-        1,
-        image_usage,
-        sharing_mode,
-        capabilities.current_transform,
-        CompositeAlpha::Opaque,
-        swap_present_mode,
-        FullscreenExclusive::AppControlled,
-        false,
-        swap_format.1,
-    )
-    .context("unable to build swapchain")?;
+    let (swapchain, images) = match old_swapchain {
+        None => Swapchain::new(
+            logical_device.clone(),
+            surface.clone(),
+            swap_image_count,
+            swap_format.0,
+            swap_extent,
+            1,
+            image_usage,
+            sharing_mode,
+            capabilities.current_transform,
+            CompositeAlpha::Opaque,
+            swap_present_mode,
+            FullscreenExclusive::AppControlled,
+            false,
+            swap_format.1,
+        ),
+        Some(old_swapchain) => Swapchain::with_old_swapchain(
+            logical_device.clone(),
+            surface.clone(),
+            swap_image_count,
+            swap_format.0,
+            swap_extent,
+            1,
+            image_usage,
+            sharing_mode,
+            capabilities.current_transform,
+            CompositeAlpha::Opaque,
+            swap_present_mode,
+            FullscreenExclusive::AppControlled,
+            false,
+            swap_format.1,
+            old_swapchain.clone(),
+        ),
+    }
+    .map_err(creation_error)?;
+    log::info!("swapchain created with {} images", images.len());
 
     Ok((swapchain, images))
 }
 
+/// Recreate the swapchain at new dimensions, keeping its other settings.
+pub fn recreate_swap_chain(
+    swapchain: &Arc<Swapchain<Window>>,
+    dimensions: [u32; 2],
+) -> Result<SwapchainAndImages, ParticleError> {
+    swapchain
+        .recreate_with_dimensions(dimensions)
+        .map_err(creation_error)
+}
+
+/// The surface's extent has moved on when the requested dimensions aren't
+/// supported, which callers can recover from by trying again later.
+fn creation_error(error: SwapchainCreationError) -> ParticleError {
+    match error {
+        SwapchainCreationError::UnsupportedDimensions => {
+            ParticleError::OutOfDate
+        }
+        error => ParticleError::swapchain_creation(error),
+    }
+}
+
+/// Returns true when the surface can still present using the swapchain's
+/// format and present mode. This can change when the window moves to a
+/// different monitor.
+pub fn is_swapchain_compatible(
+    surface: &Arc<Surface<Window>>,
+    physical_device: &PhysicalDevice,
+    swapchain: &Swapchain<Window>,
+) -> Result<bool, ParticleError> {
+    let capabilities = surface
+        .capabilities(*physical_device)
+        .context("unable to query the surface capabilities")?;
+    let format_supported = capabilities
+        .supported_formats
+        .iter()
+        .any(|(format, _)| *format == swapchain.format());
+    let present_mode_supported = capabilities
+        .present_modes
+        .supports(swapchain.present_mode());
+    Ok(format_supported && present_mode_supported)
+}
+
+/// Returns true when swapchain images are created with transfer_source
+/// usage, so presented frames can be copied back for screenshots.
+pub fn supports_capture(
+    surface: &Arc<Surface<Window>>,
+    physical_device: &PhysicalDevice,
+) -> Result<bool, ParticleError> {
+    let capabilities = surface
+        .capabilities(*physical_device)
+        .context("unable to query the surface capabilities")?;
+    Ok(capabilities.supported_usage_flags.transfer_source)
+}
+
 fn choose_sharing_mode(
     graphics_queue: &Arc<Queue>,
     present_queue: &Arc<Queue>,
 ) -> SharingMode {
-    let same_queue =
-        graphics_queue.id_within_family() == present_queue.id_within_family();
-    if same_queue {
+    // images only need to be shared when they're drawn and presented by
+    // different queue families
+    let graphics_family = graphics_queue.family().id();
+    let present_family = present_queue.family().id();
+    if graphics_family == present_family {
         SharingMode::Exclusive
     } else {
-        SharingMode::Concurrent(vec![
-            graphics_queue.id_within_family(),
-            present_queue.id_within_family(),
-        ])
+        SharingMode::Concurrent(vec![graphics_family, present_family])
     }
 }
 
-fn choose_image_count(capabilities: &Capabilities) -> u32 {
-    let suggested_count = capabilities.min_image_count + 1;
-    if let Some(max_count) = capabilities.max_image_count {
+/// Select the number of swapchain images, honoring the requested count when
+/// the surface supports it.
+fn choose_image_count(
+    capabilities: &Capabilities,
+    requested: Option<u32>,
+) -> u32 {
+    let suggested_count = requested
+        .unwrap_or(capabilities.min_image_count + 1)
+        .max(capabilities.min_image_count);
+    let count = if let Some(max_count) = capabilities.max_image_count {
         min(suggested_count, max_count)
     } else {
         suggested_count
-    }
+    };
+    log::info!("chosen image count: {} (requested {:?})", count, requested);
+    count
 }
 
 /// Select a format and color space from the available formats
+///
+/// When the hdr option is set, an extended range float format is used if the
+/// surface offers one. Otherwise B8G8R8A8Srgb is preferred, falling back to
+/// the first supported format unless the options require sRGB.
 fn choose_swap_surface_format(
     capabilities: &Capabilities,
-) -> (Format, ColorSpace) {
+    options: &DisplayOptions,
+) -> Result<(Format, ColorSpace)> {
     log::info!("display formats {:?}", capabilities.supported_formats);
 
-    let (format, color_space) = *capabilities
+    let srgb_format =
+        capabilities
+            .supported_formats
+            .iter()
+            .find(|(format, color_space)| {
+                *format == Format::B8G8R8A8Srgb
+                    && *color_space == ColorSpace::SrgbNonLinear
+            });
+    if options.require_srgb && srgb_format.is_none() {
+        return Err(ParticleError::SurfaceFormat(format!(
+            "B8G8R8A8Srgb with SrgbNonLinear is required but the surface \
+             only offers {:?}",
+            capabilities.supported_formats
+        ))
+        .into());
+    }
+
+    let hdr_format = capabilities
         .supported_formats
         .iter()
+        .filter(|_| options.hdr)
         .find(|(format, color_space)| {
-            *format == Format::B8G8R8A8Srgb
-                && *color_space == ColorSpace::SrgbNonLinear
-        })
+            *format == Format::R16G16B16A16Sfloat
+                && *color_space == ColorSpace::ExtendedSrgbLinear
+        });
+    let (format, color_space) = *hdr_format
+        .or(srgb_format)
         .unwrap_or_else(|| &capabilities.supported_formats[0]);
 
     log::info!("chosen format: {:?}", (format, color_space));
+    if !is_srgb_format(format) && !is_linear_float_format(format) {
+        log::warn!(
+            "{:?} does not encode sRGB on write, gamma will be applied in the \
+             fragment shader",
+            format
+        );
+    }
 
-    (format, color_space)
+    Ok((format, color_space))
+}
+
+/// Returns true for float formats which store linear color, as used by hdr
+/// displays.
+pub fn is_linear_float_format(format: Format) -> bool {
+    match format {
+        Format::R16G16B16A16Sfloat | Format::R32G32B32A32Sfloat => true,
+        _ => false,
+    }
+}
+
+/// Returns true when the hardware converts linear color into sRGB when
+/// writing to images with this format.
+pub fn is_srgb_format(format: Format) -> bool {
+    match format {
+        Format::R8Srgb
+        | Format::R8G8Srgb
+        | Format::R8G8B8Srgb
+        | Format::B8G8R8Srgb
+        | Format::R8G8B8A8Srgb
+        | Format::B8G8R8A8Srgb
+        | Format::A8B8G8R8SrgbPack32 => true,
+        _ => false,
+    }
 }
 
 /// Select the presentation mode
-fn choose_swap_present_mode(capabilities: &Capabilities) -> PresentMode {
-    let mode = if capabilities.present_modes.mailbox {
-        PresentMode::Mailbox
-    } else {
-        PresentMode::Fifo
+///
+/// Fifo is used when the preferred mode is unsupported because every surface
+/// is required to support it.
+fn choose_swap_present_mode(
+    capabilities: &Capabilities,
+    preferred: PreferredPresentMode,
+) -> PresentMode {
+    let modes = &capabilities.present_modes;
+    let mode = match preferred {
+        PreferredPresentMode::Mailbox if modes.mailbox => PresentMode::Mailbox,
+        PreferredPresentMode::FifoRelaxed if modes.relaxed => {
+            PresentMode::Relaxed
+        }
+        PreferredPresentMode::Immediate if modes.immediate => {
+            PresentMode::Immediate
+        }
+        _ => PresentMode::Fifo,
     };
-    log::info!("selected presentation mode: {:?}", mode);
+    log::info!(
+        "selected presentation mode {:?}, requested {:?}",
+        mode,
+        preferred
+    );
     mode
 }
 
@@ -227,6 +506,56 @@ fn choose_swap_extent(
     }
 }
 
-fn clamp(x: u32, lower: u32, upper: u32) -> u32 {
-    max(lower, min(x, upper))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UP_TO_EIGHT: u32 = vk_sys::SAMPLE_COUNT_1_BIT
+        | vk_sys::SAMPLE_COUNT_2_BIT
+        | vk_sys::SAMPLE_COUNT_4_BIT
+        | vk_sys::SAMPLE_COUNT_8_BIT;
+
+    #[test]
+    fn largest_sample_count_stays_under_the_ceiling() {
+        assert_eq!(largest_sample_count(UP_TO_EIGHT, 1), 1);
+        assert_eq!(largest_sample_count(UP_TO_EIGHT, 4), 4);
+        assert_eq!(largest_sample_count(UP_TO_EIGHT, 64), 8);
+        assert_eq!(largest_sample_count(UP_TO_EIGHT, u32::MAX), 8);
+    }
+
+    #[test]
+    fn largest_sample_count_skips_unsupported_counts() {
+        let gaps = vk_sys::SAMPLE_COUNT_1_BIT
+            | vk_sys::SAMPLE_COUNT_2_BIT
+            | vk_sys::SAMPLE_COUNT_16_BIT;
+        assert_eq!(largest_sample_count(gaps, 8), 2);
+        assert_eq!(largest_sample_count(gaps, 16), 16);
+        assert_eq!(largest_sample_count(vk_sys::SAMPLE_COUNT_1_BIT, 8), 1);
+    }
+
+    #[test]
+    fn a_single_sample_scene_is_drawn_into_directly() {
+        // --max-samples 1, and devices which only offer a single sample
+        let no_msaa = largest_sample_count(UP_TO_EIGHT, 1);
+        assert_eq!(SceneLayout::for_samples(no_msaa), SceneLayout::Direct);
+        let only_one = largest_sample_count(vk_sys::SAMPLE_COUNT_1_BIT, 8);
+        assert_eq!(SceneLayout::for_samples(only_one), SceneLayout::Direct);
+        assert_eq!(SceneLayout::Direct.samples(), 1);
+
+        let msaa = largest_sample_count(UP_TO_EIGHT, 4);
+        assert_eq!(SceneLayout::for_samples(msaa), SceneLayout::Resolved(4));
+        assert_eq!(SceneLayout::Resolved(4).samples(), 4);
+    }
+
+    #[test]
+    fn unsupported_dimensions_mean_the_swapchain_is_out_of_date() {
+        assert!(matches!(
+            creation_error(SwapchainCreationError::UnsupportedDimensions),
+            ParticleError::OutOfDate
+        ));
+        assert!(matches!(
+            creation_error(SwapchainCreationError::SurfaceLost),
+            ParticleError::SwapchainCreation(_)
+        ));
+    }
 }
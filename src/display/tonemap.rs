@@ -0,0 +1,373 @@
+use super::{swapchain::SceneLayout, TonemapOperator};
+use crate::error::ParticleError;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use vulkano::{
+    command_buffer::{
+        AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState,
+    },
+    descriptor::{
+        descriptor_set::PersistentDescriptorSet, DescriptorSet,
+        PipelineLayoutAbstract,
+    },
+    device::Device,
+    format::Format,
+    framebuffer::{
+        Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass,
+    },
+    image::{swapchain::SwapchainImage, AttachmentImage, ImageUsage},
+    instance::QueueFamily,
+    ordered_passes_renderpass,
+    pipeline::{
+        vertex::{BufferlessDefinition, BufferlessVertices},
+        viewport::Viewport,
+        GraphicsPipeline, GraphicsPipelineAbstract,
+    },
+};
+use winit::window::Window;
+
+type DynRenderPass = dyn RenderPassAbstract + Send + Sync;
+type PushConstants = fragment_shader::ty::PushConstants;
+
+/// The format of the offscreen image which the scene is drawn into before
+/// it's tonemapped.
+pub const HDR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+/// Build a render pass which draws the scene into a float image in the
+/// first subpass, then tonemaps it into the swapchain image in the second
+/// subpass. A multisampled scene is resolved for the second subpass to
+/// read, a single sampled scene is read directly.
+///
+/// The first subpass is laid out exactly like the plain render pass, so
+/// pipelines built against subpass 0 work with either.
+pub fn create_render_pass(
+    device: &Arc<Device>,
+    color_format: Format,
+    layout: SceneLayout,
+) -> Result<Arc<DynRenderPass>> {
+    log::debug!("hdr framebuffer samples {}", layout.samples());
+    let render_pass: Arc<DynRenderPass> = match layout {
+        SceneLayout::Resolved(samples) => Arc::new(
+            ordered_passes_renderpass!(
+                device.clone(),
+                attachments: {
+                    intermediary: {
+                        load: Load,
+                        store: Store,
+                        format: HDR_FORMAT,
+                        samples: samples,
+                    },
+
+                    hdr: {
+                        load: DontCare,
+                        store: DontCare,
+                        format: HDR_FORMAT,
+                        samples: 1,
+                    },
+
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: color_format,
+                        samples: 1,
+                    }
+                },
+                passes: [
+                    {
+                        color: [intermediary],
+                        depth_stencil: {},
+                        input: [],
+                        resolve: [hdr]
+                    },
+                    {
+                        color: [color],
+                        depth_stencil: {},
+                        input: [hdr]
+                    }
+                ]
+            )
+            .context("unable to create the hdr renderpass")?,
+        ),
+        SceneLayout::Direct => Arc::new(
+            ordered_passes_renderpass!(
+                device.clone(),
+                attachments: {
+                    scene: {
+                        load: Load,
+                        store: Store,
+                        format: HDR_FORMAT,
+                        samples: 1,
+                    },
+
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: color_format,
+                        samples: 1,
+                    }
+                },
+                passes: [
+                    {
+                        color: [scene],
+                        depth_stencil: {},
+                        input: []
+                    },
+                    {
+                        color: [color],
+                        depth_stencil: {},
+                        input: [scene]
+                    }
+                ]
+            )
+            .context("unable to create the hdr renderpass")?,
+        ),
+    };
+    Ok(render_pass)
+}
+
+/// Create a framebuffer for each swapchain image, along with the hdr image
+/// which the tonemap pass reads from. Every framebuffer draws into the same
+/// scene image, which is the hdr image itself when it isn't multisampled.
+pub fn create_framebuffers(
+    device: &Arc<Device>,
+    scene: &Arc<AttachmentImage>,
+    swapchain_images: &[Arc<SwapchainImage<Window>>],
+    render_pass: &Arc<DynRenderPass>,
+    layout: SceneLayout,
+) -> Result<(
+    Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    Vec<Arc<AttachmentImage>>,
+)> {
+    let hdr_usage = ImageUsage {
+        color_attachment: true,
+        input_attachment: true,
+        transient_attachment: true,
+        ..ImageUsage::none()
+    };
+
+    let mut framebuffers = vec![];
+    let mut hdr_images = vec![];
+    for image in swapchain_images {
+        if layout == SceneLayout::Direct {
+            let framebuffer = Framebuffer::start(render_pass.clone())
+                .add(scene.clone())
+                .context("unable to attach the hdr scene image")?
+                .add(image.clone())
+                .context("unable to attach the swapchain image")?
+                .build()
+                .context("unable to build the hdr framebuffer")?;
+            framebuffers.push(Arc::new(framebuffer)
+                as Arc<dyn FramebufferAbstract + Send + Sync>);
+            hdr_images.push(scene.clone());
+            continue;
+        }
+        let hdr = AttachmentImage::with_usage(
+            device.clone(),
+            image.dimensions(),
+            HDR_FORMAT,
+            hdr_usage,
+        )
+        .context("unable to create the resolved hdr image")?;
+        let framebuffer = Framebuffer::start(render_pass.clone())
+            .add(scene.clone())
+            .context("unable to attach the multisampled hdr image")?
+            .add(hdr.clone())
+            .context("unable to attach the resolved hdr image")?
+            .add(image.clone())
+            .context("unable to attach the swapchain image")?
+            .build()
+            .context("unable to build the hdr framebuffer")?;
+        framebuffers
+            .push(Arc::new(framebuffer)
+                as Arc<dyn FramebufferAbstract + Send + Sync>);
+        hdr_images.push(hdr);
+    }
+    Ok((framebuffers, hdr_images))
+}
+
+/// The full screen pass which maps the hdr image into the swapchain's
+/// displayable range.
+pub struct Tonemap {
+    device: Arc<Device>,
+    subpass: Subpass<Arc<DynRenderPass>>,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    descriptor_sets: Vec<Arc<dyn DescriptorSet + Send + Sync>>,
+    manual_gamma: bool,
+    extended_range: bool,
+    operator: TonemapOperator,
+}
+
+impl Tonemap {
+    /// Build the tonemap pipeline for subpass 1 of an hdr render pass.
+    ///
+    /// `manual_gamma` should be set when the swapchain format does not
+    /// perform sRGB encoding on write, and `extended_range` when the
+    /// swapchain can show values brighter than white so they're not clamped.
+    pub fn new(
+        device: &Arc<Device>,
+        render_pass: &Arc<DynRenderPass>,
+        dimensions: [u32; 2],
+        hdr_images: &[Arc<AttachmentImage>],
+        manual_gamma: bool,
+        extended_range: bool,
+        operator: TonemapOperator,
+    ) -> Result<Self> {
+        let vert = vertex_shader::Shader::load(device.clone())
+            .map_err(ParticleError::shader_load("tonemap vertex"))?;
+        let frag = fragment_shader::Shader::load(device.clone())
+            .map_err(ParticleError::shader_load("tonemap fragment"))?;
+
+        let subpass = Subpass::from(render_pass.clone(), 1)
+            .context("could not create the tonemap pipeline subpass")?;
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+            depth_range: 0.0..1.0,
+        };
+        let pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync> =
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input(BufferlessDefinition {})
+                    .vertex_shader(vert.main_entry_point(), ())
+                    .fragment_shader(frag.main_entry_point(), ())
+                    .viewports(vec![viewport])
+                    .depth_clamp(false)
+                    .depth_write(false)
+                    .triangle_list()
+                    .render_pass(subpass.clone())
+                    .build(device.clone())
+                    .context("could not create the tonemap pipeline")?,
+            );
+
+        let layout = pipeline
+            .descriptor_set_layout(0)
+            .context("unable to get the tonemap descriptor set layout")?;
+        let mut descriptor_sets = vec![];
+        for hdr in hdr_images {
+            let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+                PersistentDescriptorSet::start(layout.clone())
+                    .add_image(hdr.clone())
+                    .context("unable to bind the hdr image")?
+                    .build()
+                    .context("unable to build the tonemap descriptor set")?,
+            );
+            descriptor_sets.push(set);
+        }
+
+        Ok(Self {
+            device: device.clone(),
+            subpass,
+            pipeline,
+            descriptor_sets,
+            manual_gamma,
+            extended_range,
+            operator,
+        })
+    }
+
+    /// Build a secondary command buffer which runs the tonemap pass for the
+    /// framebuffer at `framebuffer_index`. It's executed in the render
+    /// pass's second subpass, before any overlays.
+    pub fn draw(
+        &self,
+        queue_family: QueueFamily,
+        framebuffer_index: usize,
+        exposure: f32,
+    ) -> Result<AutoCommandBuffer> {
+        let mut builder =
+            AutoCommandBufferBuilder::secondary_graphics_one_time_submit(
+                self.device.clone(),
+                queue_family,
+                self.subpass.clone(),
+            )
+            .context("unable to create the tonemap command buffer builder")?;
+        let constants = PushConstants {
+            exposure,
+            manual_gamma: if self.manual_gamma { 1 } else { 0 },
+            operator: self.operator.shader_id(),
+            extended_range: if self.extended_range { 1 } else { 0 },
+        };
+        builder
+            .draw(
+                self.pipeline.clone(),
+                &DynamicState::none(),
+                BufferlessVertices {
+                    vertices: 3,
+                    instances: 1,
+                },
+                vec![self.descriptor_sets[framebuffer_index].clone()],
+                constants,
+            )
+            .context("unable to draw the tonemap pass")?;
+        builder
+            .build()
+            .context("unable to build the tonemap command buffer")
+    }
+}
+
+mod vertex_shader {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            void main() {
+                // a single triangle which covers the whole screen
+                vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+                gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+            }
+            "#
+    }
+}
+
+mod fragment_shader {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r#"
+            #version 450
+            #extension GL_ARB_separate_shader_objects : enable
+
+            layout(input_attachment_index = 0, set = 0, binding = 0)
+                uniform subpassInput hdr;
+
+            layout(push_constant) uniform PushConstants {
+                float exposure;
+                int manual_gamma;
+                // 0 for aces, 1 for reinhard
+                int operator;
+                int extended_range;
+            } pc;
+
+            layout(location = 0) out vec4 outColor;
+
+            // Narkowicz's fit of the ACES filmic curve.
+            vec3 aces(vec3 x) {
+                const float a = 2.51;
+                const float b = 0.03;
+                const float c = 2.43;
+                const float d = 0.59;
+                const float e = 0.14;
+                return (x * (a * x + b)) / (x * (c * x + d) + e);
+            }
+
+            vec3 reinhard(vec3 x) {
+                return x / (1.0 + x);
+            }
+
+            void main() {
+                vec3 exposed = subpassLoad(hdr).rgb * pc.exposure;
+                vec3 color = pc.operator == 1 ? reinhard(exposed) : aces(exposed);
+                // an scrgb swapchain shows values above 1 brighter than
+                // white, anything else would clip them
+                color = pc.extended_range == 1
+                    ? max(color, 0.0)
+                    : clamp(color, 0.0, 1.0);
+                if (pc.manual_gamma == 1) {
+                    color = pow(color, vec3(1.0 / 2.2));
+                }
+                outColor = vec4(color, 1.0);
+            }
+            "#
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The errors returned by the public functions of `Display` and `Particles`.
+///
+/// Internally failures are `anyhow` errors so they can carry context. When
+/// one crosses the public api it becomes the structured variant found in its
+/// chain, if there is one, so callers can match on the failures they may want
+/// to recover from. Everything else is `Other`.
+#[derive(Debug, Error)]
+pub enum ParticleError {
+    #[error("no suitable physical device - {0}")]
+    DeviceSelection(String),
+
+    #[error("no suitable surface format - {0}")]
+    SurfaceFormat(String),
+
+    #[error("unable to create the swapchain")]
+    SwapchainCreation(#[source] BoxedError),
+
+    /// The surface changed while the swapchain was being built for it,
+    /// typically because the window was resized again. Trying again once
+    /// the resize settles is enough.
+    #[error("the swapchain is out of date with the surface")]
+    OutOfDate,
+
+    #[error("unable to load the {0} shader")]
+    ShaderLoad(&'static str, #[source] BoxedError),
+
+    #[error("unable to submit work to the gpu")]
+    Submission(#[source] BoxedError),
+
+    #[error("the seed image {0:?} is completely black")]
+    BlackSeedImage(PathBuf),
+
+    /// Any other failure, along with the context of what was being done.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ParticleError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<ParticleError>() {
+            Ok(structured) => structured,
+            Err(error) => ParticleError::Other(error),
+        }
+    }
+}
+
+impl ParticleError {
+    pub fn swapchain_creation<E>(error: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        ParticleError::SwapchainCreation(Box::new(error))
+    }
+
+    pub fn shader_load<E>(stage: &'static str) -> impl FnOnce(E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        move |error| ParticleError::ShaderLoad(stage, Box::new(error))
+    }
+
+    pub fn submission<E>(error: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        ParticleError::Submission(Box::new(error))
+    }
+}
@@ -1,12 +1,19 @@
 mod application;
+mod args;
+mod config;
 mod display;
+mod error;
+mod math;
 
 use anyhow::Result;
 use application::Application;
+use args::Args;
+use config::Config;
 use flexi_logger::DeferredNow;
 use flexi_logger::Logger;
 use flexi_logger::Record;
 use std::fmt::Write as FmtWrite;
+use structopt::StructOpt;
 use textwrap::{termwidth, Options};
 
 fn main() -> Result<()> {
@@ -22,11 +29,21 @@ fn main() -> Result<()> {
 }
 
 fn run() -> Result<()> {
+    let args = Args::from_args();
+
     Logger::with_env_or_str("info")
         .format(multiline_format)
         .start()?;
 
-    let app = Application::initialize()?;
+    let config = Config::load(args.config.as_deref())?;
+    let app = Application::initialize(&args, &config)?;
+    if let Some(frames) = args.hash_frame {
+        println!("{:016x}", app.hash_frame(frames)?);
+        return Ok(());
+    }
+    if let Some(frames) = args.render_sequence {
+        return app.render_sequence(frames, &args.out_dir);
+    }
     app.main_loop()
 }
 
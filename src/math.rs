@@ -0,0 +1,47 @@
+//! Small numeric helpers shared across the application.
+
+/// Linearly interpolate between `min` and `max`.
+///
+/// `x` is the interpolation factor: 0.0 yields `min`, 1.0 yields `max`, and
+/// values outside of [0, 1] extrapolate. `min` may be larger than `max` to
+/// flip the direction of the mapping.
+pub fn lerp(x: f32, min: f32, max: f32) -> f32 {
+    x * max + (1.0 - x) * min
+}
+
+/// Restrict `x` to the inclusive range [lower, upper].
+///
+/// `lower` wins when the range is empty.
+pub fn clamp<T: PartialOrd>(x: T, lower: T, upper: T) -> T {
+    let x = if x > upper { upper } else { x };
+    if x < lower {
+        lower
+    } else {
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lerp_maps_the_factor_onto_the_range() {
+        assert_eq!(lerp(0.0, 2.0, 6.0), 2.0);
+        assert_eq!(lerp(1.0, 2.0, 6.0), 6.0);
+        assert_eq!(lerp(0.25, 2.0, 6.0), 3.0);
+        assert_eq!(lerp(1.5, 2.0, 6.0), 8.0);
+        // a reversed range flips the mapping
+        assert_eq!(lerp(0.25, 1.0, -1.0), 0.5);
+    }
+
+    #[test]
+    fn clamp_restricts_to_the_range() {
+        assert_eq!(clamp(5, 0, 10), 5);
+        assert_eq!(clamp(-5, 0, 10), 0);
+        assert_eq!(clamp(15, 0, 10), 10);
+        assert_eq!(clamp(0.5, 0.0, 1.0), 0.5);
+        // lower wins when the range is empty
+        assert_eq!(clamp(5, 10, 0), 10);
+    }
+}